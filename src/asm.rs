@@ -0,0 +1,868 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::config::MemoryMap;
+use crate::constants::{START_ADDRESS, VMWord, VmAddr};
+use crate::error::{Result, VMError};
+use crate::register::RegisterId;
+use crate::utils::instruction_builder;
+use crate::vm::{AluFunction, Condition, IntOperation, Opcode, StackOperation, WideOperation};
+
+/*
+    Text assembler for the VM instruction set.
+
+    One statement per line, `;` starts a comment:
+
+        start:  LOAD_IMM16 R0, 0x1234   ; label, mnemonic and operands
+                ADD R0, 3               ; a literal 1..15 in a register slot is encoded as RIM + imm
+                JNZ start               ; jump targets are absolute addresses or labels
+        .data
+        value:  .word 42, start
+
+    Operands are registers (R0-R3, RPC, RIR, RIM, RFLAGS, RSP, RPTBR, RFAR), decimal/hex/binary literals or labels.
+    The first word is placed at START_ADDRESS and labels resolve to absolute addresses, so forward references work.
+    Data is placed in the data segment of the memory map, the default one unless `assemble_with_map` gets another.
+
+    Directives:
+        .org ADDR       continue at ADDR, the gap is filled with zeros
+        .word V, ...    emit literal words or label addresses
+        .data [ADDR]    everything after it is data placed from ADDR, after the output word by default;
+                        instructions are rejected, the gap after the code is filled with zeros
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, column: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Output of the assembler, `words` is the image starting at `START_ADDRESS`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    pub words: Vec<VMWord>,
+    pub code_end: usize, // address right after the code, the words up to `data_start` are zero
+    pub data_start: Option<VmAddr>, // address of the first word after `.data`
+    pub symbols: BTreeMap<String, VmAddr>,
+}
+
+impl Assembly {
+    // Code is always placed at START_ADDRESS, a `start` label moves the entry point
+    pub fn entry_point(&self) -> VmAddr {
+        self.symbols.get("start").copied().unwrap_or(START_ADDRESS)
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<VMWord>> {
+    Ok(assemble_with_symbols(source)?.words)
+}
+
+pub fn assemble_with_symbols(source: &str) -> Result<Assembly> {
+    assemble_with_map(source, &MemoryMap::default())
+}
+
+// `.data` without an address starts at `map.data_address()`, after the output word
+pub fn assemble_with_map(source: &str, map: &MemoryMap) -> Result<Assembly> {
+    let assembler = Assembler {
+        data_segment: map.data_address(),
+        ..Assembler::default()
+    };
+    assembler.assemble(source).map_err(VMError::from)
+}
+
+// How the operands of a mnemonic map onto the dst/src/imm fields and the extension word
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OperandForm {
+    None,            // HALT, RET
+    DstSrc,          // COPY R0, R1 or COPY R0, 5 (RIM + imm)
+    DstImm,          // LOAD_IMM RIM, 5
+    Src,             // STORE_OUT R0
+    SrcOrImm,        // SYSCALL R1 or SYSCALL 5 (RIM + imm)
+    Dst,             // POP R0
+    DstSrcRegisters, // SUB R0, R1, the imm nibble selects the function
+    Jump,            // JZ R1 or JZ label (PC-relative)
+    DstWide,         // LOAD_IMM16 R0, 0x1234
+    JumpWide,        // JZ16 label
+    Wide,            // CALL16 label
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct InstructionSpec {
+    pub mnemonic: String,
+    pub opcode: Opcode,
+    pub fixed_dst: Option<u8>, // condition of jumps
+    pub fixed_imm: Option<u8>, // sub-operation of ALU, STACK and WIDE
+    pub form: OperandForm,
+}
+
+impl InstructionSpec {
+    pub fn size(&self) -> VmAddr {
+        if self.opcode.has_extension_word() {
+            4
+        } else {
+            2
+        }
+    }
+}
+
+// Every mnemonic the assembler accepts, the disassembler uses the same table to decode
+pub(crate) fn instruction_set() -> Vec<InstructionSpec> {
+    let mut specs = vec![];
+    let mut spec = |mnemonic: String, opcode, fixed_dst, fixed_imm, form| {
+        specs.push(InstructionSpec {
+            mnemonic,
+            opcode,
+            fixed_dst,
+            fixed_imm,
+            form,
+        })
+    };
+    let conditions: Vec<Condition> = (0..16)
+        .filter_map(|i| Condition::try_from(i).ok())
+        .collect();
+
+    for (opcode, form) in [
+        (Opcode::HALT, OperandForm::None),
+        (Opcode::COPY, OperandForm::DstSrc),
+        (Opcode::LOAD, OperandForm::DstSrc),
+        (Opcode::WRITE, OperandForm::DstSrc),
+        (Opcode::ADD, OperandForm::DstSrc),
+        (Opcode::LOAD_IMM, OperandForm::DstImm),
+        (Opcode::STORE_OUT, OperandForm::Src),
+        (Opcode::CMP, OperandForm::DstSrc),
+        (Opcode::SYSCALL, OperandForm::SrcOrImm),
+    ] {
+        spec(format!("{opcode:?}"), opcode, None, None, form);
+    }
+
+    for condition in &conditions {
+        let mnemonic = condition.mnemonic().to_string();
+        spec(
+            mnemonic,
+            Opcode::JMP,
+            Some(condition.id()),
+            None,
+            OperandForm::Jump,
+        );
+    }
+
+    for function in (0..16).filter_map(|i| AluFunction::try_from(i).ok()) {
+        let form = match function {
+            AluFunction::NOT => OperandForm::Dst,
+            _ => OperandForm::DstSrcRegisters,
+        };
+        spec(
+            format!("{function:?}"),
+            Opcode::ALU,
+            None,
+            Some(function.id()),
+            form,
+        );
+    }
+
+    for operation in (0..16).filter_map(|i| StackOperation::try_from(i).ok()) {
+        let form = match operation {
+            StackOperation::PUSH | StackOperation::CALL => OperandForm::Src,
+            StackOperation::POP => OperandForm::Dst,
+            StackOperation::RET => OperandForm::None,
+        };
+        spec(
+            format!("{operation:?}"),
+            Opcode::STACK,
+            None,
+            Some(operation.id()),
+            form,
+        );
+    }
+
+    for operation in (0..16).filter_map(|i| WideOperation::try_from(i).ok()) {
+        let imm = Some(operation.id());
+        match operation {
+            WideOperation::LOAD_IMM16 | WideOperation::ADDI16 => spec(
+                format!("{operation:?}"),
+                Opcode::WIDE,
+                None,
+                imm,
+                OperandForm::DstWide,
+            ),
+            WideOperation::JMP16 => {
+                for condition in &conditions {
+                    let mnemonic = format!("{}16", condition.mnemonic());
+                    spec(
+                        mnemonic,
+                        Opcode::WIDE,
+                        Some(condition.id()),
+                        imm,
+                        OperandForm::JumpWide,
+                    );
+                }
+            }
+            WideOperation::CALL16 => spec(
+                format!("{operation:?}"),
+                Opcode::WIDE,
+                None,
+                imm,
+                OperandForm::Wide,
+            ),
+        }
+    }
+
+    for operation in (0..16).filter_map(|i| IntOperation::try_from(i).ok()) {
+        spec(
+            format!("{operation:?}"),
+            Opcode::INT,
+            None,
+            Some(operation.id()),
+            OperandForm::None,
+        );
+    }
+
+    specs
+}
+
+// A piece of source text together with the column it starts at (1-based)
+#[derive(Debug, Clone)]
+struct Token<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+#[derive(Debug)]
+enum StatementKind {
+    Instruction(InstructionSpec),
+    Word,
+}
+
+#[derive(Debug)]
+struct Statement<'a> {
+    line: usize,
+    column: usize,
+    addr: VmAddr,
+    kind: StatementKind,
+    operands: Vec<Token<'a>>,
+}
+
+enum Operand<'a> {
+    Register(RegisterId),
+    Number(i64),
+    Label(&'a str),
+}
+
+#[derive(Default)]
+struct Assembler<'a> {
+    statements: Vec<Statement<'a>>,
+    symbols: BTreeMap<String, VmAddr>,
+    data_segment: VmAddr,
+    data_start: Option<VmAddr>,
+    code_end: Option<VmAddr>,
+    end: u32, // address right after the last emitted word
+}
+
+impl<'a> Assembler<'a> {
+    fn assemble(mut self, source: &'a str) -> core::result::Result<Assembly, AsmError> {
+        let specs = instruction_set();
+        self.end = u32::from(START_ADDRESS);
+
+        // Pass 1: assign an address to every statement and collect the labels
+        for (line_idx, line) in source.lines().enumerate() {
+            self.parse_line(line_idx + 1, line, &specs)?;
+        }
+
+        // Pass 2: encode with every label known
+        let mut words = vec![0; ((self.end - u32::from(START_ADDRESS)) / 2) as usize];
+        for statement in &self.statements {
+            let encoded = self.encode(statement)?;
+            let first = usize::from((statement.addr - START_ADDRESS) / 2);
+            words[first..first + encoded.len()].copy_from_slice(&encoded);
+        }
+
+        Ok(Assembly {
+            words,
+            code_end: self.code_end.map_or(self.end as usize, usize::from),
+            data_start: self.data_start,
+            symbols: self.symbols,
+        })
+    }
+
+    fn parse_line(
+        &mut self,
+        line_no: usize,
+        line: &'a str,
+        specs: &[InstructionSpec],
+    ) -> core::result::Result<(), AsmError> {
+        let code = line.split(';').next().unwrap_or_default();
+        // Every token is a sub-slice of the line, so its column is its byte offset
+        let column_of = |part: &str| part.as_ptr() as usize - code.as_ptr() as usize + 1;
+        let mut rest = code.trim_start();
+
+        // Optional `label:` prefix
+        if let Some((name, after)) = rest.split_once(':')
+            && is_identifier(name.trim_end())
+        {
+            let name = name.trim_end();
+            if RegisterId::from_name(name).is_some() {
+                return Err(AsmError::new(
+                    line_no,
+                    column_of(rest),
+                    format!("label `{name}` clashes with a register name"),
+                ));
+            }
+            let addr = self.current_addr(line_no, column_of(rest))?;
+            if self.symbols.insert(name.to_string(), addr).is_some() {
+                return Err(AsmError::new(
+                    line_no,
+                    column_of(rest),
+                    format!("label `{name}` is defined more than once"),
+                ));
+            }
+            rest = after.trim_start();
+        }
+
+        if rest.trim_end().is_empty() {
+            return Ok(());
+        }
+
+        let column = column_of(rest);
+        let (head, tail) = rest
+            .split_once(char::is_whitespace)
+            .unwrap_or((rest.trim_end(), ""));
+        let operands: Vec<Token<'a>> = if tail.trim().is_empty() {
+            vec![]
+        } else {
+            tail.split(',')
+                .map(|part| Token {
+                    text: part.trim(),
+                    column: column_of(part.trim_start()),
+                })
+                .collect()
+        };
+        if let Some(empty) = operands.iter().find(|token| token.text.is_empty()) {
+            return Err(AsmError::new(line_no, empty.column, "missing operand"));
+        }
+
+        let addr = self.current_addr(line_no, column)?;
+        match head.to_ascii_lowercase().as_str() {
+            ".org" => {
+                let [target] = operands.as_slice() else {
+                    return Err(AsmError::new(line_no, column, "`.org` takes one address"));
+                };
+                self.end = u32::from(forward_address(line_no, target, addr, ".org")?);
+            }
+            ".word" => {
+                if operands.is_empty() {
+                    return Err(AsmError::new(line_no, column, "`.word` needs a value"));
+                }
+                self.push_statement(line_no, column, addr, StatementKind::Word, operands)?;
+            }
+            ".data" => {
+                if operands.len() > 1 || self.data_start.is_some() {
+                    return Err(AsmError::new(
+                        line_no,
+                        column,
+                        "`.data` takes at most one address and may appear only once",
+                    ));
+                }
+                let data_start = match operands.first() {
+                    Some(target) => forward_address(line_no, target, addr, ".data")?,
+                    None if addr <= self.data_segment => self.data_segment,
+                    None => {
+                        return Err(AsmError::new(
+                            line_no,
+                            column,
+                            format!("the code runs into `.data` at {:#06x}", self.data_segment),
+                        ));
+                    }
+                };
+                self.code_end = Some(addr);
+                self.data_start = Some(data_start);
+                self.end = u32::from(data_start);
+            }
+            directive if directive.starts_with('.') => {
+                return Err(AsmError::new(
+                    line_no,
+                    column,
+                    format!("unknown directive `{head}`"),
+                ));
+            }
+            _ => {
+                let spec = specs
+                    .iter()
+                    .find(|spec| spec.mnemonic.eq_ignore_ascii_case(head))
+                    .ok_or_else(|| {
+                        AsmError::new(line_no, column, format!("unknown mnemonic `{head}`"))
+                    })?;
+                if self.data_start.is_some() {
+                    return Err(AsmError::new(
+                        line_no,
+                        column,
+                        "instructions are not allowed after `.data`",
+                    ));
+                }
+                let kind = StatementKind::Instruction(spec.clone());
+                self.push_statement(line_no, column, addr, kind, operands)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn current_addr(&self, line: usize, column: usize) -> core::result::Result<VmAddr, AsmError> {
+        VmAddr::try_from(self.end)
+            .map_err(|_| AsmError::new(line, column, "program does not fit in the address space"))
+    }
+
+    fn push_statement(
+        &mut self,
+        line: usize,
+        column: usize,
+        addr: VmAddr,
+        kind: StatementKind,
+        operands: Vec<Token<'a>>,
+    ) -> core::result::Result<(), AsmError> {
+        let size = match &kind {
+            StatementKind::Instruction(spec) => u32::from(spec.size()),
+            StatementKind::Word => 2 * operands.len() as u32,
+        };
+        self.end += size;
+        if self.end > u32::from(VmAddr::MAX) + 1 {
+            return Err(AsmError::new(
+                line,
+                column,
+                "program does not fit in the address space",
+            ));
+        }
+        self.statements.push(Statement {
+            line,
+            column,
+            addr,
+            kind,
+            operands,
+        });
+        Ok(())
+    }
+
+    fn encode(&self, statement: &Statement) -> core::result::Result<Vec<VMWord>, AsmError> {
+        let line = statement.line;
+        let spec = match &statement.kind {
+            StatementKind::Word => {
+                return statement
+                    .operands
+                    .iter()
+                    .map(|token| self.word_value(line, token))
+                    .collect();
+            }
+            StatementKind::Instruction(spec) => spec,
+        };
+
+        let expected = match spec.form {
+            OperandForm::None => 0,
+            OperandForm::Src
+            | OperandForm::SrcOrImm
+            | OperandForm::Dst
+            | OperandForm::Jump
+            | OperandForm::JumpWide
+            | OperandForm::Wide => 1,
+            OperandForm::DstSrc
+            | OperandForm::DstImm
+            | OperandForm::DstSrcRegisters
+            | OperandForm::DstWide => 2,
+        };
+        if statement.operands.len() != expected {
+            return Err(AsmError::new(
+                line,
+                statement.column,
+                format!("`{}` takes {expected} operand(s)", spec.mnemonic),
+            ));
+        }
+
+        let ops = &statement.operands;
+        let opcode = spec.opcode.id();
+        let fixed_dst = spec.fixed_dst.unwrap_or(0);
+        let fixed_imm = spec.fixed_imm.unwrap_or(0);
+        let words = match spec.form {
+            OperandForm::None => vec![instruction_builder(opcode, fixed_dst, 0, fixed_imm)],
+            OperandForm::DstSrc => {
+                let (dst, dst_imm) = self.register_or_small_imm(line, &ops[0])?;
+                let (src, src_imm) = self.register_or_small_imm(line, &ops[1])?;
+                // Both slots share the single imm nibble
+                let imm = match (dst_imm, src_imm) {
+                    (Some(a), Some(b)) if a != b => {
+                        return Err(AsmError::new(
+                            line,
+                            ops[1].column,
+                            "both operands are immediates but only one imm value can be encoded",
+                        ));
+                    }
+                    (a, b) => a.or(b).unwrap_or(0),
+                };
+                vec![instruction_builder(opcode, dst, src, imm)]
+            }
+            OperandForm::DstImm => {
+                let dst = self.register(line, &ops[0])?;
+                let imm = self.nibble(line, &ops[1], 0..=15)?;
+                vec![instruction_builder(opcode, dst, 0, imm)]
+            }
+            OperandForm::Src => {
+                let src = self.register(line, &ops[0])?;
+                vec![instruction_builder(opcode, fixed_dst, src, fixed_imm)]
+            }
+            OperandForm::SrcOrImm => {
+                let (src, imm) = self.register_or_small_imm(line, &ops[0])?;
+                vec![instruction_builder(
+                    opcode,
+                    fixed_dst,
+                    src,
+                    imm.unwrap_or(0),
+                )]
+            }
+            OperandForm::Dst => {
+                let dst = self.register(line, &ops[0])?;
+                vec![instruction_builder(opcode, dst, 0, fixed_imm)]
+            }
+            OperandForm::DstSrcRegisters => {
+                let dst = self.register(line, &ops[0])?;
+                let src = self.register(line, &ops[1])?;
+                vec![instruction_builder(opcode, dst, src, fixed_imm)]
+            }
+            OperandForm::Jump => match self.operand(line, &ops[0])? {
+                Operand::Register(RegisterId::RIM) => {
+                    return Err(AsmError::new(
+                        line,
+                        ops[0].column,
+                        "RIM in a jump selects a relative offset, use a label or address instead",
+                    ));
+                }
+                Operand::Register(reg) => {
+                    vec![instruction_builder(opcode, fixed_dst, reg.id(), 0)]
+                }
+                _ => {
+                    let target = self.word_value(line, &ops[0])?;
+                    let offset = relative_offset(statement.addr, target).ok_or_else(|| {
+                        AsmError::new(
+                            line,
+                            ops[0].column,
+                            format!(
+                                "jump target {target:#06x} is out of range for a relative jump, use `{}16`",
+                                spec.mnemonic
+                            ),
+                        )
+                    })?;
+                    let imm = (offset & 0xF) as u8;
+                    vec![instruction_builder(
+                        opcode,
+                        fixed_dst,
+                        RegisterId::RIM.id(),
+                        imm,
+                    )]
+                }
+            },
+            OperandForm::DstWide => {
+                let dst = self.register(line, &ops[0])?;
+                let value = self.word_value(line, &ops[1])?;
+                vec![instruction_builder(opcode, dst, 0, fixed_imm), value]
+            }
+            OperandForm::JumpWide | OperandForm::Wide => {
+                let value = self.word_value(line, &ops[0])?;
+                vec![instruction_builder(opcode, fixed_dst, 0, fixed_imm), value]
+            }
+        };
+        Ok(words)
+    }
+
+    fn operand<'t>(
+        &self,
+        line: usize,
+        token: &Token<'t>,
+    ) -> core::result::Result<Operand<'t>, AsmError> {
+        if let Some(reg) = RegisterId::from_name(token.text) {
+            Ok(Operand::Register(reg))
+        } else if let Some(number) = parse_number(token.text) {
+            Ok(Operand::Number(number))
+        } else if is_identifier(token.text) {
+            Ok(Operand::Label(token.text))
+        } else {
+            Err(AsmError::new(
+                line,
+                token.column,
+                format!("invalid operand `{}`", token.text),
+            ))
+        }
+    }
+
+    fn register(&self, line: usize, token: &Token) -> core::result::Result<u8, AsmError> {
+        match self.operand(line, token)? {
+            Operand::Register(reg) => Ok(reg.id()),
+            _ => Err(AsmError::new(
+                line,
+                token.column,
+                format!("expected a register, found `{}`", token.text),
+            )),
+        }
+    }
+
+    // A literal in a register slot is encoded the way the VM resolves it: RIM loaded from the imm nibble
+    fn register_or_small_imm(
+        &self,
+        line: usize,
+        token: &Token,
+    ) -> core::result::Result<(u8, Option<u8>), AsmError> {
+        match self.operand(line, token)? {
+            Operand::Register(reg) => Ok((reg.id(), None)),
+            _ => Ok((
+                RegisterId::RIM.id(),
+                Some(self.nibble(line, token, 1..=15)?),
+            )),
+        }
+    }
+
+    fn nibble(
+        &self,
+        line: usize,
+        token: &Token,
+        range: std::ops::RangeInclusive<i64>,
+    ) -> core::result::Result<u8, AsmError> {
+        match self.operand(line, token)? {
+            Operand::Number(n) if range.contains(&n) => Ok(n as u8),
+            _ => Err(AsmError::new(
+                line,
+                token.column,
+                format!(
+                    "expected an immediate in {}..={}, use LOAD_IMM16 for other values",
+                    range.start(),
+                    range.end()
+                ),
+            )),
+        }
+    }
+
+    // Any 16-bit value, negative literals are stored in two's complement
+    fn word_value(&self, line: usize, token: &Token) -> core::result::Result<VMWord, AsmError> {
+        match self.operand(line, token)? {
+            Operand::Number(n) if (i64::from(i16::MIN)..=i64::from(VMWord::MAX)).contains(&n) => {
+                Ok(n as VMWord)
+            }
+            Operand::Number(_) => Err(AsmError::new(
+                line,
+                token.column,
+                format!("`{}` does not fit in 16 bits", token.text),
+            )),
+            Operand::Label(name) => self.symbols.get(name).copied().ok_or_else(|| {
+                AsmError::new(line, token.column, format!("undefined label `{name}`"))
+            }),
+            Operand::Register(_) => Err(AsmError::new(
+                line,
+                token.column,
+                format!("expected a value or label, found register `{}`", token.text),
+            )),
+        }
+    }
+}
+
+// Offset in instructions from the word after the jump, as encoded in the imm nibble
+pub(crate) fn relative_offset(jump_addr: VmAddr, target: VmAddr) -> Option<i16> {
+    let delta = i32::from(target) - (i32::from(jump_addr) + 2);
+    if delta % 2 != 0 {
+        return None;
+    }
+    let offset = delta / 2;
+    (-8..=7).contains(&offset).then_some(offset as i16)
+}
+
+pub(crate) fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if lower.chars().all(|c| c.is_ascii_digit()) && !lower.is_empty() {
+        lower.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+// Target of `.org` and `.data`, an even address that is not behind the current one
+fn forward_address(
+    line: usize,
+    target: &Token,
+    current: VmAddr,
+    directive: &str,
+) -> core::result::Result<VmAddr, AsmError> {
+    parse_number(target.text)
+        .filter(|n| (i64::from(current)..=i64::from(VmAddr::MAX)).contains(n))
+        .filter(|n| n % 2 == 0)
+        .map(|n| n as VmAddr)
+        .ok_or_else(|| {
+            AsmError::new(
+                line,
+                target.column,
+                format!("`{directive}` needs an even address that is not behind the current one"),
+            )
+        })
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Segment;
+    use crate::constants::MEMORY_SIZE;
+    use crate::memory::LinearMemory;
+    use crate::program::Program;
+    use crate::utils::build_simple_program;
+    use crate::vm::VM;
+
+    fn run(words: &[VMWord]) -> VM {
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(MEMORY_SIZE)));
+        vm.load_program(&Program::new(words.to_vec())).unwrap();
+        while !vm.halted {
+            vm.tick().unwrap();
+        }
+        vm
+    }
+
+    fn asm_error(source: &str) -> AsmError {
+        match assemble(source) {
+            Err(VMError::Asm(err)) => err,
+            other => panic!("expected an assembler error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_assembles_simple_program() {
+        let source = "
+            ; same program as utils::build_simple_program
+            LOAD_IMM RIM, 5
+            COPY R0, RIM
+            load_imm rim, 0x3
+            COPY R1, RIM
+            ADD R0, R1
+            STORE_OUT R0
+        ";
+        assert_eq!(assemble(source).unwrap(), build_simple_program());
+    }
+
+    #[test]
+    fn test_labels_and_forward_references() {
+        let source = "
+                    CALL16 sum      ; forward reference
+                    HALT
+            sum:    LOAD_IMM16 R1, 1000
+            loop:   ADD R0, 1
+                    CMP R0, R1
+                    JNE loop
+                    RET
+        ";
+        let assembly = assemble_with_symbols(source).unwrap();
+        assert_eq!(assembly.symbols["sum"], START_ADDRESS + 6);
+        assert_eq!(assembly.words[1], START_ADDRESS + 6);
+
+        let vm = run(&assembly.words);
+        let r0 = vm
+            .registers
+            .get_register_read_only(RegisterId::RR0.id())
+            .unwrap();
+        assert_eq!(r0.value, 1000);
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = "
+                    LOAD_IMM16 R0, table
+                    HALT
+            .org 0x108
+            .word 7
+            .data
+            table:  .word 0xBEEF, -1, table
+        ";
+        let assembly = assemble_with_symbols(source).unwrap();
+        assert_eq!(assembly.code_end, 0x10A);
+        assert_eq!(assembly.data_start, Some(0x1082));
+        assert_eq!(
+            &assembly.words[..5],
+            &[0xB000, 0x1082, 0x0000, 0x0000, 0x0007]
+        );
+        assert_eq!(&assembly.words[0x7C0..], &[0x0000, 0xBEEF, 0xFFFF, 0x1082]);
+
+        // An explicit address places constants in rodata, another memory map moves the data segment
+        let rodata = assemble_with_symbols("HALT\n.data 0x1000\nk: .word 1").unwrap();
+        assert_eq!(rodata.symbols["k"], 0x1000);
+        let map = MemoryMap {
+            data: Segment::new(0x120, 0x140),
+            ..MemoryMap::default()
+        };
+        let moved = assemble_with_map("HALT\n.data\nv: .word 1", &map).unwrap();
+        assert_eq!(moved.symbols["v"], 0x122);
+        assert_eq!(moved.words.len(), 0x12);
+    }
+
+    #[test]
+    fn test_store_out_leaves_data_alone() {
+        let assembly =
+            assemble_with_symbols("COPY R0, 3\nSTORE_OUT R0\nHALT\n.data\nv: .word 7").unwrap();
+        let v = assembly.symbols["v"];
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(MEMORY_SIZE)));
+        vm.load_program(&Program::from(assembly)).unwrap();
+        let result = vm.run();
+        assert!(result.is_halted());
+        assert_eq!(result.output, Some(3));
+        assert_eq!(vm.memory.read2(v), Some(7));
+    }
+
+    #[test]
+    fn test_errors_report_line_and_column() {
+        assert_eq!(
+            asm_error("HALT\n  FOO R0"),
+            AsmError::new(2, 3, "unknown mnemonic `FOO`")
+        );
+        assert_eq!(
+            asm_error("JMP nowhere"),
+            AsmError::new(1, 5, "undefined label `nowhere`")
+        );
+        assert_eq!(asm_error("ADD R0, 16").column, 9);
+        assert_eq!(asm_error("SUB R0, 3").column, 9);
+        assert_eq!(asm_error("x: HALT\nx: HALT").line, 2);
+        assert_eq!(asm_error(".data\nHALT").line, 2);
+        assert_eq!(asm_error("HALT\n.data 0x100").column, 7);
+        assert!(
+            asm_error(".org 0x1084\n.data")
+                .message
+                .contains("runs into `.data` at 0x1082")
+        );
+        assert_eq!(asm_error(".org 0x50").column, 6);
+        assert!(
+            asm_error(".org 0x200\nJMP 0x100")
+                .message
+                .contains("out of range")
+        );
+    }
+}
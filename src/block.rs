@@ -0,0 +1,430 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::bus::{BusDevice, NO_BYTES};
+use crate::constants::{VMWord, VmAddr};
+use crate::error::{Result, VMError};
+
+/*
+    Block storage.
+
+    A peripheral for the `SystemBus` moving whole sectors between a storage backend and VM memory, with five word
+    registers, offsets are relative to where it is mapped:
+
+        0x0     SECTOR      sector number of the next transfer
+        0x2     BUFFER      VM address of the SECTOR_SIZE bytes to transfer
+        0x4     COMMAND     writing BLOCK_READ (storage -> memory) or BLOCK_WRITE (memory -> storage) starts a
+                            transfer, reads back the last command
+        0x6     STATUS      result of the last transfer, read-only
+        0x8     SECTORS     number of sectors of the storage, read-only
+
+    Transfers are DMA-style: the device copies the buffer itself through the `read`/`write` of the bus, right after
+    the command is written, so the program finds the data in place and STATUS set on the next instruction.
+    Only a `SystemBus` performs transfers, see `BusDevice::dma`. Failures never stop the machine, they are reported
+    in STATUS. A buffer that runs into unmapped or read-only memory may be partially transferred.
+*/
+
+pub const BLOCK_SECTOR: VmAddr = 0x0;
+pub const BLOCK_BUFFER: VmAddr = 0x2;
+pub const BLOCK_COMMAND: VmAddr = 0x4;
+pub const BLOCK_STATUS: VmAddr = 0x6;
+pub const BLOCK_SECTORS: VmAddr = 0x8;
+pub const BLOCK_SIZE: usize = 10;
+
+pub const SECTOR_SIZE: usize = 256;
+
+pub const BLOCK_READ: VMWord = 1;
+pub const BLOCK_WRITE: VMWord = 2;
+
+pub const STATUS_OK: VMWord = 0;
+pub const STATUS_BAD_SECTOR: VMWord = 1;
+pub const STATUS_BAD_BUFFER: VMWord = 2; // the buffer leaves the address space or touches unusable memory
+pub const STATUS_BAD_COMMAND: VMWord = 3;
+pub const STATUS_IO_ERROR: VMWord = 4;
+
+/// Sectors of SECTOR_SIZE bytes, numbered from 0
+pub trait BlockStorage: fmt::Debug {
+    fn sectors(&self) -> VMWord;
+    fn read_sector(&mut self, sector: VMWord, buffer: &mut [u8; SECTOR_SIZE]) -> io::Result<()>;
+    fn write_sector(&mut self, sector: VMWord, buffer: &[u8; SECTOR_SIZE]) -> io::Result<()>;
+}
+
+/// A host file, persisting the sectors between runs
+#[derive(Debug)]
+pub struct FileStorage {
+    file: File,
+    sectors: VMWord,
+}
+
+impl FileStorage {
+    // Creates the file when it does not exist and grows it to `sectors` sectors
+    pub fn open(path: impl AsRef<Path>, sectors: VMWord) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let size = (sectors as u64) * SECTOR_SIZE as u64;
+        if file.metadata()?.len() < size {
+            file.set_len(size)?;
+        }
+        Ok(Self { file, sectors })
+    }
+
+    fn seek(&mut self, sector: VMWord) -> io::Result<()> {
+        let offset = u64::from(sector) * SECTOR_SIZE as u64;
+        self.file.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+}
+
+impl BlockStorage for FileStorage {
+    fn sectors(&self) -> VMWord {
+        self.sectors
+    }
+
+    fn read_sector(&mut self, sector: VMWord, buffer: &mut [u8; SECTOR_SIZE]) -> io::Result<()> {
+        self.seek(sector)?;
+        self.file.read_exact(buffer)
+    }
+
+    fn write_sector(&mut self, sector: VMWord, buffer: &[u8; SECTOR_SIZE]) -> io::Result<()> {
+        self.seek(sector)?;
+        self.file.write_all(buffer)?;
+        self.file.flush()
+    }
+}
+
+/// An in-memory image, clones share the bytes so a test can inspect what the program wrote
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(sectors: VMWord) -> Self {
+        Self::from_image(vec![0; usize::from(sectors) * SECTOR_SIZE])
+    }
+
+    // A trailing partial sector is padded with zeros
+    pub fn from_image(mut image: Vec<u8>) -> Self {
+        image.resize(image.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE, 0);
+        Self {
+            bytes: Rc::new(RefCell::new(image)),
+        }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.borrow().clone()
+    }
+
+    fn range(sector: VMWord) -> std::ops::Range<usize> {
+        let start = usize::from(sector) * SECTOR_SIZE;
+        start..start + SECTOR_SIZE
+    }
+}
+
+impl BlockStorage for MemoryStorage {
+    fn sectors(&self) -> VMWord {
+        VMWord::try_from(self.bytes.borrow().len() / SECTOR_SIZE).unwrap_or(VMWord::MAX)
+    }
+
+    fn read_sector(&mut self, sector: VMWord, buffer: &mut [u8; SECTOR_SIZE]) -> io::Result<()> {
+        let bytes = self.bytes.borrow();
+        let sector = bytes
+            .get(Self::range(sector))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buffer.copy_from_slice(sector);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector: VMWord, buffer: &[u8; SECTOR_SIZE]) -> io::Result<()> {
+        let mut bytes = self.bytes.borrow_mut();
+        let sector = bytes
+            .get_mut(Self::range(sector))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        sector.copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockDevice {
+    storage: Box<dyn BlockStorage>,
+    sector: VMWord,
+    buffer: VmAddr,
+    command: VMWord,
+    status: VMWord,
+    pending: bool, // a command was written and waits for `dma`
+}
+
+impl BlockDevice {
+    pub fn new(storage: impl BlockStorage + 'static) -> Self {
+        Self {
+            storage: Box::new(storage),
+            sector: 0,
+            buffer: 0,
+            command: 0,
+            status: STATUS_OK,
+            pending: false,
+        }
+    }
+
+    fn register(&self, offset: VmAddr) -> Option<VMWord> {
+        match offset {
+            BLOCK_SECTOR => Some(self.sector),
+            BLOCK_BUFFER => Some(self.buffer),
+            BLOCK_COMMAND => Some(self.command),
+            BLOCK_STATUS => Some(self.status),
+            BLOCK_SECTORS => Some(self.storage.sectors()),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, offset: VmAddr, value: VMWord) -> Result<()> {
+        match offset {
+            BLOCK_SECTOR => self.sector = value,
+            BLOCK_BUFFER => self.buffer = value,
+            BLOCK_COMMAND => {
+                self.command = value;
+                self.pending = true;
+            }
+            BLOCK_STATUS | BLOCK_SECTORS => {}
+            _ => return Err(VMError::OutOfBounds(offset)),
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, memory: &mut dyn BusDevice) -> VMWord {
+        if self.command != BLOCK_READ && self.command != BLOCK_WRITE {
+            return STATUS_BAD_COMMAND;
+        }
+        if self.sector >= self.storage.sectors() {
+            return STATUS_BAD_SECTOR;
+        }
+        let start = self.buffer;
+        if usize::from(start) + SECTOR_SIZE > usize::from(VmAddr::MAX) + 1 {
+            return STATUS_BAD_BUFFER;
+        }
+        let addresses = (0..SECTOR_SIZE as VmAddr).map(|offset| start + offset);
+
+        let mut data = [0u8; SECTOR_SIZE];
+        if self.command == BLOCK_READ {
+            if self.storage.read_sector(self.sector, &mut data).is_err() {
+                return STATUS_IO_ERROR;
+            }
+            for (addr, byte) in addresses.zip(data) {
+                if memory.write(addr, byte).is_err() {
+                    return STATUS_BAD_BUFFER;
+                }
+            }
+        } else {
+            for (addr, byte) in addresses.zip(&mut data) {
+                match memory.read(addr) {
+                    Some(value) => *byte = value,
+                    None => return STATUS_BAD_BUFFER,
+                }
+            }
+            if self.storage.write_sector(self.sector, &data).is_err() {
+                return STATUS_IO_ERROR;
+            }
+        }
+        STATUS_OK
+    }
+}
+
+impl BusDevice for BlockDevice {
+    // Byte accesses address the low or high half of a register, a command starts once its low byte is written
+    fn read(&self, addr: VmAddr) -> Option<u8> {
+        let value = self.register(addr & !1)?;
+        Some((value >> (8 * (addr & 1))) as u8)
+    }
+
+    fn write(&mut self, addr: VmAddr, value: u8) -> Result<()> {
+        let offset = addr & !1;
+        let old = self.register(offset).ok_or(VMError::OutOfBounds(addr))?;
+        let shift = 8 * (addr & 1);
+        let word = (old & !(0xFF << shift)) | (VMWord::from(value) << shift);
+        if offset == BLOCK_COMMAND && shift != 0 {
+            self.command = word;
+            return Ok(());
+        }
+        self.set_register(offset, word)
+    }
+
+    fn read2(&self, addr: VmAddr) -> Option<u16> {
+        if addr % 2 == 1 {
+            return None;
+        }
+        self.register(addr)
+    }
+
+    fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
+        if addr % 2 == 1 {
+            return Err(VMError::OutOfBounds(addr));
+        }
+        self.set_register(addr, value)
+    }
+
+    fn memory_range(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.pending
+    }
+
+    fn dma(&mut self, memory: &mut dyn BusDevice) {
+        self.pending = false;
+        self.status = self.transfer(memory);
+    }
+
+    fn as_bytes(&self) -> &Vec<u8> {
+        &NO_BYTES
+    }
+
+    fn get_specific_memory_location(&self, idx: usize) -> u16 {
+        VmAddr::try_from(idx)
+            .ok()
+            .and_then(|addr| self.read2(addr))
+            .unwrap_or_default()
+    }
+
+    fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8> {
+        (start_addr..end_addr)
+            .map(|idx| {
+                VmAddr::try_from(idx)
+                    .ok()
+                    .and_then(|addr| self.read(addr))
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::memory::LinearMemory;
+    use crate::program::Program;
+    use crate::protection::Permissions;
+    use crate::register::RegisterId;
+    use crate::system_bus::SystemBus;
+    use crate::vm::VM;
+
+    const DISK: VmAddr = 0xFF20;
+
+    fn disk_vm(storage: MemoryStorage, source: &str) -> VM {
+        let bus = SystemBus::new()
+            .with(
+                "ram",
+                0x0000,
+                Box::new(LinearMemory::new(0x1000)),
+                Permissions::ALL,
+            )
+            .unwrap()
+            .with(
+                "rom",
+                0x2000,
+                Box::new(LinearMemory::new(0x100)),
+                Permissions::READ_ONLY,
+            )
+            .unwrap()
+            .with(
+                "disk",
+                DISK,
+                Box::new(BlockDevice::new(storage)),
+                Permissions::READ_WRITE,
+            )
+            .unwrap();
+        let mut vm = VM::builder().memory(Box::new(bus)).build().unwrap();
+        vm.load_program(&Program::new(assemble(source).unwrap()))
+            .unwrap();
+        vm
+    }
+
+    // Sets SECTOR, BUFFER and COMMAND from R1, R2 and R3, then copies STATUS into R0
+    const TRANSFER: &str = "
+            LOAD_IMM16 R0, 0xff20
+            WRITE R0, R1
+            ADD R0, 2
+            WRITE R0, R2
+            ADD R0, 2
+            WRITE R0, R3
+            ADD R0, 2
+            LOAD R0, R0
+            HALT
+    ";
+
+    fn transfer(vm: &mut VM, sector: VMWord, buffer: VmAddr, command: VMWord) -> VMWord {
+        for (id, value) in [
+            (RegisterId::RR1, sector),
+            (RegisterId::RR2, buffer),
+            (RegisterId::RR3, command),
+            (RegisterId::RPC, vm.config.load_address),
+        ] {
+            vm.registers.get_register_mut(id.id()).unwrap().value = value;
+        }
+        vm.halted = false;
+        assert!(vm.run().is_halted());
+        vm.registers
+            .get_register_read_only(RegisterId::RR0.id())
+            .unwrap()
+            .value
+    }
+
+    #[test]
+    fn test_sectors_move_between_storage_and_memory() {
+        let mut image = vec![0; SECTOR_SIZE];
+        image.extend(b"persisted");
+        let storage = MemoryStorage::from_image(image);
+        let mut vm = disk_vm(storage.clone(), TRANSFER);
+        assert_eq!(vm.memory.read2(DISK + BLOCK_SECTORS), Some(2));
+
+        assert_eq!(transfer(&mut vm, 1, 0x800, BLOCK_READ), STATUS_OK);
+        assert_eq!(vm.memory.get_subset_of_memory(0x800, 0x809), b"persisted");
+
+        vm.memory.write2(0x900, 0xBEEF).unwrap();
+        assert_eq!(transfer(&mut vm, 0, 0x900, BLOCK_WRITE), STATUS_OK);
+        assert_eq!(storage.contents()[..2], [0xEF, 0xBE]);
+    }
+
+    #[test]
+    fn test_failures_are_reported_in_status() {
+        let storage = MemoryStorage::new(2);
+        let mut vm = disk_vm(storage.clone(), TRANSFER);
+        assert_eq!(transfer(&mut vm, 2, 0x800, BLOCK_READ), STATUS_BAD_SECTOR);
+        assert_eq!(transfer(&mut vm, 0, 0xFFF0, BLOCK_READ), STATUS_BAD_BUFFER);
+        // Unmapped memory and the read-only ROM
+        assert_eq!(transfer(&mut vm, 0, 0x0F80, BLOCK_WRITE), STATUS_BAD_BUFFER);
+        assert_eq!(transfer(&mut vm, 0, 0x2000, BLOCK_READ), STATUS_BAD_BUFFER);
+        assert_eq!(transfer(&mut vm, 0, 0x800, 7), STATUS_BAD_COMMAND);
+        assert_eq!(transfer(&mut vm, 1, 0x800, BLOCK_WRITE), STATUS_OK);
+        assert_eq!(storage.contents().len(), 2 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn test_file_storage_persists_sectors() {
+        let path = std::env::temp_dir().join(format!("rust-vm-block-{}.img", std::process::id()));
+        let mut storage = FileStorage::open(&path, 4).unwrap();
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[..3].copy_from_slice(b"abc");
+        storage.write_sector(3, &sector).unwrap();
+        drop(storage);
+
+        let mut storage = FileStorage::open(&path, 4).unwrap();
+        let mut read = [0u8; SECTOR_SIZE];
+        storage.read_sector(3, &mut read).unwrap();
+        assert_eq!(&read[..3], b"abc");
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            4 * SECTOR_SIZE as u64
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
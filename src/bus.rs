@@ -1,8 +1,12 @@
 use crate::{
     constants::VmAddr,
     error::{Result, VMError},
+    protection::Permissions,
 };
 
+// What `as_bytes` returns for devices that are not backed by memory
+pub(crate) static NO_BYTES: Vec<u8> = Vec::new();
+
 // Interface for read and write access to memory or devices at specific addresses
 pub trait BusDevice: std::fmt::Debug {
     fn read(&self, addr: VmAddr) -> Option<u8>;
@@ -11,28 +15,43 @@ pub trait BusDevice: std::fmt::Debug {
     fn as_bytes(&self) -> &Vec<u8>;
 
     fn read2(&self, addr: VmAddr) -> Option<u16> {
+        // A word at 0xffff would wrap around to 0x0000
         if let Some(x0) = self.read(addr)
-            && let Some(x1) = self.read(addr + 1)
+            && let Some(x1) = self.read(addr.checked_add(1)?)
         {
             return Some((x0 as u16) | ((x1 as u16) << 8));
         };
 
         None
     }
+    // Instruction fetch, kept apart from `read2` so wrapping devices can tell code accesses from data accesses
+    fn fetch2(&self, addr: VmAddr) -> Option<u16> {
+        self.read2(addr)
+    }
+    // `read2` and `fetch2` with the reason of a failure, devices that check permissions report a ProtectionFault
+    fn try_read2(&self, addr: VmAddr) -> Result<u16> {
+        self.read2(addr).ok_or(VMError::OutOfBounds(addr))
+    }
+    fn try_fetch2(&self, addr: VmAddr) -> Result<u16> {
+        self.fetch2(addr).ok_or(VMError::OutOfBounds(addr))
+    }
+    // `read` and `read2` for inspection by the debugger, the gdb stub and watchpoints. Memory reads have no side
+    // effects, devices whose reads change state (see `uart.rs`) return the value without changing it
+    fn peek(&self, addr: VmAddr) -> Option<u8> {
+        self.read(addr)
+    }
+    fn peek2(&self, addr: VmAddr) -> Option<u16> {
+        self.read2(addr)
+    }
+
     fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
         let low_byte = value & 0xff;
         let high_byte = (value & 0xff00) >> 8;
 
+        let high_addr = addr.checked_add(1).ok_or(VMError::OutOfBounds(addr))?;
         // If the first write fails the second is not attempted, and the result is false, so called circuit
         self.write(addr, low_byte as u8)?;
-        self.write(addr + 1, high_byte as u8)?;
-
-        // ===== LOGGING
-        println!("Write on Addr: {}, Value: {}", addr, low_byte);
-        println!("Write on Addr: {}, Value: {}", addr + 1, high_byte);
-
-        let read_written_addr = self.read2(addr).unwrap();
-        println!("Result on Addr: {}, Value: {}\n", addr, read_written_addr);
+        self.write(high_addr, high_byte as u8)?;
         Ok(())
     }
 
@@ -48,6 +67,26 @@ pub trait BusDevice: std::fmt::Debug {
         Ok(())
     }
 
+    // Bit n is set while interrupt line n is raised, see `interrupt.rs`
+    fn pending_interrupts(&self) -> u8 {
+        0
+    }
+    // Called when the VM takes the interrupt on `line`
+    fn acknowledge_interrupt(&mut self, _line: u8) {}
+    // Called by the VM after every executed instruction, the clock of devices that count time
+    fn tick(&mut self) {}
+    // Devices that copy data on their own (see `block.rs`) report a pending transfer here, the `SystemBus` then
+    // calls `dma` with itself, the device's own region unmapped, right after the write that started it
+    fn dma_pending(&self) -> bool {
+        false
+    }
+    fn dma(&mut self, _memory: &mut dyn BusDevice) {}
+    // Sets the permissions of `len` bytes from `start`, see `protection.rs`. Memory without protection ignores it
+    fn protect(&mut self, _start: VmAddr, _len: usize, _permissions: Permissions) {}
+    // Called by the VM before every instruction and on trap entry with RPTBR and whether it runs in user mode,
+    // see `mmu.rs`. Devices without address translation ignore it
+    fn set_address_space(&mut self, _page_table: VmAddr, _user: bool) {}
+
     fn get_specific_memory_location(&self, idx: usize) -> u16;
     fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8>;
 }
@@ -79,7 +118,7 @@ mod tests {
                 *slot = value;
                 Ok(())
             } else {
-                Err(VMError::OutOfBounds)
+                Err(VMError::OutOfBounds(addr))
             }
         }
         fn memory_range(&self) -> usize {
@@ -151,6 +190,20 @@ mod tests {
         assert!(bus.write2(addr, value).is_err());
     }
 
+    #[test]
+    fn test_words_do_not_wrap_around_the_address_space() {
+        let mut memory = crate::memory::LinearMemory::new(0x10000);
+        assert_eq!(memory.read2(0xFFFF), None);
+        assert!(matches!(
+            memory.write2(0xFFFF, 0x1234),
+            Err(VMError::OutOfBounds(0xFFFF))
+        ));
+        assert_eq!(memory.read(0xFFFF), Some(0));
+        assert_eq!(memory.read(0x0000), Some(0));
+        memory.write2(0xFFFE, 0x1234).unwrap();
+        assert_eq!(memory.read2(0xFFFE), Some(0x1234));
+    }
+
     #[test]
     fn test_copy_success() {
         let mut bus = MockBus::new();
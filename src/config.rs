@@ -0,0 +1,465 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{MEMORY_SIZE, STACK_SIZE, STACK_TOP, START_ADDRESS, VmAddr};
+use crate::error::{Result, VMError};
+use crate::interrupt::VECTOR_TABLE_SIZE;
+use crate::vm::Opcode;
+
+/*
+    VM configuration, everything a run depends on besides the program itself.
+
+    It can be built in code (see `VMBuilder`) or read from a TOML or JSON file, every field is optional in a file
+    and falls back to its default:
+
+        memory_size = 5000
+        load_address = 0x100
+        entry_point = 0x104        # defaults to the entry point of the loaded program
+        max_steps = 10000          # `tick` fails with StepLimitReached after this many instructions
+        trace = "zk"               # "off", "trace" or "zk"
+        zk_state_capacity = 256
+        overflow = "trap"          # "wrap" or "trap"
+        gas_limit = 50000          # `tick` fails with OutOfGas once the instructions cost more than this
+        memory_protection = false  # the default true makes only loaded code executable, code and vectors read-only
+
+        [stack]
+        base = 0x1100
+        top = 0x1200
+
+        [gas_costs]                # per opcode, see `GasCosts` for the defaults
+        alu = 4
+
+        [memory_map.data]          # any segment of `MemoryMap`, see there for the defaults
+        start = 0x1080
+        end = 0x1100
+*/
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub memory_size: usize,          // bytes of the default `LinearMemory`
+    pub load_address: VmAddr,        // where raw words passed to `VM::load_words` are placed
+    pub entry_point: Option<VmAddr>, // overrides the entry point of loaded programs
+    pub stack: StackRegion,
+    pub max_steps: Option<u64>,
+    pub trace: TraceMode,
+    pub zk_state_capacity: Option<usize>, // falls back to the ZK_STATE_CAPACITY environment variable
+    pub overflow: OverflowMode,
+    pub gas_limit: Option<u64>, // no metering when None
+    pub gas_costs: GasCosts,
+    // `VMBuilder::build` wraps the memory in a `ProtectedBus`, see `protection.rs`. On by default, legacy programs
+    // that keep data between their instructions or write their own trap vectors turn it off
+    pub memory_protection: bool,
+    pub memory_map: MemoryMap,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            memory_size: MEMORY_SIZE,
+            load_address: START_ADDRESS,
+            entry_point: None,
+            stack: StackRegion {
+                base: STACK_TOP - STACK_SIZE,
+                top: STACK_TOP,
+            },
+            max_steps: None,
+            trace: TraceMode::Off,
+            zk_state_capacity: None,
+            overflow: OverflowMode::Wrap,
+            gas_limit: None,
+            gas_costs: GasCosts::default(),
+            memory_protection: true,
+            memory_map: MemoryMap::default(),
+        }
+    }
+}
+
+/// Memory reserved for the stack, `RSP` starts at `top` and may go down to `base`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StackRegion {
+    pub base: VmAddr,
+    pub top: VmAddr,
+}
+
+/// Address range of a segment, `end` is exclusive and may be 0x10000 for a segment ending at the top of memory
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Segment {
+    pub start: VmAddr,
+    pub end: usize,
+}
+
+impl Segment {
+    pub const fn new(start: VmAddr, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    pub fn contains(&self, addr: VmAddr) -> bool {
+        self.start <= addr && usize::from(addr) < self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(usize::from(self.start))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Prefix,
+    Code,
+    Rodata,
+    Data,
+    Stack,
+    Mmio,
+}
+
+impl SegmentKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegmentKind::Prefix => "prefix",
+            SegmentKind::Code => "code",
+            SegmentKind::Rodata => "rodata",
+            SegmentKind::Data => "data",
+            SegmentKind::Stack => "stack",
+            SegmentKind::Mmio => "mmio",
+        }
+    }
+}
+
+/// Where things live in the address space, the stack is the `stack` of the `Config`. The defaults:
+///
+/// ```text
+/// 0x0000 - 0x00ff     prefix  reserved, the vector table is at its bottom (see `interrupt.rs`)
+/// 0x0100 - 0x0fff     code    programs are loaded here, at `load_address`
+/// 0x1000 - 0x107f     rodata  constants, read-only with `memory_protection`
+/// 0x1080 - 0x10ff     data    STORE_OUT writes the output to its first word, variables and heap follow
+/// 0x1100 - 0x11ff     stack
+/// 0xff00 - 0xffff     mmio    peripherals of a `SystemBus`
+/// ```
+///
+/// Code gets most of the default `MEMORY_SIZE` bytes, memory above the stack is not assigned to any segment.
+/// Segments may not overlap, the assembler places `.data`
+/// right after the output word, at `data_address`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryMap {
+    pub prefix: Segment,
+    pub code: Segment,
+    pub rodata: Segment,
+    pub data: Segment,
+    pub mmio: Segment,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self {
+            prefix: Segment::new(0x0000, usize::from(START_ADDRESS)),
+            code: Segment::new(START_ADDRESS, 0x1000),
+            rodata: Segment::new(0x1000, 0x1080),
+            data: Segment::new(0x1080, usize::from(STACK_TOP - STACK_SIZE)),
+            mmio: Segment::new(0xFF00, 0x10000),
+        }
+    }
+}
+
+impl MemoryMap {
+    // Where STORE_OUT writes, the first word of the data segment
+    pub fn output_address(&self) -> VmAddr {
+        self.data.start
+    }
+
+    // Where the assembler places `.data`, right after the output word so STORE_OUT leaves variables alone
+    pub fn data_address(&self) -> VmAddr {
+        self.data.start.saturating_add(2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceMode {
+    Off,
+    Trace, // record a `TraceEntry` per instruction
+    Zk,    // trace and derive the ZK program state on HALT
+}
+
+/// What ADD, ADDI16 and the ALU do when a result sets the signed overflow flag
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowMode {
+    Wrap, // keep the wrapped result, like a real 16-bit ALU
+    Trap, // fail with `VMError::Overflow`, leaving the destination and the flags untouched
+}
+
+/// Gas charged for one instruction, by opcode.
+/// The defaults are the number of bus accesses the instruction makes, fetching included.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GasCosts {
+    pub halt: u64,
+    pub copy: u64,
+    pub load: u64,
+    pub write: u64,
+    pub add: u64,
+    pub load_imm: u64,
+    pub store_out: u64,
+    pub cmp: u64,
+    pub jmp: u64,
+    pub alu: u64,
+    pub stack: u64,
+    pub wide: u64,
+    pub int: u64,
+    pub syscall: u64, // host functions do not charge for their own work
+}
+
+impl Default for GasCosts {
+    fn default() -> Self {
+        Self {
+            halt: 1,
+            copy: 1,
+            load: 2,
+            write: 2,
+            add: 1,
+            load_imm: 1,
+            store_out: 2,
+            cmp: 1,
+            jmp: 1,
+            alu: 1,
+            stack: 2,
+            wide: 2,
+            int: 3, // IRET pops two words
+            syscall: 1,
+        }
+    }
+}
+
+impl GasCosts {
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        match opcode {
+            Opcode::HALT => self.halt,
+            Opcode::COPY => self.copy,
+            Opcode::LOAD => self.load,
+            Opcode::WRITE => self.write,
+            Opcode::ADD => self.add,
+            Opcode::LOAD_IMM => self.load_imm,
+            Opcode::STORE_OUT => self.store_out,
+            Opcode::CMP => self.cmp,
+            Opcode::JMP => self.jmp,
+            Opcode::ALU => self.alu,
+            Opcode::STACK => self.stack,
+            Opcode::WIDE => self.wide,
+            Opcode::INT => self.int,
+            Opcode::SYSCALL => self.syscall,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let config: Config =
+            toml::from_str(text).map_err(|error| VMError::InvalidConfig(error.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let config: Config = serde_json::from_str(text)
+            .map_err(|error| VMError::InvalidConfig(error.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // The format is picked by the extension, `.json` is JSON and anything else TOML
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&text),
+            _ => Self::from_toml(&text),
+        }
+    }
+
+    pub fn segment(&self, kind: SegmentKind) -> Segment {
+        let map = &self.memory_map;
+        match kind {
+            SegmentKind::Prefix => map.prefix,
+            SegmentKind::Code => map.code,
+            SegmentKind::Rodata => map.rodata,
+            SegmentKind::Data => map.data,
+            SegmentKind::Stack => Segment::new(self.stack.base, usize::from(self.stack.top)),
+            SegmentKind::Mmio => map.mmio,
+        }
+    }
+
+    // Every segment in address order of the defaults, empty ones included
+    pub fn segments(&self) -> impl Iterator<Item = (SegmentKind, Segment)> + '_ {
+        [
+            SegmentKind::Prefix,
+            SegmentKind::Code,
+            SegmentKind::Rodata,
+            SegmentKind::Data,
+            SegmentKind::Stack,
+            SegmentKind::Mmio,
+        ]
+        .into_iter()
+        .map(|kind| (kind, self.segment(kind)))
+    }
+
+    pub fn segment_at(&self, addr: VmAddr) -> Option<(SegmentKind, Segment)> {
+        self.segments().find(|(_, segment)| segment.contains(addr))
+    }
+
+    // Where STORE_OUT writes, see `MemoryMap::output_address`
+    pub fn output_address(&self) -> VmAddr {
+        self.memory_map.output_address()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(VMError::InvalidConfig(message.to_string()));
+        if self.memory_size > usize::from(VmAddr::MAX) + 1 {
+            return invalid("memory_size exceeds the 16-bit address space");
+        }
+        if !self.load_address.is_multiple_of(2) {
+            return invalid("load_address must be word aligned");
+        }
+        if self
+            .entry_point
+            .is_some_and(|entry| !entry.is_multiple_of(2))
+        {
+            return invalid("entry_point must be word aligned");
+        }
+        let StackRegion { base, top } = self.stack;
+        if base > top || !base.is_multiple_of(2) || !top.is_multiple_of(2) {
+            return invalid("stack needs word aligned base <= top");
+        }
+        self.validate_memory_map()
+    }
+
+    fn validate_memory_map(&self) -> Result<()> {
+        let invalid = |message: String| Err(VMError::InvalidConfig(message));
+        let segments: Vec<_> = self.segments().collect();
+        for (kind, segment) in &segments {
+            let name = kind.name();
+            if usize::from(segment.start) > segment.end
+                || segment.end > usize::from(VmAddr::MAX) + 1
+                || !segment.start.is_multiple_of(2)
+                || !segment.end.is_multiple_of(2)
+            {
+                return invalid(format!("{name} needs word aligned start <= end <= 0x10000"));
+            }
+            if let Some((other, _)) = segments.iter().find(|(other, other_segment)| {
+                other != kind
+                    && !other_segment.is_empty()
+                    && !segment.is_empty()
+                    && usize::from(other_segment.start) < segment.end
+                    && usize::from(segment.start) < other_segment.end
+            }) {
+                return invalid(format!("{name} overlaps {}", other.name()));
+            }
+        }
+        if self.memory_map.prefix.start != 0 || self.memory_map.prefix.end < VECTOR_TABLE_SIZE {
+            return invalid("prefix must start at 0 and hold the vector table".to_string());
+        }
+        if !self.memory_map.code.contains(self.load_address) {
+            return invalid("load_address must lie in the code segment".to_string());
+        }
+        if self
+            .entry_point
+            .is_some_and(|entry| !self.memory_map.code.contains(entry))
+        {
+            return invalid("entry_point must lie in the code segment".to_string());
+        }
+        if self.memory_map.data.len() < 2 {
+            return invalid("data needs room for the output word".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_files_use_defaults() {
+        let config = Config::from_toml(
+            "
+            memory_size = 0x2000
+            trace = \"zk\"
+            overflow = \"trap\"
+            [stack]
+            base = 0x1800
+            top = 0x1900
+            ",
+        )
+        .unwrap();
+        assert_eq!(config.memory_size, 0x2000);
+        assert_eq!(config.trace, TraceMode::Zk);
+        assert_eq!(config.overflow, OverflowMode::Trap);
+        assert_eq!(config.stack.top, 0x1900);
+        assert_eq!(config.load_address, START_ADDRESS);
+
+        let json = Config::from_json(
+            r#"{"max_steps": 10, "zk_state_capacity": 64, "gas_costs": {"alu": 5}}"#,
+        )
+        .unwrap();
+        assert_eq!(json.gas_costs.cost(Opcode::ALU), 5);
+        assert_eq!(json.gas_costs.cost(Opcode::LOAD), 2);
+        assert_eq!(json.max_steps, Some(10));
+        assert_eq!(json.zk_state_capacity, Some(64));
+        assert_eq!(json.memory_size, Config::default().memory_size);
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        for text in [
+            "memory_size = 0x10002",
+            "load_address = 0x101",
+            "[stack]\nbase = 0x400\ntop = 0x200",
+            "entry_point = 0x105",
+            "unknown = 1",
+            "trace = \"verbose\"",
+            "[gas_costs]\nnop = 1",
+            "[memory_map.data]\nstart = 0x1180\nend = 0x1200",
+            "[memory_map.prefix]\nstart = 0\nend = 0x10",
+            "[memory_map.mmio]\nstart = 0xff00\nend = 0x10002",
+        ] {
+            assert!(
+                matches!(Config::from_toml(text), Err(VMError::InvalidConfig(_))),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_memory_map_segments() {
+        let config = Config::from_toml(
+            "
+            [memory_map.data]
+            start = 0x2000
+            end = 0x2400
+            ",
+        )
+        .unwrap();
+        assert_eq!(config.output_address(), 0x2000);
+        assert_eq!(config.segment(SegmentKind::Data).len(), 0x400);
+        assert_eq!(
+            config.segment(SegmentKind::Stack).start,
+            STACK_TOP - STACK_SIZE
+        );
+        assert_eq!(config.memory_map.code, MemoryMap::default().code);
+
+        let kind = |addr| config.segment_at(addr).map(|(kind, _)| kind);
+        assert_eq!(kind(0x001E), Some(SegmentKind::Prefix));
+        assert_eq!(kind(START_ADDRESS), Some(SegmentKind::Code));
+        assert_eq!(kind(0x1080), None);
+        assert_eq!(kind(0x23FE), Some(SegmentKind::Data));
+        assert_eq!(kind(0xFFFF), Some(SegmentKind::Mmio));
+    }
+}
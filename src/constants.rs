@@ -3,6 +3,13 @@ use ark_ff::PrimeField;
 
 pub static START_ADDRESS: u16 = 0x100; // I use this as start address, so i will first 256 bytes reserved for Program Segment Prefix
 
+// Default size of the linear memory, in bytes
+pub static MEMORY_SIZE: usize = 5000;
+
+// Default stack region, the stack grows down from STACK_TOP and may use STACK_SIZE bytes
+pub static STACK_TOP: u16 = 0x1200;
+pub static STACK_SIZE: u16 = 0x100;
+
 // VM word is currently 16-bit since i build 16bit VM
 pub type VMWord = u16;
 pub type VmAddr = VMWord;
@@ -0,0 +1,563 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+
+use crate::asm::parse_number;
+use crate::constants::{VMWord, VmAddr};
+use crate::disasm::{decode_instruction, disassemble_instructions};
+use crate::image::Symbol;
+use crate::register::RegisterId;
+use crate::vm::{Opcode, VM};
+use crate::watch::{WatchKind, Watchpoint, Watchpoints};
+
+/*
+    Debugger front-end over `VM::tick`.
+
+    Commands are read one per line, from stdin or from a command file, `#` starts a comment:
+
+        step [n]              s    execute n instructions, 1 by default
+        continue              c    run until a breakpoint, HALT or an error
+        break <addr|label>    b    set a breakpoint
+        delete <addr|label>   d    clear a breakpoint
+        breakpoints           bl   list the breakpoints
+        watch <addr|label> [bytes]     stop after an instruction writes the range, 2 bytes by default
+        rwatch / awatch ...            the same for reads / for reads and writes
+        unwatch <id>                   clear a watchpoint
+        watchpoints           wl   list the watchpoints
+        regs                  r    print the registers
+        set <reg> <value>          modify a register
+        mem <addr> [words]    x    dump memory, 8 words by default
+        poke <addr> <value>        write a word to memory
+        disasm [n]            l    disassemble n instructions around PC, 7 by default
+        help                  h
+        quit                  q
+
+    Addresses and values take the assembler number syntax (decimal, 0x.., 0b..), addresses may also be labels
+    from the symbol table of the image.
+*/
+
+const DEFAULT_MEM_WORDS: usize = 8;
+const DEFAULT_LISTING: usize = 7;
+
+pub struct Debugger {
+    pub vm: VM,
+    pub continue_limit: Option<u64>, // `continue` gives up after this many instructions
+    symbols: BTreeMap<String, VmAddr>,
+    breakpoints: BTreeSet<VmAddr>,
+    watchpoints: Watchpoints,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Output(String),
+    Quit,
+}
+
+impl Debugger {
+    // The memory of `vm` is wrapped in a `WatchBus`, so watchpoints work over any bus device
+    pub fn new(mut vm: VM, symbols: &[Symbol]) -> Self {
+        let watchpoints = Watchpoints::new();
+        watchpoints.attach(&mut vm);
+        Self {
+            vm,
+            continue_limit: None,
+            symbols: symbols
+                .iter()
+                .map(|symbol| (symbol.name.clone(), symbol.address))
+                .collect(),
+            breakpoints: BTreeSet::new(),
+            watchpoints,
+        }
+    }
+
+    /// Reads commands until `quit` or the end of the input. In a non-interactive session every command
+    /// is echoed before its output, so a command file produces a readable transcript.
+    pub fn run_session(
+        &mut self,
+        input: impl BufRead,
+        output: &mut impl Write,
+        interactive: bool,
+    ) -> io::Result<()> {
+        if interactive {
+            write!(output, "(vm) ")?;
+            output.flush()?;
+        }
+        for line in input.lines() {
+            let line = line?;
+            let command = line.split('#').next().unwrap_or_default().trim();
+            if !command.is_empty() {
+                if !interactive {
+                    writeln!(output, "(vm) {command}")?;
+                }
+                match self.execute(command) {
+                    Ok(Reply::Output(text)) if text.is_empty() => {}
+                    Ok(Reply::Output(text)) => writeln!(output, "{text}")?,
+                    Ok(Reply::Quit) => return Ok(()),
+                    Err(message) => writeln!(output, "error: {message}")?,
+                }
+            }
+            if interactive {
+                write!(output, "(vm) ")?;
+                output.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, command: &str) -> Result<Reply, String> {
+        let mut parts = command.split_whitespace();
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let args: Vec<&str> = parts.collect();
+
+        let text = match (name.as_str(), args.as_slice()) {
+            ("step" | "s", []) => self.step(1),
+            ("step" | "s", [count]) => self.step(parse_count(count)?),
+            ("continue" | "c", []) => self.resume(),
+            ("break" | "b", [location]) => {
+                let addr = self.address(location)?;
+                self.breakpoints.insert(addr);
+                format!("breakpoint at {}", self.describe_addr(addr))
+            }
+            ("delete" | "d", [location]) => {
+                let addr = self.address(location)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {addr:#06x}"));
+                }
+                format!("deleted breakpoint at {}", self.describe_addr(addr))
+            }
+            ("breakpoints" | "bl", []) => self
+                .breakpoints
+                .iter()
+                .map(|addr| self.describe_addr(*addr))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ("watch" | "rwatch" | "awatch", [location, rest @ ..]) if rest.len() <= 1 => {
+                let kind = match name.as_str() {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let addr = self.address(location)?;
+                let len = match rest {
+                    [bytes] => parse_word(bytes)?,
+                    _ => 2,
+                };
+                let id = self.watchpoints.add(Watchpoint { addr, len, kind });
+                format!(
+                    "watchpoint {id}: {}",
+                    describe_watchpoint(&Watchpoint { addr, len, kind })
+                )
+            }
+            ("unwatch", [id]) => {
+                let id = parse_count(id)?;
+                if self.watchpoints.remove(id).is_none() {
+                    return Err(format!("no watchpoint {id}"));
+                }
+                format!("deleted watchpoint {id}")
+            }
+            ("watchpoints" | "wl", []) => self
+                .watchpoints
+                .list()
+                .iter()
+                .map(|(id, watchpoint)| format!("{id}: {}", describe_watchpoint(watchpoint)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ("regs" | "r", []) => self.registers(),
+            ("set", [register, value]) => {
+                let id = RegisterId::from_name(register)
+                    .ok_or_else(|| format!("unknown register `{register}`"))?;
+                let value = parse_word(value)?;
+                self.vm
+                    .registers
+                    .get_register_mut(id.id())
+                    .map_err(|error| error.to_string())?
+                    .value = value;
+                format!("{}={value:#06x}", id.name())
+            }
+            ("mem" | "x", [location]) => self.memory(self.address(location)?, DEFAULT_MEM_WORDS),
+            ("mem" | "x", [location, count]) => {
+                self.memory(self.address(location)?, parse_count(count)?)
+            }
+            ("poke", [location, value]) => {
+                let addr = self.address(location)?;
+                let value = parse_word(value)?;
+                self.vm
+                    .memory
+                    .write2(addr, value)
+                    .map_err(|error| error.to_string())?;
+                format!("{addr:#06x}: {value:04x}")
+            }
+            ("disasm" | "l", []) => self.listing(DEFAULT_LISTING),
+            ("disasm" | "l", [count]) => self.listing(parse_count(count)?),
+            ("help" | "h", []) => HELP.trim().to_string(),
+            ("quit" | "q", []) => return Ok(Reply::Quit),
+            _ => return Err(format!("cannot parse `{command}`, try `help`")),
+        };
+        Ok(Reply::Output(text))
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<VmAddr> {
+        &self.breakpoints
+    }
+
+    fn step(&mut self, count: usize) -> String {
+        for _ in 0..count {
+            if let Some(stop) = self.tick() {
+                return format!("{stop}\n{}", self.location());
+            }
+        }
+        self.location()
+    }
+
+    // Always executes the current instruction, so continuing from a breakpoint moves past it
+    fn resume(&mut self) -> String {
+        let mut steps = 0;
+        loop {
+            if self.continue_limit.is_some_and(|limit| steps >= limit) {
+                return format!("stopped after {steps} steps\n{}", self.location());
+            }
+            if let Some(stop) = self.tick() {
+                return format!("{stop}\n{}", self.location());
+            }
+            steps += 1;
+            let pc = self.pc();
+            if self.breakpoints.contains(&pc) {
+                return format!(
+                    "breakpoint at {}\n{}",
+                    self.describe_addr(pc),
+                    self.location()
+                );
+            }
+        }
+    }
+
+    // Executes one instruction, returns why execution stopped if it did
+    fn tick(&mut self) -> Option<String> {
+        if self.vm.halted {
+            return Some("the program has halted".to_string());
+        }
+        // Drop the accesses made by inspection commands, only the instruction about to run counts
+        self.watchpoints.take_hits();
+        let pc = self.pc();
+        match self.vm.tick() {
+            Err(error) => Some(format!("error: {error}")),
+            Ok(()) if self.vm.halted => Some("halted".to_string()),
+            Ok(()) => {
+                let hits = self.watchpoints.take_hits();
+                if hits.is_empty() {
+                    return None;
+                }
+                let lines: Vec<String> = hits
+                    .iter()
+                    .map(|hit| format!("{hit} (pc {pc:#06x})"))
+                    .collect();
+                Some(lines.join("\n"))
+            }
+        }
+    }
+
+    fn pc(&self) -> VmAddr {
+        self.register(RegisterId::RPC)
+    }
+
+    fn register(&self, id: RegisterId) -> VMWord {
+        self.vm
+            .registers
+            .get_register_read_only(id.id())
+            .map(|reg| reg.value)
+            .unwrap_or_default()
+    }
+
+    // The instruction RPC points at
+    fn location(&self) -> String {
+        let pc = self.pc();
+        let text = self
+            .vm
+            .memory
+            .peek2(pc)
+            .and_then(|word| {
+                let wide =
+                    Opcode::try_from((word >> 12) as u8).is_ok_and(|op| op.has_extension_word());
+                let ext = if wide {
+                    self.vm.memory.peek2(pc.checked_add(2)?)
+                } else {
+                    None
+                };
+                decode_instruction(pc, word, ext)
+            })
+            .unwrap_or_else(|| "??".to_string());
+        format!("=> {}: {text}", self.describe_addr(pc))
+    }
+
+    fn registers(&self) -> String {
+        self.vm
+            .registers
+            .register_map
+            .values()
+            .map(|reg| format!("{}={:#06x}", reg.id.name(), reg.value))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn memory(&self, start: VmAddr, count: usize) -> String {
+        let words: Vec<VMWord> = (start..=VmAddr::MAX)
+            .step_by(2)
+            .take(count)
+            .map_while(|addr| self.vm.memory.peek2(addr))
+            .collect();
+        if words.is_empty() {
+            return format!("{start:#06x}: out of bounds");
+        }
+        words
+            .chunks(DEFAULT_MEM_WORDS)
+            .enumerate()
+            .map(|(row, chunk)| {
+                let addr = usize::from(start) + row * DEFAULT_MEM_WORDS * 2;
+                let raw: Vec<String> = chunk.iter().map(|word| format!("{word:04x}")).collect();
+                format!("{addr:#06x}: {}", raw.join(" "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Instructions are variable length, so decoding backwards is a guess: the listing starts at the farthest word
+    // before RPC from which every instruction decodes and one of them starts exactly at RPC.
+    // It stays inside the segment of RPC, so the words of neighbouring data segments are not decoded as code
+    fn listing(&self, count: usize) -> String {
+        let pc = self.pc();
+        let before = count / 2;
+        let segment = self.vm.config.segment_at(pc).map(|(_, segment)| segment);
+        let read = |start: VmAddr| -> Vec<VMWord> {
+            (start..=VmAddr::MAX)
+                .step_by(2)
+                .take(2 * (count + before))
+                .take_while(|addr| segment.is_none_or(|segment| segment.contains(*addr)))
+                .map_while(|addr| self.vm.memory.peek2(addr))
+                .collect()
+        };
+
+        let instructions = (0..=2 * before)
+            .rev()
+            .filter_map(|back| pc.checked_sub((back * 2) as VmAddr))
+            .filter(|start| segment.is_none_or(|segment| segment.contains(*start)))
+            .map(|start| disassemble_instructions(&read(start), start))
+            .find_map(|instructions| {
+                let at_pc = instructions.iter().position(|ix| ix.addr == pc)?;
+                if instructions[..at_pc].iter().any(|ix| ix.text.is_none()) {
+                    return None;
+                }
+                Some(instructions.into_iter().skip(at_pc.saturating_sub(before)))
+            });
+        let Some(instructions) = instructions else {
+            return format!("{pc:#06x}: out of bounds");
+        };
+
+        let mut lines = vec![];
+        for instruction in instructions.take(count) {
+            lines.extend(
+                self.labels_at(instruction.addr)
+                    .map(|name| format!("{name}:")),
+            );
+            let marker = if instruction.addr == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&instruction.addr) {
+                "*"
+            } else {
+                " "
+            };
+            lines.push(format!("{marker}{breakpoint}{instruction}"));
+        }
+        lines.join("\n")
+    }
+
+    fn labels_at(&self, addr: VmAddr) -> impl Iterator<Item = &str> {
+        self.symbols
+            .iter()
+            .filter(move |(_, symbol_addr)| **symbol_addr == addr)
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn describe_addr(&self, addr: VmAddr) -> String {
+        match self.labels_at(addr).next() {
+            Some(name) => format!("{addr:#06x} <{name}>"),
+            None => format!("{addr:#06x}"),
+        }
+    }
+
+    fn address(&self, location: &str) -> Result<VmAddr, String> {
+        if let Some(addr) = self.symbols.get(location) {
+            return Ok(*addr);
+        }
+        parse_number(location)
+            .and_then(|n| VmAddr::try_from(n).ok())
+            .ok_or_else(|| format!("`{location}` is neither an address nor a label"))
+    }
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let kind = match watchpoint.kind {
+        WatchKind::Read => "read",
+        WatchKind::Write => "write",
+        WatchKind::Access => "access",
+    };
+    format!("{kind} {:#06x}, {} bytes", watchpoint.addr, watchpoint.len)
+}
+
+const HELP: &str = "
+step [n]              execute n instructions
+continue              run until a breakpoint, HALT or an error
+break <addr|label>    set a breakpoint
+delete <addr|label>   clear a breakpoint
+breakpoints           list the breakpoints
+watch <addr> [bytes]  stop when the range is written, rwatch / awatch for reads / any access
+unwatch <id>          clear a watchpoint
+watchpoints           list the watchpoints
+regs                  print the registers
+set <reg> <value>     modify a register
+mem <addr> [words]    dump memory
+poke <addr> <value>   write a word to memory
+disasm [n]            disassemble around PC
+quit                  leave the debugger
+";
+
+fn parse_count(text: &str) -> Result<usize, String> {
+    text.parse().map_err(|_| format!("`{text}` is not a count"))
+}
+
+// Negative values are accepted and stored as their two's complement
+fn parse_word(text: &str) -> Result<VMWord, String> {
+    parse_number(text)
+        .filter(|n| (i64::from(i16::MIN)..=i64::from(VMWord::MAX)).contains(n))
+        .map(|n| n as VMWord)
+        .ok_or_else(|| format!("`{text}` is not a 16-bit value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_with_symbols;
+    use crate::constants::MEMORY_SIZE;
+    use crate::image::ExecutableImage;
+    use crate::memory::LinearMemory;
+
+    const SOURCE: &str = "
+        start:  COPY R0, 2
+                COPY R1, R3
+        loop:   ADD R1, R0
+                CMP R1, 6
+                JLT loop
+        done:   STORE_OUT R1
+                HALT
+    ";
+
+    fn debugger() -> Debugger {
+        let image = ExecutableImage::from(&assemble_with_symbols(SOURCE).unwrap());
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(MEMORY_SIZE)));
+        vm.load_program(&image.to_program()).unwrap();
+        Debugger::new(vm, image.symbols.as_deref().unwrap_or_default())
+    }
+
+    fn output(debugger: &mut Debugger, command: &str) -> String {
+        match debugger.execute(command) {
+            Ok(Reply::Output(text)) => text,
+            other => panic!("unexpected reply {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_breakpoints_by_label_and_address() {
+        let mut debugger = debugger();
+        output(&mut debugger, "break loop");
+        output(&mut debugger, "b 0x010a");
+        assert_eq!(debugger.breakpoints().len(), 2);
+
+        let stop = output(&mut debugger, "continue");
+        assert!(stop.starts_with("breakpoint at 0x0104 <loop>"));
+        assert!(stop.contains("=> 0x0104 <loop>: ADD R1, R0"));
+        assert!(output(&mut debugger, "c").starts_with("breakpoint at 0x0104 <loop>"));
+
+        output(&mut debugger, "delete loop");
+        assert!(output(&mut debugger, "c").starts_with("breakpoint at 0x010a <done>"));
+        output(&mut debugger, "d 0x010a");
+        assert!(output(&mut debugger, "c").starts_with("halted"));
+        assert_eq!(output(&mut debugger, "mem 0x1080 1"), "0x1080: 0006");
+        assert!(output(&mut debugger, "step").starts_with("the program has halted"));
+    }
+
+    #[test]
+    fn test_step_and_modify_state() {
+        let mut debugger = debugger();
+        assert_eq!(
+            output(&mut debugger, "step 2"),
+            "=> 0x0104 <loop>: ADD R1, R0"
+        );
+        assert!(output(&mut debugger, "regs").starts_with("R0=0x0002 R1=0x0000"));
+
+        output(&mut debugger, "set R1 -1");
+        assert_eq!(debugger.register(RegisterId::RR1), 0xFFFF);
+        output(&mut debugger, "poke 0x200 0xbeef");
+        assert_eq!(output(&mut debugger, "x 0x200 2"), "0x0200: beef 0000");
+
+        let listing = output(&mut debugger, "disasm 3");
+        assert!(listing.starts_with("   COPY R1, R3"));
+        assert!(listing.contains("loop:\n=> ADD R1, R0"));
+
+        assert!(debugger.execute("set R9 1").is_err());
+        assert!(debugger.execute("break nowhere").is_err());
+        assert!(debugger.execute("frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_watchpoints_pause_execution() {
+        let mut debugger = debugger();
+        assert_eq!(
+            output(&mut debugger, "watch 0x1080"),
+            "watchpoint 1: write 0x1080, 2 bytes"
+        );
+        output(&mut debugger, "rwatch 0x300 4");
+        // Inspecting memory reads it, which must not count as an access by the program
+        output(&mut debugger, "mem 0x300");
+
+        let stop = output(&mut debugger, "continue");
+        assert!(stop.starts_with("watchpoint 1: write 0x1080: 0x0000 -> 0x0006 (pc 0x010a)"));
+        assert!(stop.ends_with("=> 0x010c: HALT"));
+        assert_eq!(output(&mut debugger, "wl").lines().count(), 2);
+        output(&mut debugger, "unwatch 1");
+        assert!(debugger.execute("unwatch 1").is_err());
+        assert!(output(&mut debugger, "c").starts_with("halted"));
+    }
+
+    #[test]
+    fn test_commands_at_the_top_of_memory() {
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(0x10000)));
+        vm.load_words(&[0]).unwrap();
+        let mut debugger = Debugger::new(vm, &[]);
+        output(&mut debugger, "poke 0xfffe 0x1065");
+        assert_eq!(
+            output(&mut debugger, "mem 0xfff0 16"),
+            "0xfff0: 0000 0000 0000 0000 0000 0000 0000 1065"
+        );
+
+        output(&mut debugger, "set RPC 0xfffe");
+        assert!(
+            output(&mut debugger, "disasm")
+                .ends_with("=> COPY R0, 5                  ; 0xfffe: 1065")
+        );
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let mut debugger = debugger();
+        let script = "break done # stop before the output\ncontinue\nregs\nquit\nstep\n";
+        let mut transcript = Vec::new();
+        debugger
+            .run_session(script.as_bytes(), &mut transcript, false)
+            .unwrap();
+        let transcript = String::from_utf8(transcript).unwrap();
+
+        assert!(transcript.starts_with("(vm) break done\nbreakpoint at 0x010a <done>\n"));
+        assert!(transcript.contains("R1=0x0006"));
+        assert!(transcript.trim_end().ends_with("(vm) quit"));
+        assert!(!debugger.vm.halted);
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+
+use crate::asm::{InstructionSpec, OperandForm, instruction_set};
+use crate::bus::BusDevice;
+use crate::config::MemoryMap;
+use crate::constants::{START_ADDRESS, VMWord, VmAddr};
+use crate::image::ExecutableImage;
+use crate::register::RegisterId;
+use crate::vm::Opcode;
+
+/*
+    Disassembler, the inverse of `asm::assemble`.
+
+    Every instruction is printed in the assembler syntax followed by a comment with its address and raw words,
+    so a listing can be fed back into the assembler. Words that do not decode to a canonical instruction
+    (unknown opcode, unknown register, fields the instruction ignores set to non-zero) are printed as `.word`
+    instead of failing, which keeps the round trip exact for data mixed with code.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+    pub addr: VmAddr,
+    pub words: Vec<VMWord>, // the instruction word and its extension word, if any
+    pub text: Option<String>, // None when the word cannot be decoded
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let raw: Vec<String> = self.words.iter().map(|w| format!("{w:04x}")).collect();
+        match &self.text {
+            Some(text) => write!(f, "{text:<28}; {:#06x}: {}", self.addr, raw.join(" ")),
+            None => write!(
+                f,
+                "{:<28}; {:#06x}: {} (undecodable)",
+                format!(".word {:#06x}", self.words[0]),
+                self.addr,
+                raw.join(" ")
+            ),
+        }
+    }
+}
+
+/// Decodes one instruction located at `addr` into assembler syntax, `ext` is the word following it.
+/// Returns None for words the assembler would never produce.
+pub fn decode_instruction(addr: VmAddr, word: VMWord, ext: Option<VMWord>) -> Option<String> {
+    let opcode = Opcode::try_from((word >> 12) as u8).ok()?;
+    let dst = ((word & 0x0F00) >> 8) as u8;
+    let src = ((word & 0x00F0) >> 4) as u8;
+    let imm = (word & 0x000F) as u8;
+
+    let spec = instruction_set().into_iter().find(|spec| {
+        spec.opcode == opcode
+            && spec.fixed_dst.is_none_or(|fixed| fixed == dst)
+            && spec.fixed_imm.is_none_or(|fixed| fixed == imm)
+    })?;
+    let InstructionSpec {
+        mnemonic,
+        fixed_dst,
+        fixed_imm,
+        form,
+        ..
+    } = spec;
+
+    // Fields that are neither fixed by the mnemonic nor used by the operand form must be zero
+    let unused_dst = if fixed_dst.is_none() { dst } else { 0 };
+    let unused_imm = if fixed_imm.is_none() { imm } else { 0 };
+
+    let operands = match form {
+        OperandForm::None => {
+            if unused_dst != 0 || src != 0 || unused_imm != 0 {
+                return None;
+            }
+            vec![]
+        }
+        OperandForm::DstSrc => {
+            // The imm nibble only has a meaning when one of the slots is RIM
+            let rim = RegisterId::RIM.id();
+            if imm != 0 && dst != rim && src != rim {
+                return None;
+            }
+            vec![register_or_imm(dst, imm)?, register_or_imm(src, imm)?]
+        }
+        OperandForm::DstImm => {
+            if src != 0 {
+                return None;
+            }
+            vec![register(dst)?, imm.to_string()]
+        }
+        OperandForm::Src => {
+            if unused_dst != 0 || unused_imm != 0 {
+                return None;
+            }
+            vec![register(src)?]
+        }
+        OperandForm::SrcOrImm => {
+            if unused_dst != 0 || (imm != 0 && src != RegisterId::RIM.id()) {
+                return None;
+            }
+            vec![register_or_imm(src, imm)?]
+        }
+        OperandForm::Dst => {
+            if src != 0 || unused_imm != 0 {
+                return None;
+            }
+            vec![register(dst)?]
+        }
+        OperandForm::DstSrcRegisters => vec![register(dst)?, register(src)?],
+        OperandForm::Jump => {
+            if src == RegisterId::RIM.id() {
+                // Relative jumps are shown with their absolute target, like the assembler expects them
+                let offset = i32::from(((imm as i8) << 4) >> 4);
+                let target = i32::from(addr) + 2 + offset * 2;
+                vec![format!("{:#06x}", VmAddr::try_from(target).ok()?)]
+            } else {
+                if imm != 0 {
+                    return None;
+                }
+                vec![register(src)?]
+            }
+        }
+        OperandForm::DstWide => {
+            if src != 0 {
+                return None;
+            }
+            vec![register(dst)?, format!("{:#06x}", ext?)]
+        }
+        OperandForm::JumpWide | OperandForm::Wide => {
+            if unused_dst != 0 || src != 0 {
+                return None;
+            }
+            vec![format!("{:#06x}", ext?)]
+        }
+    };
+
+    if operands.is_empty() {
+        Some(mnemonic)
+    } else {
+        Some(format!("{mnemonic} {}", operands.join(", ")))
+    }
+}
+
+pub fn disassemble_instructions(words: &[VMWord], base: VmAddr) -> Vec<DisassembledInstruction> {
+    let mut instructions = vec![];
+    let mut idx = 0;
+    while idx < words.len() {
+        let Some(addr) = base.checked_add((idx * 2) as VmAddr) else {
+            break;
+        };
+        let word = words[idx];
+        let wide = Opcode::try_from((word >> 12) as u8).is_ok_and(|op| op.has_extension_word());
+        let ext = if wide {
+            words.get(idx + 1).copied()
+        } else {
+            None
+        };
+
+        match decode_instruction(addr, word, ext) {
+            Some(text) => {
+                let mut raw = vec![word];
+                raw.extend(ext);
+                idx += raw.len();
+                instructions.push(DisassembledInstruction {
+                    addr,
+                    words: raw,
+                    text: Some(text),
+                });
+            }
+            None => {
+                idx += 1;
+                instructions.push(DisassembledInstruction {
+                    addr,
+                    words: vec![word],
+                    text: None,
+                });
+            }
+        }
+    }
+    instructions
+}
+
+/// Annotated listing of `words`, the first word being located at `base`
+pub fn disassemble(words: &[VMWord], base: VmAddr) -> String {
+    listing(&disassemble_instructions(words, base))
+}
+
+/// Annotated listing of the words stored on the bus in `start..end`, peeked so device registers are left as they are
+pub fn disassemble_bus(bus: &dyn BusDevice, start: VmAddr, end: VmAddr) -> String {
+    let words: Vec<VMWord> = (start..end)
+        .step_by(2)
+        .map_while(|addr| bus.peek2(addr))
+        .collect();
+    disassemble(&words, start)
+}
+
+/// Listing of an executable image that assembles back to the same sections,
+/// symbols are printed as labels and the data section follows a `.data` directive with its address.
+/// Only the code segment of `map` is decoded, code words outside it are printed as `.word` like the data.
+pub fn disassemble_image(image: &ExecutableImage, map: &MemoryMap) -> String {
+    let label = |addr: VmAddr| -> String {
+        image
+            .symbols
+            .iter()
+            .flatten()
+            .filter(|symbol| symbol.address == addr)
+            .map(|symbol| format!("{}:\n", symbol.name))
+            .collect()
+    };
+
+    let mut out = String::new();
+    if image.code.address != START_ADDRESS {
+        out.push_str(&format!(".org {:#06x}\n", image.code.address));
+    }
+    let code = (image.code.address..).step_by(2).zip(&image.code.words);
+    let decoded = code
+        .clone()
+        .take_while(|(addr, _)| map.code.contains(*addr))
+        .count();
+    let words = &image.code.words[..decoded];
+    for instruction in disassemble_instructions(words, image.code.address) {
+        out.push_str(&label(instruction.addr));
+        out.push_str(&format!("{instruction}\n"));
+    }
+    let word_line = |(addr, word): (VmAddr, &VMWord)| {
+        format!(
+            "{}{:<28}; {addr:#06x}\n",
+            label(addr),
+            format!(".word {word:#06x}")
+        )
+    };
+    out.extend(code.skip(decoded).map(word_line));
+
+    if !image.data.words.is_empty() {
+        out.push_str(&format!(".data {:#06x}\n", image.data.address));
+        let data = (image.data.address..).step_by(2).zip(&image.data.words);
+        out.extend(data.map(word_line));
+    }
+    out
+}
+
+fn listing(instructions: &[DisassembledInstruction]) -> String {
+    instructions
+        .iter()
+        .map(|instruction| format!("{instruction}\n"))
+        .collect()
+}
+
+fn register(id: u8) -> Option<String> {
+    RegisterId::try_from(id)
+        .ok()
+        .map(|reg| reg.name().to_string())
+}
+
+// Mirrors `VM::resolve_register_or_immediate`, RIM with a non-zero imm is the literal itself
+fn register_or_imm(id: u8, imm: u8) -> Option<String> {
+    if id == RegisterId::RIM.id() && imm != 0 {
+        Some(imm.to_string())
+    } else {
+        register(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, assemble_with_symbols};
+    use crate::memory::LinearMemory;
+    use crate::uart::{SharedBuffer, UART_DATA, UART_STATUS, Uart};
+
+    const SOURCE: &str = "
+                LOAD_IMM RIM, 5
+                COPY R0, RIM
+                COPY R1, 3
+                WRITE 8, R0
+        loop:   ADD R0, R1
+                CMP R0, 15
+                JLT loop
+                JNE R2
+                SUB R0, R1
+                NOT R3
+                PUSH R0
+                POP RFLAGS
+                CALL R1
+                SYSCALL 3
+                SYSCALL R1
+                LOAD_IMM16 R2, 0xbeef
+                ADDI16 RSP, -2
+                JGEU16 loop
+                CALL16 end
+                STORE_OUT R0
+        end:    RET
+                HALT
+    ";
+
+    #[test]
+    fn test_round_trips_through_the_assembler() {
+        let words = assemble(SOURCE).unwrap();
+        let listing = disassemble(&words, START_ADDRESS);
+        assert_eq!(assemble(&listing).unwrap(), words);
+        assert!(listing.contains("JLT 0x0108"));
+        assert!(listing.contains("LOAD_IMM16 R2, 0xbeef"));
+        assert!(listing.contains("SYSCALL 3 "));
+    }
+
+    #[test]
+    fn test_undecodable_words_are_flagged() {
+        // Opcode 0xF does not exist, COPY R0, R1 with a stray imm is not canonical, the wide jump misses its literal
+        let words = [0xF000, 0x1013, 0xB002];
+        let instructions = disassemble_instructions(&words, START_ADDRESS);
+        assert_eq!(instructions.len(), 3);
+        assert!(instructions.iter().all(|ix| ix.text.is_none()));
+
+        let listing = disassemble(&words, START_ADDRESS);
+        assert!(listing.starts_with(".word 0xf000"));
+        assert!(listing.contains("(undecodable)"));
+        assert_eq!(assemble(&listing).unwrap(), words);
+    }
+
+    #[test]
+    fn test_disassembles_bus_region() {
+        let words = assemble(SOURCE).unwrap();
+        let mut memory = LinearMemory::new(1024);
+        memory.load_at(START_ADDRESS, &words).unwrap();
+        let listing = disassemble_bus(&memory, START_ADDRESS, START_ADDRESS + 4);
+        assert_eq!(listing.lines().count(), 2);
+        assert!(listing.starts_with("LOAD_IMM RIM, 5"));
+        assert!(listing.contains("; 0x0100: 5605"));
+
+        // Listing a device region does not consume its input
+        let uart = Uart::new(SharedBuffer::new(), &b"hi"[..]);
+        let listing = disassemble_bus(&uart, UART_DATA, UART_STATUS);
+        assert!(listing.starts_with(".word 0x0068"));
+        assert_eq!(uart.read2(UART_DATA), Some(u16::from(b'h')));
+    }
+
+    #[test]
+    fn test_disassembled_image_reassembles() {
+        let assembly = assemble_with_symbols(
+            "
+            start:  LOAD_IMM16 R0, value
+                    LOAD R1, R0
+                    HALT
+            .data
+            value:  .word 42
+            ",
+        )
+        .unwrap();
+        let image = ExecutableImage::from(&assembly);
+        let listing = disassemble_image(&image, &MemoryMap::default());
+        assert!(listing.starts_with("start:\nLOAD_IMM16 R0, 0x1082"));
+        assert!(listing.contains(".data 0x1082\nvalue:\n.word 0x002a"));
+
+        let reassembled = assemble_with_symbols(&listing).unwrap();
+        assert_eq!(ExecutableImage::from(&reassembled), image);
+    }
+
+    #[test]
+    fn test_image_listing_stops_at_the_code_segment() {
+        // The code section runs past the code segment into rodata, its last word is not decoded
+        let assembly =
+            assemble_with_symbols(".org 0xffc\nHALT\nCOPY R0, 5\nk: .word 0x1015").unwrap();
+        let image = ExecutableImage::from(&assembly);
+        let listing = disassemble_image(&image, &MemoryMap::default());
+        assert!(listing.contains("COPY R0, 5                  ; 0x0ffe: 1065"));
+        assert!(listing.ends_with("k:\n.word 0x1015                ; 0x1000\n"));
+
+        let reassembled = assemble_with_symbols(&listing).unwrap();
+        assert_eq!(ExecutableImage::from(&reassembled), image);
+    }
+}
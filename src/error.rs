@@ -1,20 +1,59 @@
-use derive_more::{Display, From};
+use std::fmt;
+
+use derive_more::From;
+
+use crate::asm::AsmError;
+use crate::constants::{VMWord, VmAddr};
+use crate::disasm::decode_instruction;
+use crate::protection::Access;
+use crate::vm::Opcode;
 
 pub type Result<T> = core::result::Result<T, VMError>;
 
-#[derive(Debug, Display, From)]
-#[display("{self:?}")]
+#[derive(Debug, From)]
 pub enum VMError {
     // memory
-    OutOfBounds,
+    OutOfBounds(VmAddr), // the address that could not be accessed
+    ProgramDoesNotFit,
+    CodeOutsideSegment {
+        start: VmAddr,
+        end: usize,
+    }, // the code of the program, it has to lie in the code segment of the memory map
+    InvalidEntryPoint,
+    Unmapped(VmAddr), // no device is mapped at the address
+    ProtectionFault {
+        addr: VmAddr,
+        kind: Access,
+    }, // the permissions of the address deny the access
+    PageFault {
+        addr: VmAddr,
+        kind: Access,
+    }, // the page table entry of the virtual address denies the access
+    InvalidBusMapping(String),
 
     // register
-    UnknownRegister,
+    UnknownRegister(u8),
 
     // vm
     Halted,
+    StepLimitReached,
+    OutOfGas,
     MemoryReadError,
     OpcodeDoesNotExist,
+    ConditionDoesNotExist,
+    AluFunctionDoesNotExist,
+    StackOperationDoesNotExist,
+    WideOperationDoesNotExist,
+    IntOperationDoesNotExist,
+    PrivilegedInstruction,
+    InvalidInterruptLine(u8),
+    UnknownSyscall(VMWord),
+    Aborted(VMWord), // exit code passed to the abort helper
+
+    // stack
+    StackOverflow,
+    StackUnderflow,
+    InvalidJumpTarget(VmAddr),
 
     // bus
     AddInstructionFail,
@@ -22,10 +61,27 @@ pub enum VMError {
 
     // Math
     Overflow,
+    DivideByZero,
 
     // zk
     MemoryTypeIsNotSupported,
 
+    // config
+    InvalidConfig(String),
+
+    // image
+    MalformedImage,
+    InvalidImageMagic,
+    UnsupportedImageVersion,
+    InvalidImageLayout,
+
+    // asm
+    #[from]
+    Asm(AsmError),
+
+    // An error raised while executing an instruction, see `Fault`
+    Fault(Box<Fault>),
+
     // -- Externals
     #[from]
     Io(std::io::Error),
@@ -34,11 +90,114 @@ pub enum VMError {
 impl VMError {
     pub fn message(&self) -> &'static str {
         match self {
-            VMError::UnknownRegister => "Unknown Register",
-            VMError::OutOfBounds => "Memory access is out of bounds",
+            VMError::UnknownRegister(_) => "Unknown Register",
+            VMError::OutOfBounds(_) => "Memory access is out of bounds",
+            VMError::ProgramDoesNotFit => "Program image does not fit in memory",
+            VMError::CodeOutsideSegment { .. } => "Program code does not fit in the code segment",
+            VMError::InvalidEntryPoint => "Entry point is not an instruction of the program",
+            VMError::Unmapped(_) => "No device is mapped at address",
+            VMError::ProtectionFault { .. } => "Memory protection fault",
+            VMError::PageFault { .. } => "Page fault",
+            VMError::InvalidBusMapping(_) => "Invalid bus mapping",
+            VMError::MalformedImage => "Executable image cannot be decoded",
+            VMError::InvalidImageMagic => "Not an executable image, wrong magic bytes",
+            VMError::UnsupportedImageVersion => "Unsupported executable image version",
+            VMError::InvalidImageLayout => "Executable image sections are misaligned or overlap",
             VMError::Halted => "Cannot use a Halted machine",
+            VMError::StepLimitReached => "Step limit reached before the program halted",
+            VMError::OutOfGas => "Out of gas",
+            VMError::InvalidConfig(_) => "Invalid VM configuration",
+            VMError::Overflow => "Arithmetic overflow",
             VMError::MemoryReadError => "Memory read failed",
-            _ => "Else",
+            VMError::OpcodeDoesNotExist => "Unknown opcode",
+            VMError::ConditionDoesNotExist => "Unknown jump condition",
+            VMError::InvalidJumpTarget(_) => "Jump target is odd or outside of memory",
+            VMError::AluFunctionDoesNotExist => "Unknown ALU function",
+            VMError::DivideByZero => "Division by zero",
+            VMError::StackOperationDoesNotExist => "Unknown stack operation",
+            VMError::WideOperationDoesNotExist => "Unknown wide immediate operation",
+            VMError::IntOperationDoesNotExist => "Unknown interrupt operation",
+            VMError::PrivilegedInstruction => "Privileged instruction in user mode",
+            VMError::InvalidInterruptLine(_) => "Interrupt line does not exist",
+            VMError::UnknownSyscall(_) => "No host function is registered for syscall",
+            VMError::Aborted(_) => "Program aborted with code",
+            VMError::StackOverflow => "Stack overflow",
+            VMError::StackUnderflow => "Stack underflow",
+            VMError::AddInstructionFail => "ADD instruction failed",
+            VMError::CopyInstructionFail => "Copy between memory locations failed",
+            VMError::MemoryTypeIsNotSupported => "Memory type is not supported",
+            VMError::Asm(_) => "Assembly failed",
+            VMError::Fault(fault) => fault.cause.message(),
+            VMError::Io(_) => "I/O error",
+        }
+    }
+
+    // Attaches the instruction at `pc` to `cause`
+    pub fn fault(
+        cause: VMError,
+        pc: VmAddr,
+        instruction: VMWord,
+        extension: Option<VMWord>,
+    ) -> Self {
+        VMError::Fault(Box::new(Fault {
+            cause,
+            pc,
+            instruction: Some(instruction),
+            extension,
+            opcode: Opcode::try_from((instruction >> 12) as u8).ok(),
+        }))
+    }
+
+    // Attaches `pc` to `cause` when the instruction at `pc` could not be fetched
+    pub fn fetch_failed(cause: VMError, pc: VmAddr) -> Self {
+        VMError::Fault(Box::new(Fault {
+            cause,
+            pc,
+            instruction: None,
+            extension: None,
+            opcode: None,
+        }))
+    }
+
+    // The error without the context of the instruction that raised it
+    pub fn cause(&self) -> &VMError {
+        match self {
+            VMError::Fault(fault) => &fault.cause,
+            error => error,
+        }
+    }
+
+    pub fn into_cause(self) -> VMError {
+        match self {
+            VMError::Fault(fault) => fault.cause,
+            error => error,
+        }
+    }
+
+    pub fn as_fault(&self) -> Option<&Fault> {
+        match self {
+            VMError::Fault(fault) => Some(fault),
+            _ => None,
+        }
+    }
+
+    // The memory address the error is about, if any
+    pub fn addr(&self) -> Option<VmAddr> {
+        match self.cause() {
+            VMError::OutOfBounds(addr)
+            | VMError::InvalidJumpTarget(addr)
+            | VMError::Unmapped(addr)
+            | VMError::ProtectionFault { addr, .. }
+            | VMError::PageFault { addr, .. } => Some(*addr),
+            _ => None,
+        }
+    }
+
+    // The register id the error is about, if any
+    pub fn register(&self) -> Option<u8> {
+        match self.cause() {
+            VMError::UnknownRegister(id) => Some(*id),
+            _ => None,
         }
     }
 
@@ -51,4 +210,72 @@ impl VMError {
     // }
 }
 
-impl std::error::Error for VMError {}
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VMError::OutOfBounds(addr)
+            | VMError::InvalidJumpTarget(addr)
+            | VMError::Unmapped(addr) => write!(f, "{} ({addr:#06x})", self.message()),
+            VMError::ProtectionFault { addr, kind } | VMError::PageFault { addr, kind } => {
+                write!(f, "{} ({kind} {addr:#06x})", self.message())
+            }
+            VMError::CodeOutsideSegment { start, end } => {
+                write!(f, "{} ({start:#06x}..{end:#06x})", self.message())
+            }
+            VMError::UnknownRegister(id) | VMError::InvalidInterruptLine(id) => {
+                write!(f, "{} ({id})", self.message())
+            }
+            VMError::UnknownSyscall(number) | VMError::Aborted(number) => {
+                write!(f, "{} {number}", self.message())
+            }
+            VMError::InvalidConfig(reason) | VMError::InvalidBusMapping(reason) => {
+                write!(f, "{}: {reason}", self.message())
+            }
+            VMError::Asm(error) => write!(f, "{error}"),
+            VMError::Io(error) => write!(f, "{error}"),
+            VMError::Fault(fault) => write!(f, "{fault}"),
+            _ => f.write_str(self.message()),
+        }
+    }
+}
+
+impl std::error::Error for VMError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VMError::Fault(fault) => Some(&fault.cause),
+            VMError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The instruction that raised `cause`, attached by `VM::tick`.
+/// Errors that leave the machine able to continue (Halted, StepLimitReached, OutOfGas) are returned without a fault.
+/// A failed fetch has a fault without the instruction.
+#[derive(Debug)]
+pub struct Fault {
+    pub cause: VMError,
+    pub pc: VmAddr, // address of the instruction, not the already incremented RPC
+    pub instruction: Option<VMWord>, // raw instruction word, None when it could not be fetched
+    pub extension: Option<VMWord>,
+    pub opcode: Option<Opcode>, // None when the opcode field does not decode
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(instruction) = self.instruction else {
+            return write!(
+                f,
+                "{} fetching the instruction at {:#06x}",
+                self.cause, self.pc
+            );
+        };
+        let text = decode_instruction(self.pc, instruction, self.extension)
+            .unwrap_or_else(|| ".word".to_string());
+        write!(
+            f,
+            "{} at {:#06x}: {text} ({instruction:#06x})",
+            self.cause, self.pc
+        )
+    }
+}
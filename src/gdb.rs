@@ -0,0 +1,654 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::constants::{VMWord, VmAddr};
+use crate::register::RegisterId;
+use crate::vm::VM;
+use crate::watch::{WatchKind, Watchpoint, Watchpoints};
+
+/*
+    GDB Remote Serial Protocol stub, lets gdb or lldb drive the VM over a local TCP socket.
+
+    Packets are framed as `$<payload>#<checksum>` and acknowledged with `+`, a raw 0x03 byte interrupts a `c`.
+    Supported packets:
+
+        ?               why the target stopped
+        g / G           read / write all registers
+        p n / P n=v     read / write one register
+        m addr,len      read memory
+        M addr,len:hex  write memory
+        s / c           single step / continue until a breakpoint
+        Z0 / z0         insert / remove a software breakpoint
+        Z2..Z4 / z2..z4 insert / remove a write, read or access watchpoint
+        qSupported, qXfer:features:read:target.xml, qAttached, k, D
+
+    Registers are sent in `RegisterBank` id order as 16-bit little-endian values, the target description
+    built by `target_xml` uses the same order. Unsupported packets get the empty reply, as the protocol asks.
+*/
+
+const SIGTRAP: u8 = 5;
+const SIGINT: u8 = 2;
+const SIGABRT: u8 = 6;
+// How many instructions `c` runs between two checks for an interrupt from the client
+const INTERRUPT_POLL_INTERVAL: u32 = 1024;
+
+pub struct GdbStub {
+    pub vm: VM,
+    breakpoints: BTreeSet<VmAddr>,
+    watchpoints: Watchpoints,
+    watch_ids: BTreeMap<(u8, VmAddr, u16), usize>, // (Z type, addr, length) -> watchpoint id
+    stop_reply: String,
+}
+
+// What the session loop has to do after a packet
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Reply(String),
+    Continue,
+    Close(Option<String>), // optional last reply before the connection is dropped
+}
+
+impl GdbStub {
+    pub fn new(mut vm: VM) -> Self {
+        let watchpoints = Watchpoints::new();
+        watchpoints.attach(&mut vm);
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints,
+            watch_ids: BTreeMap::new(),
+            stop_reply: format!("S{SIGTRAP:02x}"),
+        }
+    }
+
+    /// Accepts one client and serves it until it detaches, kills the target or disconnects
+    pub fn serve(&mut self, listener: &TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        while let Some(packet) = read_packet(&mut reader, &mut writer)? {
+            let action = match packet {
+                Packet::Interrupt => Action::Reply(self.stop_reply.clone()),
+                Packet::Command(command) => self.handle_packet(&command),
+            };
+            match action {
+                Action::Reply(reply) => write_packet(&mut writer, &reply)?,
+                Action::Continue => {
+                    let reply = self.resume(&mut reader)?;
+                    write_packet(&mut writer, &reply)?;
+                }
+                Action::Close(reply) => {
+                    if let Some(reply) = reply {
+                        write_packet(&mut writer, &reply)?;
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handle_packet(&mut self, packet: &str) -> Action {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply.clone(),
+            Some(b'g') => self
+                .registers()
+                .map(|(_, value)| encode_word(value))
+                .collect(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b's') => self.step(),
+            Some(b'c') => return Action::Continue,
+            Some(b'Z' | b'z') => self.breakpoint(packet),
+            Some(b'H') => "OK".to_string(),
+            Some(b'k') => return Action::Close(None),
+            Some(b'D') => return Action::Close(Some("OK".to_string())),
+            Some(b'q') => self.query(&packet[1..]),
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    /// Register layout for gdb, in the order used by the `g` and `G` packets
+    pub fn target_xml(&self) -> String {
+        let registers: String = self
+            .registers()
+            .map(|(id, _)| {
+                let kind = match RegisterId::try_from(id) {
+                    Ok(RegisterId::RPC) => "code_ptr",
+                    Ok(RegisterId::RSP) => "data_ptr",
+                    _ => "uint16",
+                };
+                let name = RegisterId::try_from(id)
+                    .map(|reg| reg.name().to_ascii_lowercase())
+                    .unwrap_or_else(|_| format!("r{id}"));
+                format!(
+                    "    <reg name=\"{name}\" bitsize=\"16\" regnum=\"{id}\" type=\"{kind}\"/>\n"
+                )
+            })
+            .collect();
+        format!(
+            "<?xml version=\"1.0\"?>\n\
+             <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n  <feature name=\"org.rust-vm.core\">\n{registers}  </feature>\n</target>\n"
+        )
+    }
+
+    fn registers(&self) -> impl Iterator<Item = (u8, VMWord)> + '_ {
+        self.vm
+            .registers
+            .register_map
+            .iter()
+            .map(|(id, reg)| (*id, reg.value))
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        let ids: Vec<u8> = self.registers().map(|(id, _)| id).collect();
+        if hex.len() != ids.len() * 4 {
+            return error_reply();
+        }
+        for (id, chunk) in ids.into_iter().zip(hex.as_bytes().chunks(4)) {
+            let Some(value) = std::str::from_utf8(chunk).ok().and_then(decode_word) else {
+                return error_reply();
+            };
+            self.set_register(id, value);
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        u8::from_str_radix(args, 16)
+            .ok()
+            .and_then(|id| self.vm.registers.get_register_read_only(id).ok())
+            .map(|reg| encode_word(reg.value))
+            .unwrap_or_else(error_reply)
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(id, value)| {
+            let id = u8::from_str_radix(id, 16).ok()?;
+            self.vm.registers.get_register_read_only(id).ok()?;
+            Some((id, decode_word(value)?))
+        });
+        match parsed {
+            Some((id, value)) => {
+                self.set_register(id, value);
+                "OK".to_string()
+            }
+            None => error_reply(),
+        }
+    }
+
+    fn set_register(&mut self, id: u8, value: VMWord) {
+        if let Ok(reg) = self.vm.registers.get_register_mut(id) {
+            reg.value = value;
+        }
+    }
+
+    // Reads as many bytes as the bus has, the reply is only an error when not even the first byte exists
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_addr_len(args) else {
+            return error_reply();
+        };
+        let bytes: Vec<u8> = (0..len)
+            .map_while(|offset| addr.checked_add(offset))
+            .map_while(|addr| self.vm.memory.peek(addr))
+            .collect();
+        if bytes.is_empty() && len > 0 {
+            return error_reply();
+        }
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return error_reply();
+        };
+        let Some((addr, len)) = parse_addr_len(range) else {
+            return error_reply();
+        };
+        let Some(bytes) = decode_hex(data).filter(|bytes| bytes.len() == usize::from(len)) else {
+            return error_reply();
+        };
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            let written = addr
+                .checked_add(offset as VmAddr)
+                .map(|addr| self.vm.memory.write(addr, byte));
+            if !matches!(written, Some(Ok(()))) {
+                return error_reply();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Z<type>,addr,kind and z<type>,addr,kind, for watchpoints `kind` is the length of the watched range
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let fields: Vec<&str> = packet[1..].split(',').collect();
+        let [kind, addr, len] = fields.as_slice() else {
+            return error_reply();
+        };
+        let (Ok(addr), Ok(len)) = (
+            VmAddr::from_str_radix(addr, 16),
+            u16::from_str_radix(len, 16),
+        ) else {
+            return error_reply();
+        };
+
+        let watch_kind = match *kind {
+            "0" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let key = (kind.as_bytes()[0], addr, len);
+        if insert {
+            let id = self.watchpoints.add(Watchpoint {
+                addr,
+                len,
+                kind: watch_kind,
+            });
+            if let Some(old) = self.watch_ids.insert(key, id) {
+                self.watchpoints.remove(old);
+            }
+        } else if let Some(id) = self.watch_ids.remove(&key) {
+            self.watchpoints.remove(id);
+        }
+        "OK".to_string()
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+".to_string()
+        } else if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, length)) = args.split_once(',').and_then(|(offset, length)| {
+                Some((
+                    usize::from_str_radix(offset, 16).ok()?,
+                    usize::from_str_radix(length, 16).ok()?,
+                ))
+            }) else {
+                return error_reply();
+            };
+            let xml = self.target_xml();
+            let start = offset.min(xml.len());
+            let end = start.saturating_add(length).min(xml.len());
+            let marker = if end < xml.len() { 'm' } else { 'l' };
+            format!("{marker}{}", &xml[start..end])
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if query == "C" {
+            "QC1".to_string()
+        } else if query == "fThreadInfo" {
+            "m1".to_string()
+        } else if query == "sThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn step(&mut self) -> String {
+        if let Some(stop) = self.tick() {
+            return stop;
+        }
+        self.stop(SIGTRAP)
+    }
+
+    // Runs until a breakpoint, HALT, an error or a 0x03 byte from the client
+    fn resume(&mut self, reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+        let mut since_poll = 0;
+        loop {
+            if let Some(stop) = self.tick() {
+                return Ok(stop);
+            }
+            if self.breakpoints.contains(&self.pc()) {
+                return Ok(self.stop(SIGTRAP));
+            }
+            since_poll += 1;
+            if since_poll == INTERRUPT_POLL_INTERVAL {
+                since_poll = 0;
+                if interrupted(reader)? {
+                    return Ok(self.stop(SIGINT));
+                }
+            }
+        }
+    }
+
+    // Executes one instruction, returns the stop reply when it halted the program or fired a watchpoint
+    fn tick(&mut self) -> Option<String> {
+        if self.vm.halted {
+            return Some(self.stop_reply.clone());
+        }
+        // Memory read by `m` packets is not an access by the program
+        self.watchpoints.take_hits();
+        let result = self.vm.tick();
+        if !self.vm.halted {
+            let hit = self.watchpoints.take_hits().into_iter().next()?;
+            let kind = self
+                .watchpoints
+                .list()
+                .into_iter()
+                .find(|(id, _)| *id == hit.id)
+                .map(|(_, watchpoint)| watchpoint.kind)?;
+            let name = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            self.stop_reply = format!("T{SIGTRAP:02x}{name}:{:x};", hit.addr);
+            return Some(self.stop_reply.clone());
+        }
+        // HALT exits the program with status 0, an error kills it
+        self.stop_reply = match result {
+            Ok(()) => "W00".to_string(),
+            Err(_) => format!("X{SIGABRT:02x}"),
+        };
+        Some(self.stop_reply.clone())
+    }
+
+    fn stop(&mut self, signal: u8) -> String {
+        self.stop_reply = format!("S{signal:02x}");
+        self.stop_reply.clone()
+    }
+
+    fn pc(&self) -> VmAddr {
+        self.vm
+            .registers
+            .get_register_read_only(RegisterId::RPC.id())
+            .map(|reg| reg.value)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+// Reads the next packet and acknowledges it, packets with a bad checksum are nacked and skipped
+fn read_packet(reader: &mut impl BufRead, writer: &mut impl Write) -> io::Result<Option<Packet>> {
+    loop {
+        let mut byte = [0u8];
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            0x03 => return Ok(Some(Packet::Interrupt)),
+            b'$' => {
+                let mut payload = Vec::new();
+                if reader.read_until(b'#', &mut payload)? == 0 || payload.pop() != Some(b'#') {
+                    return Ok(None);
+                }
+                let mut checksum = [0u8; 2];
+                reader.read_exact(&mut checksum)?;
+                let expected = std::str::from_utf8(&checksum)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if expected != Some(checksum_of(&payload)) {
+                    writer.write_all(b"-")?;
+                    continue;
+                }
+                writer.write_all(b"+")?;
+                return Ok(Some(Packet::Command(
+                    String::from_utf8_lossy(&unescape(&payload)).into_owned(),
+                )));
+            }
+            // Acknowledgements from the client and line noise between packets
+            _ => {}
+        }
+    }
+}
+
+fn write_packet(writer: &mut impl Write, payload: &str) -> io::Result<()> {
+    let escaped = escape(payload.as_bytes());
+    writer.write_all(b"$")?;
+    writer.write_all(&escaped)?;
+    write!(writer, "#{:02x}", checksum_of(&escaped))?;
+    writer.flush()
+}
+
+fn interrupted(reader: &mut BufReader<TcpStream>) -> io::Result<bool> {
+    if reader.buffer().contains(&0x03) {
+        reader.consume(reader.buffer().len());
+        return Ok(true);
+    }
+    reader.get_ref().set_nonblocking(true)?;
+    let mut byte = [0u8];
+    let polled = reader.get_mut().read(&mut byte);
+    reader.get_ref().set_nonblocking(false)?;
+    match polled {
+        Ok(read) => Ok(read == 1 && byte[0] == 0x03),
+        Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+fn checksum_of(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// `}` escapes the following byte, which is XORed with 0x20
+fn unescape(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'}' => out.extend(iter.next().map(|escaped| escaped ^ 0x20)),
+            _ => out.push(*byte),
+        }
+    }
+    out
+}
+
+fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    for byte in bytes {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            out.extend([b'}', byte ^ 0x20]);
+        } else {
+            out.push(*byte);
+        }
+    }
+    out
+}
+
+// Registers and memory travel in target byte order, which is little-endian
+fn encode_word(value: VMWord) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn decode_word(hex: &str) -> Option<VMWord> {
+    let bytes: [u8; 2] = decode_hex(hex)?.try_into().ok()?;
+    Some(VMWord::from_le_bytes(bytes))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_addr_len(args: &str) -> Option<(VmAddr, VmAddr)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        VmAddr::from_str_radix(addr, 16).ok()?,
+        VmAddr::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn error_reply() -> String {
+    "E01".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::memory::LinearMemory;
+    use crate::program::Program;
+    use std::thread;
+
+    const SOURCE: &str = "
+                COPY R0, 2
+        loop:   ADD R1, R0
+                CMP R1, 6
+                JLT loop
+                HALT
+    ";
+
+    fn stub() -> GdbStub {
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(1024)));
+        vm.load_program(&Program::new(assemble(SOURCE).unwrap()))
+            .unwrap();
+        GdbStub::new(vm)
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle_packet(packet) {
+            Action::Reply(reply) => reply,
+            other => panic!("unexpected action {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = stub();
+        // R0..R3, then RPC = 0x0100
+        assert!(reply(&mut stub, "g").starts_with("0000000000000000"));
+        assert_eq!(reply(&mut stub, "p4"), "0001");
+
+        assert_eq!(reply(&mut stub, "P0=3412"), "OK");
+        assert_eq!(
+            stub.vm.registers.get_register_read_only(0).unwrap().value,
+            0x1234
+        );
+        let all = reply(&mut stub, "g");
+        assert_eq!(reply(&mut stub, &format!("G{all}")), "OK");
+        assert_eq!(reply(&mut stub, "G00"), "E01");
+
+        assert_eq!(reply(&mut stub, "m100,2"), "6210");
+        assert_eq!(reply(&mut stub, "M200,2:efbe"), "OK");
+        assert_eq!(stub.vm.memory.read2(0x200), Some(0xbeef));
+        assert_eq!(reply(&mut stub, "m3fe,4"), "0000");
+        assert_eq!(reply(&mut stub, "m400,2"), "E01");
+    }
+
+    #[test]
+    fn test_step_breakpoints_and_target_description() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "p4"), "0201");
+        assert_eq!(reply(&mut stub, "Z0,108,2"), "OK");
+        assert_eq!(reply(&mut stub, "Z1,108,2"), "");
+        assert_eq!(stub.handle_packet("c"), Action::Continue);
+
+        let xml = stub.target_xml();
+        assert!(xml.contains("<reg name=\"rpc\" bitsize=\"16\" regnum=\"4\" type=\"code_ptr\"/>"));
+        let first = reply(&mut stub, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[..16]));
+        let last = reply(
+            &mut stub,
+            &format!("qXfer:features:read:target.xml:{:x},1000", 16),
+        );
+        assert_eq!(last, format!("l{}", &xml[16..]));
+    }
+
+    #[test]
+    fn test_watchpoints_stop_with_watch_reply() {
+        let mut stub = stub();
+        stub.vm.memory.write2(0x200, 0x1234).unwrap();
+        assert_eq!(reply(&mut stub, "Z3,200,2"), "OK");
+        // Reads made on behalf of gdb do not fire
+        assert_eq!(reply(&mut stub, "m200,2"), "3412");
+        assert_eq!(reply(&mut stub, "s"), "S05");
+
+        // The sample program never touches data, patch in R0 = 0x200 and LOAD R1, R0 as the next instruction
+        assert_eq!(reply(&mut stub, "P0=0002"), "OK");
+        assert_eq!(reply(&mut stub, "M102,2:0021"), "OK");
+        assert_eq!(reply(&mut stub, "s"), "T05rwatch:200;");
+        assert_eq!(reply(&mut stub, "?"), "T05rwatch:200;");
+        assert_eq!(reply(&mut stub, "z3,200,2"), "OK");
+        assert!(stub.watchpoints.list().is_empty());
+    }
+
+    #[test]
+    fn test_session_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stub = stub();
+            stub.serve(&listener).unwrap();
+            stub.vm.halted
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut exchange = |packet: &str| -> String {
+            write_packet(&mut writer, packet).unwrap();
+            let mut ack = [0u8];
+            reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            match read_packet(&mut reader, &mut io::sink()).unwrap() {
+                Some(Packet::Command(reply)) => reply,
+                other => panic!("unexpected packet {other:?}"),
+            }
+        };
+
+        assert!(exchange("qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+        assert_eq!(exchange("Z0,106,2"), "OK");
+        assert_eq!(exchange("c"), "S05");
+        assert_eq!(exchange("p4"), "0601");
+        assert_eq!(exchange("z0,106,2"), "OK");
+        assert_eq!(exchange("c"), "W00");
+        assert_eq!(exchange("D"), "OK");
+
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut framed = Vec::new();
+        write_packet(&mut framed, "a#b").unwrap();
+        assert_eq!(framed, b"$a}\x03b#43");
+
+        let mut acks = Vec::new();
+        let input: &[u8] = b"+$m0,2#fb$g#00$g#67";
+        let mut reader = BufReader::new(input);
+        assert_eq!(
+            read_packet(&mut reader, &mut acks).unwrap(),
+            Some(Packet::Command("m0,2".to_string()))
+        );
+        // The second packet has a wrong checksum and is skipped
+        assert_eq!(
+            read_packet(&mut reader, &mut acks).unwrap(),
+            Some(Packet::Command("g".to_string()))
+        );
+        assert_eq!(acks, b"+-+");
+        assert_eq!(read_packet(&mut reader, &mut acks).unwrap(), None);
+    }
+}
@@ -0,0 +1,280 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use crate::bus::BusDevice;
+use crate::constants::{VMWord, VmAddr};
+use crate::error::{Result, VMError};
+use crate::register::{RegisterBank, RegisterId};
+
+/*
+    Host functions, the Rust side of SYSCALL.
+
+    The syscall number is the value of the src register, or the imm nibble when src is RIM (`SYSCALL 2`), so numbers
+    1 to 15 can be called without a register. Arguments and results are passed in R0 and R1, a host function may
+    read and write any register and the whole bus. An unknown number raises the invalid instruction trap, an error
+    returned by a host function faults like any other instruction.
+
+        1   print   write R0 as a decimal number and a newline
+        2   log     write the R1 bytes at address R0 as text and a newline
+        3   read    read up to R1 bytes of input into memory at address R0, R0 <- bytes read, 0 at the end of input
+        4   abort   stop the program, the machine halts with `VMError::Aborted(R0)`
+        5   steps   R0 <- low word, R1 <- high word of the instructions completed so far
+
+    `steps` is answered by the VM, which owns the step counter, unless a function is registered under its number.
+*/
+
+pub const SYS_PRINT: VMWord = 1;
+pub const SYS_LOG: VMWord = 2;
+pub const SYS_READ: VMWord = 3;
+pub const SYS_ABORT: VMWord = 4;
+pub const SYS_STEPS: VMWord = 5;
+
+pub type HostFunction = Box<dyn FnMut(&mut RegisterBank, &mut dyn BusDevice) -> Result<()>>;
+
+/// Host functions callable through SYSCALL, by number
+#[derive(Default)]
+pub struct HostFunctions {
+    functions: BTreeMap<VMWord, (String, HostFunction)>,
+}
+
+impl HostFunctions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // print and log write to `output`, read takes its bytes from `input`
+    pub fn with_builtins(output: impl Write + 'static, input: impl Read + 'static) -> Self {
+        let output = Rc::new(RefCell::new(output));
+        let mut input = input;
+        let mut functions = Self::new();
+
+        let print_output = Rc::clone(&output);
+        functions.register(SYS_PRINT, "print", move |registers, _| {
+            let value = argument(registers, RegisterId::RR0)?;
+            writeln!(print_output.borrow_mut(), "{value}")?;
+            Ok(())
+        });
+
+        functions.register(SYS_LOG, "log", move |registers, memory| {
+            let addr = argument(registers, RegisterId::RR0)?;
+            let len = argument(registers, RegisterId::RR1)?;
+            let bytes = (0..len)
+                .map(|offset| {
+                    let byte_addr = byte_address(addr, offset)?;
+                    memory
+                        .read(byte_addr)
+                        .ok_or(VMError::OutOfBounds(byte_addr))
+                })
+                .collect::<Result<Vec<u8>>>()?;
+            writeln!(output.borrow_mut(), "{}", String::from_utf8_lossy(&bytes))?;
+            Ok(())
+        });
+
+        functions.register(SYS_READ, "read", move |registers, memory| {
+            let addr = argument(registers, RegisterId::RR0)?;
+            let len = argument(registers, RegisterId::RR1)?;
+            let mut buffer = vec![0; usize::from(len)];
+            let count = input.read(&mut buffer)?;
+            for (offset, byte) in (0..).zip(&buffer[..count]) {
+                memory.write(byte_address(addr, offset)?, *byte)?;
+            }
+            registers.get_register_mut(RegisterId::RR0.id())?.value = count as VMWord;
+            Ok(())
+        });
+
+        functions.register(SYS_ABORT, "abort", |registers, _| {
+            Err(VMError::Aborted(argument(registers, RegisterId::RR0)?))
+        });
+
+        functions
+    }
+
+    /// Registers `function` under `number`, replacing the function registered there before
+    pub fn register(
+        &mut self,
+        number: VMWord,
+        name: impl Into<String>,
+        function: impl FnMut(&mut RegisterBank, &mut dyn BusDevice) -> Result<()> + 'static,
+    ) {
+        self.functions
+            .insert(number, (name.into(), Box::new(function)));
+    }
+
+    pub fn name(&self, number: VMWord) -> Option<&str> {
+        self.functions.get(&number).map(|(name, _)| name.as_str())
+    }
+
+    pub fn call(
+        &mut self,
+        number: VMWord,
+        registers: &mut RegisterBank,
+        memory: &mut dyn BusDevice,
+    ) -> Result<()> {
+        let (_, function) = self
+            .functions
+            .get_mut(&number)
+            .ok_or(VMError::UnknownSyscall(number))?;
+        function(registers, memory)
+    }
+}
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.functions
+                    .iter()
+                    .map(|(number, (name, _))| (number, name)),
+            )
+            .finish()
+    }
+}
+
+fn argument(registers: &RegisterBank, id: RegisterId) -> Result<VMWord> {
+    Ok(registers.get_register_read_only(id.id())?.value)
+}
+
+// Buffers may not wrap around the end of the address space
+fn byte_address(addr: VmAddr, offset: VMWord) -> Result<VmAddr> {
+    addr.checked_add(offset).ok_or(VMError::OutOfBounds(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::LinearMemory;
+
+    // Output shared with the test, the builtins take ownership of the writer they are given
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn set(registers: &mut RegisterBank, id: RegisterId, value: VMWord) {
+        registers.get_register_mut(id.id()).unwrap().value = value;
+    }
+
+    #[test]
+    fn test_builtins() {
+        let output = SharedOutput::default();
+        let mut host = HostFunctions::with_builtins(output.clone(), &b"hi"[..]);
+        let mut registers = RegisterBank::new();
+        let mut memory = LinearMemory::new(0x100);
+
+        set(&mut registers, RegisterId::RR0, 0x40);
+        set(&mut registers, RegisterId::RR1, 8);
+        host.call(SYS_READ, &mut registers, &mut memory).unwrap();
+        assert_eq!(argument(&registers, RegisterId::RR0).unwrap(), 2);
+        assert_eq!(memory.read2(0x40), Some(u16::from_le_bytes(*b"hi")));
+
+        set(&mut registers, RegisterId::RR0, 0x40);
+        set(&mut registers, RegisterId::RR1, 2);
+        host.call(SYS_LOG, &mut registers, &mut memory).unwrap();
+        set(&mut registers, RegisterId::RR0, 1234);
+        host.call(SYS_PRINT, &mut registers, &mut memory).unwrap();
+        assert_eq!(output.0.borrow().as_slice(), b"hi\n1234\n");
+
+        // End of input
+        host.call(SYS_READ, &mut registers, &mut memory).unwrap();
+        assert_eq!(argument(&registers, RegisterId::RR0).unwrap(), 0);
+
+        set(&mut registers, RegisterId::RR0, 7);
+        assert!(matches!(
+            host.call(SYS_ABORT, &mut registers, &mut memory),
+            Err(VMError::Aborted(7))
+        ));
+        set(&mut registers, RegisterId::RR0, 0xF0);
+        set(&mut registers, RegisterId::RR1, 0x20);
+        assert!(matches!(
+            host.call(SYS_LOG, &mut registers, &mut memory),
+            Err(VMError::OutOfBounds(0x100))
+        ));
+        assert!(matches!(
+            host.call(9, &mut registers, &mut memory),
+            Err(VMError::UnknownSyscall(9))
+        ));
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut host = HostFunctions::new();
+        let mut registers = RegisterBank::new();
+        let mut memory = LinearMemory::new(0x100);
+        assert!(matches!(
+            host.call(SYS_PRINT, &mut registers, &mut memory),
+            Err(VMError::UnknownSyscall(SYS_PRINT))
+        ));
+        assert_eq!(host.name(SYS_PRINT), None);
+
+        // Registering a number again replaces the function and its name
+        host.register(SYS_PRINT, "one", |registers, _| {
+            registers.get_register_mut(RegisterId::RR0.id())?.value = 1;
+            Ok(())
+        });
+        host.register(SYS_PRINT, "two", |registers, _| {
+            registers.get_register_mut(RegisterId::RR0.id())?.value = 2;
+            Ok(())
+        });
+        host.call(SYS_PRINT, &mut registers, &mut memory).unwrap();
+        assert_eq!(argument(&registers, RegisterId::RR0).unwrap(), 2);
+        assert_eq!(host.name(SYS_PRINT), Some("two"));
+
+        // Errors are returned as they are, state the function changed before failing is kept
+        host.register(0xFFFF, "fails", |registers, memory| {
+            registers.get_register_mut(RegisterId::RR1.id())?.value = 3;
+            memory.write(0x100, 0)
+        });
+        assert!(matches!(
+            host.call(0xFFFF, &mut registers, &mut memory),
+            Err(VMError::OutOfBounds(0x100))
+        ));
+        assert_eq!(argument(&registers, RegisterId::RR1).unwrap(), 3);
+        assert_eq!(format!("{host:?}"), r#"{1: "two", 65535: "fails"}"#);
+    }
+
+    #[test]
+    fn test_builtin_edge_cases() {
+        let output = SharedOutput::default();
+        let mut host = HostFunctions::with_builtins(output.clone(), &b"abcdef"[..]);
+        let mut registers = RegisterBank::new();
+        let mut memory = LinearMemory::new(0x100);
+
+        // Zero-length reads and logs touch nothing
+        set(&mut registers, RegisterId::RR0, 0x40);
+        set(&mut registers, RegisterId::RR1, 0);
+        host.call(SYS_READ, &mut registers, &mut memory).unwrap();
+        assert_eq!(argument(&registers, RegisterId::RR0).unwrap(), 0);
+        set(&mut registers, RegisterId::RR0, 0x40);
+        host.call(SYS_LOG, &mut registers, &mut memory).unwrap();
+        assert_eq!(output.0.borrow().as_slice(), b"\n");
+
+        // Reads stop at R1 bytes, the rest of the input waits for the next call
+        set(&mut registers, RegisterId::RR1, 4);
+        host.call(SYS_READ, &mut registers, &mut memory).unwrap();
+        assert_eq!(argument(&registers, RegisterId::RR0).unwrap(), 4);
+        assert_eq!(memory.get_subset_of_memory(0x40, 0x45), b"abcd\0");
+
+        // A buffer wrapping around the end of the address space fails before reading
+        set(&mut registers, RegisterId::RR0, 0xFFFF);
+        set(&mut registers, RegisterId::RR1, 2);
+        assert!(matches!(
+            host.call(SYS_LOG, &mut registers, &mut memory),
+            Err(VMError::OutOfBounds(_))
+        ));
+        set(&mut registers, RegisterId::RR0, 0);
+        assert!(matches!(
+            host.call(SYS_ABORT, &mut registers, &mut memory),
+            Err(VMError::Aborted(0))
+        ));
+    }
+}
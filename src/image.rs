@@ -0,0 +1,331 @@
+use std::fs;
+use std::path::Path;
+
+use wincode::{deserialize, serialize};
+use wincode_derive::{SchemaRead, SchemaWrite};
+
+use crate::asm::Assembly;
+use crate::constants::{START_ADDRESS, VMWord, VmAddr};
+use crate::error::{Result, VMError};
+use crate::program::Program;
+
+/*
+    On-disk executable format, serialized with wincode:
+
+        header   magic "VM16", format version, entry point and load address
+        code     instruction words and the address they are loaded at
+        data     initialised data words and their address
+        bss      zero-initialised region, only its address and size are stored
+        symbols  optional label table, e.g. for the debugger
+
+    Every section lives at or above the load address, sections never overlap and the entry point is inside code.
+    `canonical_bytes` leaves out the symbols, so the same program has the same bytes (and ZK commitment)
+    whether or not a tool kept its labels.
+*/
+
+pub const IMAGE_MAGIC: [u8; 4] = *b"VM16";
+pub const IMAGE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, SchemaWrite, SchemaRead)]
+pub struct ImageHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub entry_point: VmAddr,
+    pub load_address: VmAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, SchemaWrite, SchemaRead)]
+pub struct Section {
+    pub address: VmAddr,
+    pub words: Vec<VMWord>,
+}
+
+#[derive(Debug, Clone, PartialEq, SchemaWrite, SchemaRead)]
+pub struct BssSection {
+    pub address: VmAddr,
+    pub size: u16, // bytes
+}
+
+#[derive(Debug, Clone, PartialEq, SchemaWrite, SchemaRead)]
+pub struct Symbol {
+    pub name: String,
+    pub address: VmAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, SchemaWrite, SchemaRead)]
+pub struct ExecutableImage {
+    pub header: ImageHeader,
+    pub code: Section,
+    pub data: Section,
+    pub bss: BssSection,
+    pub symbols: Option<Vec<Symbol>>,
+}
+
+impl ExecutableImage {
+    // Image with only a code section loaded and started at `load_address`
+    pub fn new(load_address: VmAddr, code: Vec<VMWord>) -> Self {
+        let end = section_end(load_address, code.len() * 2) as VmAddr;
+        Self {
+            header: ImageHeader {
+                magic: IMAGE_MAGIC,
+                version: IMAGE_VERSION,
+                entry_point: load_address,
+                load_address,
+            },
+            code: Section {
+                address: load_address,
+                words: code,
+            },
+            data: Section {
+                address: end,
+                words: vec![],
+            },
+            bss: BssSection {
+                address: end,
+                size: 0,
+            },
+            symbols: None,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let image: ExecutableImage = deserialize(bytes).map_err(|_| VMError::MalformedImage)?;
+        image.validate()?;
+        Ok(image)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.validate()?;
+        serialize(self).map_err(|_| VMError::MalformedImage)
+    }
+
+    // Serialization without the symbol table, this is what the program commitment is computed over
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>> {
+        Self {
+            symbols: None,
+            ..self.clone()
+        }
+        .to_bytes()
+    }
+
+    pub fn read_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.header.magic != IMAGE_MAGIC {
+            return Err(VMError::InvalidImageMagic);
+        }
+        if self.header.version != IMAGE_VERSION {
+            return Err(VMError::UnsupportedImageVersion);
+        }
+
+        let load = usize::from(self.header.load_address);
+        let mut ranges = [
+            (usize::from(self.code.address), words_end(&self.code)),
+            (usize::from(self.data.address), words_end(&self.data)),
+            (
+                usize::from(self.bss.address),
+                section_end(self.bss.address, usize::from(self.bss.size)),
+            ),
+        ];
+        let address_space = usize::from(VmAddr::MAX) + 1;
+        if ranges
+            .iter()
+            .any(|(start, end)| start % 2 != 0 || *start < load || *end > address_space)
+        {
+            return Err(VMError::InvalidImageLayout);
+        }
+        ranges.sort();
+        if ranges.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+            return Err(VMError::InvalidImageLayout);
+        }
+
+        let entry = usize::from(self.header.entry_point);
+        if entry % 2 != 0
+            || entry < usize::from(self.code.address)
+            || entry + 2 > words_end(&self.code)
+        {
+            return Err(VMError::InvalidEntryPoint);
+        }
+        Ok(())
+    }
+
+    /// Flattens the sections into one contiguous program, gaps and bss become zero words
+    pub fn to_program(&self) -> Program {
+        let load = usize::from(self.header.load_address);
+        let end = [
+            words_end(&self.code),
+            words_end(&self.data),
+            section_end(self.bss.address, usize::from(self.bss.size)),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(load);
+
+        let mut words = vec![0; (end - load).div_ceil(2)];
+        for section in [&self.code, &self.data] {
+            let first = (usize::from(section.address) - load) / 2;
+            words[first..first + section.words.len()].copy_from_slice(&section.words);
+        }
+
+        let program = Program::new(words)
+            .with_load_address(self.header.load_address)
+            .with_entry_point(self.header.entry_point);
+        // Everything after the code is data or bss
+        let code_end = words_end(&self.code);
+        if end > code_end {
+            program.with_data_address(code_end as VmAddr)
+        } else {
+            program
+        }
+    }
+}
+
+// Words from the data address on become the data section
+impl From<&Program> for ExecutableImage {
+    fn from(program: &Program) -> Self {
+        let split = (program.code_end() - usize::from(program.load_address)) / 2;
+        let (code, data) = program.words.split_at(split);
+        let mut image = ExecutableImage::new(program.load_address, code.to_vec());
+        image.header.entry_point = program.entry_point;
+        image.data.words = data.to_vec();
+        image.bss.address = words_end(&image.data) as VmAddr;
+        image
+    }
+}
+
+// Raw words are treated like programs built in `utils`, loaded and started at START_ADDRESS
+impl From<Vec<VMWord>> for ExecutableImage {
+    fn from(words: Vec<VMWord>) -> Self {
+        ExecutableImage::new(START_ADDRESS, words)
+    }
+}
+
+// Words before `.data` become the code section, the rest the data section, labels become symbols
+impl From<&Assembly> for ExecutableImage {
+    fn from(assembly: &Assembly) -> Self {
+        // The zeros between the code and `.data` are left out, the sections keep their addresses
+        let word_index = |addr: usize| (addr - usize::from(START_ADDRESS)) / 2;
+        let code = &assembly.words[..word_index(assembly.code_end)];
+
+        let mut image = ExecutableImage::new(START_ADDRESS, code.to_vec());
+        image.header.entry_point = assembly.entry_point();
+        if let Some(data_start) = assembly.data_start {
+            image.data.address = data_start;
+            image.data.words = assembly.words[word_index(usize::from(data_start))..].to_vec();
+        }
+        let end = words_end(&image.data) as VmAddr;
+        image.bss.address = end;
+        image.symbols = Some(
+            assembly
+                .symbols
+                .iter()
+                .map(|(name, address)| Symbol {
+                    name: name.clone(),
+                    address: *address,
+                })
+                .collect(),
+        );
+        image
+    }
+}
+
+fn section_end(address: VmAddr, size: usize) -> usize {
+    usize::from(address) + size
+}
+
+fn words_end(section: &Section) -> usize {
+    section_end(section.address, section.words.len() * 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_with_symbols;
+
+    fn sample_image() -> ExecutableImage {
+        let assembly = assemble_with_symbols(
+            "
+            start:  LOAD_IMM16 R0, value
+                    LOAD R1, R0
+                    HALT
+            .data
+            value:  .word 42
+            ",
+        )
+        .unwrap();
+        let mut image = ExecutableImage::from(&assembly);
+        image.bss.size = 4;
+        image
+    }
+
+    #[test]
+    fn test_round_trips_through_bytes() {
+        let image = sample_image();
+        let bytes = image.to_bytes().unwrap();
+        assert_eq!(&bytes[..4], b"VM16");
+        assert_eq!(ExecutableImage::from_bytes(&bytes).unwrap(), image);
+        assert_eq!(image.code.words.len(), 4);
+        assert_eq!(image.data.address, 0x1082);
+        assert_eq!(image.bss.address, 0x1084);
+    }
+
+    #[test]
+    fn test_flattens_to_program() {
+        let program = sample_image().to_program();
+        assert_eq!(program.load_address, START_ADDRESS);
+        assert_eq!(program.words.len(), 0x7C4);
+        assert!(program.words[4..0x7C1].iter().all(|word| *word == 0));
+        assert_eq!(&program.words[0x7C1..], &[42, 0, 0]);
+        assert_eq!(program.data_address, Some(START_ADDRESS + 8));
+    }
+
+    #[test]
+    fn test_canonical_bytes_ignore_symbols() {
+        let image = sample_image();
+        let stripped = ExecutableImage {
+            symbols: None,
+            ..image.clone()
+        };
+        assert_eq!(
+            image.canonical_bytes().unwrap(),
+            stripped.to_bytes().unwrap()
+        );
+        assert_ne!(image.to_bytes().unwrap(), stripped.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_rejects_invalid_images() {
+        let mut bytes = sample_image().to_bytes().unwrap();
+        bytes[0] = b'X';
+        assert!(matches!(
+            ExecutableImage::from_bytes(&bytes),
+            Err(VMError::InvalidImageMagic)
+        ));
+        assert!(matches!(
+            ExecutableImage::from_bytes(&[1, 2, 3]),
+            Err(VMError::MalformedImage)
+        ));
+
+        let mut image = sample_image();
+        image.header.version = 2;
+        assert!(matches!(
+            image.validate(),
+            Err(VMError::UnsupportedImageVersion)
+        ));
+
+        let mut image = sample_image();
+        image.data.address = START_ADDRESS + 6; // overlaps the last code word
+        assert!(matches!(image.validate(), Err(VMError::InvalidImageLayout)));
+
+        let mut image = sample_image();
+        image.header.entry_point = image.data.address;
+        assert!(matches!(image.validate(), Err(VMError::InvalidEntryPoint)));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::constants::VmAddr;
+use crate::error::VMError;
+
+/*
+    Traps and interrupts.
+
+    The vector table sits at the bottom of the prefix segment, the 256 bytes reserved below START_ADDRESS: 16 words, each the
+    address of a handler, or 0 when there is none.
+
+        vector 0        invalid instruction (unknown opcode, condition, sub-operation or syscall)
+        vector 1        memory fault (out of bounds, unmapped or protected access, invalid jump target)
+        vector 2        divide by zero
+        vector 3        stack overflow or underflow
+        vector 4        arithmetic overflow, only raised in `OverflowMode::Trap`
+        vector 5        page fault, the faulting virtual address is in RFAR (see `mmu.rs`)
+        vector 8 + n    external interrupt line n
+
+    Entering a handler pushes RFLAGS and then the return address, clears the interrupt-enable and user mode flags and
+    jumps to the handler, IRET pops both back. A fault returns to the faulting instruction, so a handler that does not remove the
+    cause has to skip it by adjusting the saved address. An interrupt is taken between instructions, only while the
+    interrupt-enable flag is set, and returns to the next instruction.
+    A fault without a handler, or whose handler cannot be entered because the stack is full, halts the machine.
+*/
+
+pub const VECTOR_TABLE: VmAddr = 0x0000;
+pub const VECTOR_TABLE_SIZE: usize = 0x20; // 16 vectors of one word
+pub const IRQ_LINES: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrapVector {
+    InvalidInstruction,
+    MemoryFault,
+    DivideByZero,
+    StackFault,
+    Overflow,
+    PageFault,
+    Interrupt(u8), // external interrupt line, below IRQ_LINES
+}
+
+impl TrapVector {
+    pub fn id(&self) -> u8 {
+        match self {
+            TrapVector::InvalidInstruction => 0,
+            TrapVector::MemoryFault => 1,
+            TrapVector::DivideByZero => 2,
+            TrapVector::StackFault => 3,
+            TrapVector::Overflow => 4,
+            TrapVector::PageFault => 5,
+            TrapVector::Interrupt(line) => 8 + line,
+        }
+    }
+
+    // Address of the vector table entry holding the handler
+    pub fn entry(&self) -> VmAddr {
+        VECTOR_TABLE + 2 * VmAddr::from(self.id())
+    }
+
+    // The vector a fault is delivered to, errors without one always halt the machine
+    pub fn for_error(error: &VMError) -> Option<Self> {
+        match error.cause() {
+            VMError::OpcodeDoesNotExist
+            | VMError::ConditionDoesNotExist
+            | VMError::AluFunctionDoesNotExist
+            | VMError::StackOperationDoesNotExist
+            | VMError::WideOperationDoesNotExist
+            | VMError::IntOperationDoesNotExist
+            | VMError::UnknownSyscall(_)
+            | VMError::PrivilegedInstruction => Some(TrapVector::InvalidInstruction),
+            VMError::OutOfBounds(_)
+            | VMError::InvalidJumpTarget(_)
+            | VMError::Unmapped(_)
+            | VMError::ProtectionFault { .. } => Some(TrapVector::MemoryFault),
+            VMError::DivideByZero => Some(TrapVector::DivideByZero),
+            VMError::StackOverflow | VMError::StackUnderflow => Some(TrapVector::StackFault),
+            VMError::Overflow => Some(TrapVector::Overflow),
+            VMError::PageFault { .. } => Some(TrapVector::PageFault),
+            _ => None,
+        }
+    }
+}
@@ -1,65 +1,74 @@
 use crate::{
-    bus::BusDevice, memory::LinearMemory, utils::build_simple_program, vm::VM, zk::ZkContext,
+    config::TraceMode,
+    program::Program,
+    utils::build_simple_program,
+    vm::{ExitReason, VM},
+    zk::ZkContext,
 };
 
+pub mod asm;
+pub mod block;
 pub mod bus;
+pub mod config;
 pub mod constants;
+pub mod debugger;
+pub mod disasm;
 pub mod error;
+pub mod gdb;
+pub mod host;
+pub mod image;
+pub mod interrupt;
 pub mod memory;
+pub mod mmu;
+pub mod program;
+pub mod protection;
 pub mod register;
+pub mod system_bus;
+pub mod timer;
+pub mod uart;
 pub mod utils;
 pub mod vm;
+pub mod watch;
 pub mod zk;
-use constants::START_ADDRESS;
+
+// Bounds the demo run, the demo program needs far less
+const DEMO_GAS_LIMIT: u64 = 10_000;
 
 pub fn start_vm() {
     dotenv::dotenv().ok();
     println!("VM is running...");
 
-    let program = build_simple_program();
-    let mut vm = VM::new();
+    let program = Program::new(build_simple_program());
+    // The demo program has no HALT, it runs off its end into zeroed memory, which only works without protection
+    let mut vm = VM::builder()
+        .trace(TraceMode::Zk)
+        .gas_limit(DEMO_GAS_LIMIT)
+        .memory_protection(false)
+        .build()
+        .expect("the default config is valid");
 
     // Public inputs, used for the zk logic
     let mut public_inputs = ZkContext::new();
-    if public_inputs.set_public_program(program.clone()).is_err() {
+    if public_inputs.set_public_program(&program).is_err() {
         eprintln!("Error settings public inputs for program");
     }
 
     // This loads (write) the program into memory at the specified addresses (NOT EXECUTE)
-    let mut memory = LinearMemory::new(5000);
-    for (i, add_reg) in program.iter().enumerate() {
-        let address_to_write = u16::try_from(i)
-            // START_ADDRESS + (i as u16) * 2;
-            .expect("Value out of range for u16")
-            .checked_mul(2) // Implementation of a for loop step by 2
-            .expect("i * 2 failed")
-            .checked_add(START_ADDRESS)
-            .expect("Index + 0x100 out of range");
-
-        println!("\nAddress: {}, Value: {}", address_to_write, add_reg);
-
-        if let Err(e) = memory.write2(address_to_write, *add_reg) {
-            println!(
-                "Writing on memory error on location: {}, err: {}",
-                address_to_write, e
-            );
-        }
+    if let Err(e) = vm.load_program(&program) {
+        eprintln!("Cannot load the program: {e}");
+        return;
     }
 
-    vm.set_memory(Box::new(memory));
-    vm.enable_trace();
-    vm.enable_zk_output();
-
-    while !vm.halted {
-        if let Err(e) = vm.tick() {
-            eprintln!("Vm error: {}", e.message());
-            break;
-        }
+    let result = vm.run();
+    match &result.reason {
+        ExitReason::Halted => {}
+        ExitReason::Error(e) => eprintln!("Vm error: {e}"),
+        reason => eprintln!("Vm stopped early: {reason:?}"),
     }
 
     // Capture the OUTPUT state of the VM
     if public_inputs
-        .set_public_output(&vm.registers, &*vm.memory)
+        .set_public_output(&vm.registers, &*vm.memory, &vm.config)
         .is_err()
     {
         eprintln!("Cannot capture the output state from the VM.");
@@ -67,9 +76,10 @@ pub fn start_vm() {
 
     VM::_write_logs(public_inputs, "public_inputs");
 
-    if let Some(program_result) = vm.memory.read2(START_ADDRESS) {
-        println!("The Value at address 0x100 is {}", program_result);
+    if let Some(program_result) = result.output {
+        let output = vm.config.output_address();
+        println!("The Value at address {output:#06x} is {}", program_result);
     } else {
-        eprintln!("Could not read memory at 0x1000");
+        eprintln!("Could not read the output word");
     }
 }
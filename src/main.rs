@@ -1,5 +1,524 @@
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{Value, json};
+
+use rust_vm::asm::assemble_with_map;
+use rust_vm::config::{Config, MemoryMap};
+use rust_vm::constants::VmAddr;
+use rust_vm::debugger::Debugger;
+use rust_vm::disasm::disassemble_image;
+use rust_vm::error::VMError;
+use rust_vm::gdb::GdbStub;
+use rust_vm::image::{ExecutableImage, IMAGE_MAGIC};
+use rust_vm::memory::LinearMemory;
+use rust_vm::protection::Permissions;
 use rust_vm::start_vm;
+use rust_vm::system_bus::SystemBus;
+use rust_vm::uart::Uart;
+use rust_vm::vm::{ExitReason, TraceEntry, VM};
+use rust_vm::zk::ZkContext;
+
+/*
+    Command-line front end of the VM.
+
+    Every command taking an <IMAGE> accepts either an executable image (see `image.rs`) or assembly source,
+    the two are told apart by the image magic bytes. Results go to stdout, diagnostics to stderr.
+
+    Exit codes, so the binary can be used from scripts:
+        0   the program halted (or the command succeeded)
+        1   the VM stopped with a VMError, while loading or executing
+        2   invalid arguments or input that cannot be read or assembled
+        3   the step limit was reached before the program halted
+        4   the program ran out of gas
+*/
+
+const EXIT_VM_ERROR: u8 = 1;
+const EXIT_INVALID_INPUT: u8 = 2;
+const EXIT_STEP_LIMIT: u8 = 3;
+const EXIT_OUT_OF_GAS: u8 = 4;
+
+#[derive(Parser)]
+#[command(name = "rust-vm", version, about = "16-bit virtual machine")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Load an image and run it until it halts
+    Run(MachineArgs),
+    /// Assemble a source file into an executable image
+    Asm {
+        source: PathBuf,
+        /// Output image, defaults to the source path with an `.img` extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// VM configuration file, `.data` is placed in the data segment of its memory map
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Print an assembler listing of an image
+    Disasm {
+        image: PathBuf,
+        /// VM configuration file, only the code segment of its memory map is disassembled
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Run an image and print every executed instruction
+    Trace {
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[arg(long, value_enum, default_value_t = TraceFormat::Text)]
+        format: TraceFormat,
+    },
+    /// Run an image and print its public and private ZK inputs as JSON
+    Zk(MachineArgs),
+    /// Debug an image interactively, or replay a command file
+    Debug {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Read debugger commands from this file instead of stdin
+        #[arg(long)]
+        script: Option<PathBuf>,
+    },
+    /// Serve an image to gdb or lldb over the GDB remote serial protocol
+    Gdb {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// TCP port to listen on, on localhost
+        #[arg(long, default_value_t = 1234)]
+        port: u16,
+    },
+    /// Run the built-in demo program
+    Demo,
+}
+
+// The flags override the matching settings of `--config`
+#[derive(Args)]
+struct MachineArgs {
+    image: PathBuf,
+    /// VM configuration file, TOML or JSON (by `.json` extension)
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Memory size in bytes [default: 5000]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(2..=0x10000))]
+    memory_size: Option<u32>,
+    /// Start executing here instead of at the entry point of the image
+    #[arg(long, value_parser = parse_address)]
+    start_address: Option<VmAddr>,
+    /// Stop after this many instructions
+    #[arg(long)]
+    max_steps: Option<u64>,
+    /// Gas budget, instructions are charged by the cost table of the config
+    #[arg(long)]
+    gas: Option<u64>,
+    /// Map a UART console on stdin/stdout at this address, after the memory
+    #[arg(long, value_parser = parse_address)]
+    uart: Option<VmAddr>,
+    /// Let the program write its code and the vector table and execute anywhere, for legacy programs
+    #[arg(long)]
+    no_memory_protection: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TraceFormat {
+    Text,
+    Json,
+}
+
+// How the CLI reports the way a run ended
+trait Outcome {
+    fn exit_code(&self) -> ExitCode;
+    fn name(&self) -> &'static str;
+    fn report(&self, steps: u64);
+}
+
+// `VM::run` has no predicate, so it never stops at a breakpoint
+impl Outcome for ExitReason {
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            ExitReason::Halted => ExitCode::SUCCESS,
+            ExitReason::Error(_) => ExitCode::from(EXIT_VM_ERROR),
+            ExitReason::StepLimit | ExitReason::Breakpoint => ExitCode::from(EXIT_STEP_LIMIT),
+            ExitReason::OutOfGas => ExitCode::from(EXIT_OUT_OF_GAS),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ExitReason::Halted => "halted",
+            ExitReason::Error(_) => "error",
+            ExitReason::StepLimit | ExitReason::Breakpoint => "step_limit",
+            ExitReason::OutOfGas => "out_of_gas",
+        }
+    }
+
+    fn report(&self, steps: u64) {
+        match self {
+            ExitReason::Halted => eprintln!("halted after {steps} steps"),
+            ExitReason::Error(error) => {
+                eprintln!("error after {steps} steps: {error}")
+            }
+            ExitReason::StepLimit | ExitReason::Breakpoint => {
+                eprintln!("step limit reached after {steps} steps")
+            }
+            ExitReason::OutOfGas => eprintln!("out of gas after {steps} steps"),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Run(args) => run(&args),
+        Command::Asm {
+            source,
+            output,
+            config,
+        } => asm(&source, output, config.as_deref()),
+        Command::Disasm { image, config } => disasm(&image, config.as_deref()),
+        Command::Trace { machine, format } => trace(&machine, format),
+        Command::Zk(args) => zk(&args),
+        Command::Debug { machine, script } => debug(&machine, script.as_deref()),
+        Command::Gdb { machine, port } => gdb(&machine, port),
+        Command::Demo => {
+            start_vm();
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+fn run(args: &MachineArgs) -> ExitCode {
+    let (mut vm, _) = match boot(args) {
+        Ok(booted) => booted,
+        Err(code) => return code,
+    };
+    let result = vm.run();
+    result.reason.report(result.steps);
+    if let Some(gas) = vm.remaining_gas() {
+        eprintln!("{gas} gas left");
+    }
+
+    let registers: Vec<String> = result
+        .registers
+        .register_map
+        .values()
+        .map(|reg| format!("{}={:#06x}", reg.id.name(), reg.value))
+        .collect();
+    println!("{}", registers.join(" "));
+    if let Some(output) = result.output {
+        let address = vm.config.output_address();
+        println!("output ({address:#06x}) = {output:#06x}");
+    }
+    result.reason.exit_code()
+}
+
+fn asm(source: &Path, output: Option<PathBuf>, config: Option<&Path>) -> ExitCode {
+    let output = output.unwrap_or_else(|| source.with_extension("img"));
+    let config = match read_config(config) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    let image = match read_source(source, &config.memory_map) {
+        Ok(image) => image,
+        Err(error) => return invalid_input(source, &error),
+    };
+    match image.write_to_file(&output) {
+        Ok(()) => {
+            eprintln!("wrote {}", output.display());
+            ExitCode::SUCCESS
+        }
+        Err(error) => invalid_input(&output, &error),
+    }
+}
+
+fn disasm(path: &Path, config: Option<&Path>) -> ExitCode {
+    let config = match read_config(config) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    match load_image(path, &config.memory_map) {
+        Ok(image) => {
+            print!("{}", disassemble_image(&image, &config.memory_map));
+            ExitCode::SUCCESS
+        }
+        Err(error) => invalid_input(path, &error),
+    }
+}
+
+fn trace(args: &MachineArgs, format: TraceFormat) -> ExitCode {
+    let (mut vm, _) = match boot(args) {
+        Ok(booted) => booted,
+        Err(code) => return code,
+    };
+    vm.enable_trace();
+    let result = vm.run();
+
+    match format {
+        TraceFormat::Text => {
+            for entry in &result.trace {
+                println!("{entry}");
+            }
+            result.reason.report(result.steps);
+        }
+        TraceFormat::Json => {
+            let mut document = json!({
+                "outcome": result.reason.name(),
+                "steps": result.steps,
+                "trace": result.trace.iter().map(trace_entry_json).collect::<Vec<_>>(),
+            });
+            if let ExitReason::Error(error) = &result.reason {
+                document["error"] = json!(error.to_string());
+            }
+            println!("{document:#}");
+        }
+    }
+    result.reason.exit_code()
+}
+
+fn zk(args: &MachineArgs) -> ExitCode {
+    let (mut vm, image) = match boot(args) {
+        Ok(booted) => booted,
+        Err(code) => return code,
+    };
+    let mut zk_context = ZkContext::new();
+    if let Err(error) = zk_context.set_public_program(image) {
+        return vm_error(&error);
+    }
+
+    vm.enable_trace();
+    vm.enable_zk_output();
+    let result = vm.run();
+    result.reason.report(result.steps);
+    if !result.is_halted() {
+        return result.reason.exit_code();
+    }
+
+    if let Err(error) = zk_context.set_public_output(&vm.registers, &*vm.memory, &vm.config) {
+        return vm_error(&error);
+    }
+    let document = json!({
+        "public_inputs": {
+            "program_hash": zk_context.public_program_hash.to_string(),
+            "output_hash": zk_context.public_output_hash.to_string(),
+        },
+        "private_inputs": {
+            "program_sha254": zk_context.private_program_sha254.to_string(),
+            "output_sha254": zk_context.private_output_sha254.to_string(),
+        },
+        "steps": result.steps,
+    });
+    println!("{document:#}");
+    ExitCode::SUCCESS
+}
+
+// The step limit bounds every `continue` instead of the whole session, so a scripted session cannot hang on an endless loop
+fn debug(args: &MachineArgs, script: Option<&Path>) -> ExitCode {
+    let (mut vm, image) = match boot(args) {
+        Ok(booted) => booted,
+        Err(code) => return code,
+    };
+    let continue_limit = vm.config.max_steps.take();
+    let mut debugger = Debugger::new(vm, image.symbols.as_deref().unwrap_or_default());
+    debugger.continue_limit = continue_limit;
+
+    let session = match script {
+        Some(path) => match fs::File::open(path) {
+            Ok(file) => debugger.run_session(BufReader::new(file), &mut io::stdout(), false),
+            Err(error) => return invalid_input(path, &VMError::Io(error)),
+        },
+        None => debugger.run_session(io::stdin().lock(), &mut io::stdout(), true),
+    };
+    if let Err(error) = session {
+        return vm_error(&VMError::Io(error));
+    }
+    ExitCode::SUCCESS
+}
+
+// gdb decides how long the program runs, the step limit does not apply
+fn gdb(args: &MachineArgs, port: u16) -> ExitCode {
+    let (mut vm, _) = match boot(args) {
+        Ok(booted) => booted,
+        Err(code) => return code,
+    };
+    vm.config.max_steps = None;
+    let served = TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+        eprintln!("waiting for gdb on {}", listener.local_addr()?);
+        GdbStub::new(vm).serve(&listener)
+    });
+    match served {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => vm_error(&VMError::Io(error)),
+    }
+}
+
+// Reads the configuration and the image and loads it into a fresh VM, on failure the exit code is returned instead
+fn boot(args: &MachineArgs) -> Result<(VM, ExecutableImage), ExitCode> {
+    let config = read_config(args.config.as_deref())?;
+    let memory_map = config.memory_map.clone();
+    let memory_size = args
+        .memory_size
+        .map_or(config.memory_size, |size| size as usize);
+    let mut builder = VM::builder().config(config).memory_size(memory_size);
+    if let Some(uart) = args.uart {
+        let bus = SystemBus::new()
+            .with(
+                "ram",
+                0x0000,
+                Box::new(LinearMemory::new(memory_size)),
+                Permissions::ALL,
+            )
+            .and_then(|bus| {
+                bus.with(
+                    "uart",
+                    uart,
+                    Box::new(Uart::stdio()),
+                    Permissions::READ_WRITE,
+                )
+            })
+            .map_err(|error| vm_error(&error))?;
+        builder = builder.memory(Box::new(bus));
+    }
+    if let Some(start_address) = args.start_address {
+        builder = builder.entry_point(start_address);
+    }
+    if let Some(max_steps) = args.max_steps {
+        builder = builder.max_steps(max_steps);
+    }
+    if let Some(gas) = args.gas {
+        builder = builder.gas_limit(gas);
+    }
+    if args.no_memory_protection {
+        builder = builder.memory_protection(false);
+    }
+    let mut vm = builder.build().map_err(|error| vm_error(&error))?;
+
+    let image =
+        load_image(&args.image, &memory_map).map_err(|error| invalid_input(&args.image, &error))?;
+    vm.load_program(&image.to_program())
+        .map_err(|error| vm_error(&error))?;
+    Ok((vm, image))
+}
+
+fn read_config(path: Option<&Path>) -> Result<Config, ExitCode> {
+    match path {
+        Some(path) => Config::from_file(path).map_err(|error| invalid_input(path, &error)),
+        None => Ok(Config::default()),
+    }
+}
+
+// Source is assembled against `memory_map`, the one of the VM it is loaded into
+fn load_image(path: &Path, memory_map: &MemoryMap) -> Result<ExecutableImage, VMError> {
+    let bytes = fs::read(path)?;
+    if bytes.starts_with(&IMAGE_MAGIC) {
+        ExecutableImage::from_bytes(&bytes)
+    } else {
+        read_source(path, memory_map)
+    }
+}
+
+fn read_source(path: &Path, memory_map: &MemoryMap) -> Result<ExecutableImage, VMError> {
+    let source = fs::read_to_string(path)?;
+    let assembly = assemble_with_map(&source, memory_map)?;
+    Ok(ExecutableImage::from(&assembly))
+}
+
+fn trace_entry_json(entry: &TraceEntry) -> Value {
+    let registers: serde_json::Map<String, Value> = entry
+        .registers
+        .values()
+        .map(|reg| (reg.id.name().to_string(), json!(reg.value)))
+        .collect();
+    json!({
+        "addr": entry.addr(),
+        "instruction": entry.text(),
+        "opcode": entry.opcode.to_string(),
+        "dst": entry.dst,
+        "src": entry.src,
+        "imm": entry.imm,
+        "ext": entry.ext,
+        "host_function": entry.host_function,
+        "registers": registers,
+    })
+}
+
+fn parse_address(text: &str) -> Result<VmAddr, String> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => VmAddr::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("`{text}` is not a 16-bit address"))
+}
+
+fn invalid_input(path: &Path, error: &VMError) -> ExitCode {
+    eprintln!("{}: {error}", path.display());
+    ExitCode::from(EXIT_INVALID_INPUT)
+}
+
+fn vm_error(error: &VMError) -> ExitCode {
+    eprintln!("error: {error}");
+    ExitCode::from(EXIT_VM_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_vm::asm::assemble_with_symbols;
+    use rust_vm::register::RegisterId;
+
+    fn booted(source: &str) -> VM {
+        let image = ExecutableImage::from(&assemble_with_symbols(source).unwrap());
+        let mut vm = VM::builder().build().unwrap();
+        vm.load_program(&image.to_program()).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_run_outcomes() {
+        let mut vm = booted("COPY R0, 3\nHALT");
+        let result = vm.run();
+        assert!(result.is_halted());
+        assert_eq!(result.steps, 2);
+        assert_eq!(
+            vm.registers
+                .get_register_read_only(RegisterId::RR0.id())
+                .unwrap()
+                .value,
+            3
+        );
+
+        let mut vm = booted("loop: JMP loop");
+        vm.config.max_steps = Some(10);
+        let result = vm.run();
+        assert!(matches!(result.reason, ExitReason::StepLimit));
+        assert_eq!(result.steps, 10);
+        assert_eq!(result.reason.name(), "step_limit");
+
+        let mut vm = booted("loop: JMP loop");
+        vm.set_gas_budget(3);
+        let result = vm.run();
+        assert!(matches!(result.reason, ExitReason::OutOfGas));
+        assert_eq!(result.steps, 3);
+
+        let mut vm = booted("RET");
+        let result = vm.run();
+        assert!(matches!(
+            &result.reason,
+            ExitReason::Error(error) if matches!(error.cause(), VMError::StackUnderflow)
+        ));
+        assert_eq!(result.steps, 0);
+    }
 
-fn main() {
-    start_vm();
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("0x0100"), Ok(0x100));
+        assert_eq!(parse_address("256"), Ok(0x100));
+        assert!(parse_address("0x10000").is_err());
+    }
 }
@@ -1,5 +1,5 @@
 use crate::bus::BusDevice;
-use crate::constants::VmAddr;
+use crate::constants::{VMWord, VmAddr};
 use crate::error::{Result, VMError};
 
 #[derive(Debug, Clone)]
@@ -16,6 +16,19 @@ impl LinearMemory {
             size: n,
         }
     }
+
+    // Copies little-endian words starting at addr, nothing is written when they do not all fit
+    pub fn load_at(&mut self, addr: VmAddr, words: &[VMWord]) -> Result<()> {
+        let start = usize::from(addr);
+        let end = start + words.len() * 2;
+        if end > self.size {
+            return Err(VMError::ProgramDoesNotFit);
+        }
+        for (chunk, word) in self.bytes[start..end].chunks_exact_mut(2).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(())
+    }
 }
 
 impl BusDevice for LinearMemory {
@@ -29,7 +42,7 @@ impl BusDevice for LinearMemory {
             self.bytes[addr_idx] = value;
             Ok(())
         } else {
-            Err(VMError::OutOfBounds)
+            Err(VMError::OutOfBounds(addr))
         }
     }
 
@@ -0,0 +1,463 @@
+use crate::bus::BusDevice;
+use crate::constants::{VMWord, VmAddr};
+use crate::error::{Result, VMError};
+use crate::protection::{Access, Permissions};
+
+/*
+    Paged virtual memory.
+
+    `Mmu` sits between the VM and its memory and translates every address the program uses into a physical one.
+    The 16-bit address space is split into 256 pages of 256 bytes: the high byte of an address is the page number
+    and the low byte the offset into the page. A page table is 256 words in physical memory, one entry per page:
+
+        bits 15-8   frame       physical page the virtual page maps to
+        bit 2       USER        user mode may access the page
+        bit 1       WRITABLE    the page may be written
+        bit 0       PRESENT     the entry is valid
+
+    RPTBR holds the physical address of the page table of the running program. 0 turns translation off and every
+    address maps to itself, the identity mode a VM starts in, so programs that never touch RPTBR run unchanged.
+    Programs with their own page tables cannot reach each other's frames, switching RPTBR switches address spaces.
+
+    The FLAG_USER bit of RFLAGS runs the program in user mode, where only USER pages are accessible and HALT,
+    IRET/EI/DI and writes to RFLAGS, RPTBR and RFAR fault (see `VM::check_privilege`), so does pointing RSP outside
+    the stack, which trap frames are pushed onto with supervisor rights. Entering a trap or an interrupt clears
+    FLAG_USER, IRET restores it together with RFLAGS, so handlers run in supervisor mode.
+    The VM hands RPTBR and the mode to the bus through `BusDevice::set_address_space` before every instruction,
+    a new page table takes effect with the next instruction.
+
+    An access the entry does not allow fails with `VMError::PageFault`, which raises the page fault vector with the
+    faulting virtual address in RFAR. Like any fault the handler returns to the faulting instruction, so a handler
+    that maps the page makes it succeed when it is retried. The vector table is read through the page table too,
+    it has to be mapped in every address space, just like the handlers and the stack.
+    Page tables are read from physical memory directly. `protect` and the inspection methods (`as_bytes`,
+    `get_subset_of_memory`, ...) address physical memory as well.
+*/
+
+pub const PAGE_SIZE: usize = 0x100;
+pub const PAGE_TABLE_SIZE: usize = 2 * 0x100; // one word per page
+
+pub const PAGE_PRESENT: VMWord = 1 << 0;
+pub const PAGE_WRITABLE: VMWord = 1 << 1;
+pub const PAGE_USER: VMWord = 1 << 2;
+
+// Page table entry mapping a page to physical page `frame`
+pub fn page_entry(frame: u8, flags: VMWord) -> VMWord {
+    (VMWord::from(frame) << 8) | flags
+}
+
+/// Bus device translating virtual addresses through the page table at `page_table` before forwarding to `inner`
+#[derive(Debug)]
+pub struct Mmu {
+    inner: Box<dyn BusDevice>,
+    page_table: VmAddr, // physical address, 0 in identity mode
+    user: bool,
+}
+
+impl Mmu {
+    pub fn new(inner: Box<dyn BusDevice>) -> Self {
+        Self {
+            inner,
+            page_table: 0,
+            user: false,
+        }
+    }
+
+    pub fn page_table(&self) -> VmAddr {
+        self.page_table
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.user
+    }
+
+    /// Physical address of `addr`, failing with a PageFault when its page table entry does not allow `access`
+    pub fn translate(&self, addr: VmAddr, access: Access) -> Result<VmAddr> {
+        if self.page_table == 0 {
+            return Ok(addr);
+        }
+        let fault = || VMError::PageFault { addr, kind: access };
+        let entry = self
+            .page_table
+            .checked_add(2 * (addr >> 8))
+            .and_then(|entry_addr| self.inner.read2(entry_addr))
+            .ok_or_else(fault)?;
+        let allowed = entry & PAGE_PRESENT != 0
+            && (access != Access::Write || entry & PAGE_WRITABLE != 0)
+            && (!self.user || entry & PAGE_USER != 0);
+        if !allowed {
+            return Err(fault());
+        }
+        Ok((entry & 0xFF00) | (addr & 0x00FF))
+    }
+
+    // Words whose bytes lie in different pages are translated byte by byte
+    fn splits_page(&self, addr: VmAddr) -> bool {
+        self.page_table != 0 && addr & 0x00FF == 0x00FF
+    }
+
+    fn read_byte(&self, addr: VmAddr, access: Access) -> Result<u8> {
+        let physical = self.translate(addr, access)?;
+        self.inner
+            .read(physical)
+            .ok_or(VMError::OutOfBounds(physical))
+    }
+
+    fn read_word(&self, addr: VmAddr, access: Access) -> Result<u16> {
+        if self.splits_page(addr) {
+            let high = addr.checked_add(1).ok_or(VMError::OutOfBounds(addr))?;
+            let low = self.read_byte(addr, access)?;
+            return Ok(u16::from(low) | (u16::from(self.read_byte(high, access)?) << 8));
+        }
+        let physical = self.translate(addr, access)?;
+        match access {
+            Access::Execute => self.inner.try_fetch2(physical),
+            _ => self.inner.try_read2(physical),
+        }
+    }
+}
+
+impl BusDevice for Mmu {
+    fn read(&self, addr: VmAddr) -> Option<u8> {
+        self.read_byte(addr, Access::Read).ok()
+    }
+
+    fn write(&mut self, addr: VmAddr, value: u8) -> Result<()> {
+        let physical = self.translate(addr, Access::Write)?;
+        self.inner.write(physical, value)
+    }
+
+    fn read2(&self, addr: VmAddr) -> Option<u16> {
+        self.read_word(addr, Access::Read).ok()
+    }
+
+    fn fetch2(&self, addr: VmAddr) -> Option<u16> {
+        self.read_word(addr, Access::Execute).ok()
+    }
+
+    fn try_read2(&self, addr: VmAddr) -> Result<u16> {
+        self.read_word(addr, Access::Read)
+    }
+
+    fn try_fetch2(&self, addr: VmAddr) -> Result<u16> {
+        self.read_word(addr, Access::Execute)
+    }
+
+    fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
+        if self.splits_page(addr) {
+            // Both pages are checked first, so a failing word write changes nothing
+            let high = addr.checked_add(1).ok_or(VMError::OutOfBounds(addr))?;
+            let low_physical = self.translate(addr, Access::Write)?;
+            let high_physical = self.translate(high, Access::Write)?;
+            self.inner.write(low_physical, value as u8)?;
+            return self.inner.write(high_physical, (value >> 8) as u8);
+        }
+        let physical = self.translate(addr, Access::Write)?;
+        self.inner.write2(physical, value)
+    }
+
+    // Inspection sees the address space of the running program
+    fn peek(&self, addr: VmAddr) -> Option<u8> {
+        let physical = self.translate(addr, Access::Read).ok()?;
+        self.inner.peek(physical)
+    }
+
+    fn peek2(&self, addr: VmAddr) -> Option<u16> {
+        if self.splits_page(addr) {
+            let low = self.peek(addr)?;
+            let high = self.peek(addr.checked_add(1)?)?;
+            return Some(u16::from(low) | (u16::from(high) << 8));
+        }
+        let physical = self.translate(addr, Access::Read).ok()?;
+        self.inner.peek2(physical)
+    }
+
+    fn set_address_space(&mut self, page_table: VmAddr, user: bool) {
+        self.page_table = page_table;
+        self.user = user;
+    }
+
+    fn protect(&mut self, start: VmAddr, len: usize, permissions: Permissions) {
+        self.inner.protect(start, len, permissions)
+    }
+
+    fn memory_range(&self) -> usize {
+        self.inner.memory_range()
+    }
+
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.inner.pending_interrupts()
+    }
+
+    fn acknowledge_interrupt(&mut self, line: u8) {
+        self.inner.acknowledge_interrupt(line)
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.inner.dma_pending()
+    }
+
+    fn dma(&mut self, memory: &mut dyn BusDevice) {
+        self.inner.dma(memory)
+    }
+
+    fn as_bytes(&self) -> &Vec<u8> {
+        self.inner.as_bytes()
+    }
+
+    fn get_specific_memory_location(&self, idx: usize) -> u16 {
+        self.inner.get_specific_memory_location(idx)
+    }
+
+    fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8> {
+        self.inner.get_subset_of_memory(start_addr, end_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::config::{Config, MemoryMap, Segment, StackRegion};
+    use crate::interrupt::TrapVector;
+    use crate::memory::LinearMemory;
+    use crate::register::{FLAG_USER, RegisterId};
+    use crate::vm::{ExitReason, VM};
+
+    const PAGE_TABLE: VmAddr = 0x1000;
+
+    fn mmu() -> Mmu {
+        let mut mmu = Mmu::new(Box::new(LinearMemory::new(0x2000)));
+        let table = [
+            (0x00, page_entry(0x00, PAGE_PRESENT)),
+            (
+                0x04,
+                page_entry(0x08, PAGE_PRESENT | PAGE_WRITABLE | PAGE_USER),
+            ),
+            (0x05, page_entry(0x09, PAGE_PRESENT | PAGE_USER)),
+        ];
+        for (page, entry) in table {
+            mmu.write2(PAGE_TABLE + 2 * page, entry).unwrap();
+        }
+        mmu
+    }
+
+    #[test]
+    fn test_translates_through_the_page_table() {
+        let mut mmu = mmu();
+        mmu.write2(0x08FF, 0x1234).unwrap();
+        assert_eq!(mmu.translate(0x04FF, Access::Read).unwrap(), 0x04FF); // identity mode
+
+        mmu.set_address_space(PAGE_TABLE, false);
+        assert_eq!(mmu.translate(0x04FF, Access::Read).unwrap(), 0x08FF);
+        assert_eq!(mmu.read2(0x04FF), Some(0x1234));
+        assert_eq!(mmu.read2(0x0010), Some(0)); // the vector table, supervisor only
+
+        // The word at 0x05ff spans a writable and a read-only page
+        assert!(matches!(
+            mmu.write2(0x05FF, 1),
+            Err(VMError::PageFault {
+                addr: 0x05FF,
+                kind: Access::Write
+            })
+        ));
+        mmu.write2(0x04FE, 0xBEEF).unwrap();
+        assert!(matches!(
+            mmu.write2(0x05FE, 1),
+            Err(VMError::PageFault {
+                addr: 0x05FE,
+                kind: Access::Write
+            })
+        ));
+        assert!(matches!(
+            mmu.try_fetch2(0x0600),
+            Err(VMError::PageFault {
+                addr: 0x0600,
+                kind: Access::Execute
+            })
+        ));
+
+        mmu.set_address_space(PAGE_TABLE, true);
+        assert_eq!(mmu.read2(0x04FE), Some(0xBEEF));
+        assert!(matches!(
+            mmu.try_read2(0x0010),
+            Err(VMError::PageFault {
+                addr: 0x0010,
+                kind: Access::Read
+            })
+        ));
+    }
+
+    // Supervisor pages 0-3 (prefix, kernel, stack) map to themselves, user pages 4 (code) and 5 (data) to the
+    // frames of the program. The segments are packed into those pages, the stack at 0x300..0x400. The kernel at
+    // 0x100 halts the machine once the program traps to it, it is written in place rather than loaded, so the
+    // memory is unprotected.
+    fn paged_vm() -> VM {
+        let memory_map = MemoryMap {
+            code: Segment::new(0x0100, 0x0200),
+            rodata: Segment::new(0x0200, 0x0280),
+            data: Segment::new(0x0280, 0x0300),
+            ..MemoryMap::default()
+        };
+        let mut vm = VM::builder()
+            .config(Config {
+                memory_map,
+                ..Config::default()
+            })
+            .memory_size(0x2000)
+            .stack(StackRegion {
+                base: 0x0300,
+                top: 0x0400,
+            })
+            .memory_protection(false)
+            .build()
+            .unwrap();
+        let kernel = assemble("HALT").unwrap();
+        for (addr, word) in (0x0100..).step_by(2).zip(kernel) {
+            vm.memory.write2(addr, word).unwrap();
+        }
+        for vector in [TrapVector::InvalidInstruction, TrapVector::PageFault] {
+            vm.memory.write2(vector.entry(), 0x0100).unwrap();
+        }
+        vm
+    }
+
+    // Maps the user pages of a program to `code` and `data`, with its page table at `table`
+    fn map_program(vm: &mut VM, table: VmAddr, code: u8, data: u8, source: &str) {
+        for page in 0..4 {
+            let flags = if page == 3 {
+                PAGE_PRESENT | PAGE_WRITABLE | PAGE_USER
+            } else {
+                PAGE_PRESENT | PAGE_WRITABLE
+            };
+            vm.memory
+                .write2(table + 2 * page, page_entry(page as u8, flags))
+                .unwrap();
+        }
+        let user = PAGE_PRESENT | PAGE_WRITABLE | PAGE_USER;
+        vm.memory
+            .write2(table + 2 * 0x04, page_entry(code, user))
+            .unwrap();
+        vm.memory
+            .write2(table + 2 * 0x05, page_entry(data, user))
+            .unwrap();
+        let words = assemble(source).unwrap();
+        for (addr, word) in (VmAddr::from(code) << 8..).step_by(2).zip(words) {
+            vm.memory.write2(addr, word).unwrap();
+        }
+    }
+
+    fn enter_user_mode(vm: &mut VM, table: VmAddr) {
+        for (register, value) in [
+            (RegisterId::RPTBR, table),
+            (RegisterId::RPC, 0x0400),
+            (RegisterId::RFLAGS, FLAG_USER),
+        ] {
+            vm.registers.get_register_mut(register.id()).unwrap().value = value;
+        }
+    }
+
+    fn reg(vm: &VM, register: RegisterId) -> VMWord {
+        vm.registers
+            .get_register_read_only(register.id())
+            .unwrap()
+            .value
+    }
+
+    #[test]
+    fn test_programs_run_in_separate_address_spaces() {
+        let mut vm = paged_vm();
+        let program = |value: u8| {
+            format!("LOAD_IMM16 R1, 0x0500\nCOPY R0, {value}\nWRITE R1, R0\nCOPY RPTBR, R1\nHALT")
+        };
+        map_program(&mut vm, 0x1000, 0x08, 0x0A, &program(7));
+        map_program(&mut vm, 0x1200, 0x09, 0x0B, &program(9));
+
+        for table in [0x1000, 0x1200] {
+            vm.halted = false;
+            enter_user_mode(&mut vm, table);
+            assert!(vm.run().is_halted());
+            // COPY RPTBR is privileged, the kernel handler halted the machine in supervisor mode
+            assert_eq!(reg(&vm, RegisterId::RPTBR), table);
+            assert_eq!(reg(&vm, RegisterId::RFLAGS) & FLAG_USER, 0);
+            assert_eq!(vm.memory.read2(0x03FC), Some(0x0408));
+        }
+
+        vm.memory.set_address_space(0, false);
+        assert_eq!(vm.memory.read2(0x0A00), Some(7));
+        assert_eq!(vm.memory.read2(0x0B00), Some(9));
+        assert_eq!(vm.memory.read2(0x0500), Some(0));
+    }
+
+    #[test]
+    fn test_user_code_cannot_move_the_stack_onto_the_page_table() {
+        // The kernel maps the page table to edit it, traps push their frame with supervisor rights
+        let mut vm = paged_vm();
+        vm.memory
+            .write2(TrapVector::StackFault.entry(), 0x0100)
+            .unwrap();
+        let source = "ADDI16 RSP, -2\nLOAD_IMM16 RSP, 0x100C\nHALT";
+        map_program(&mut vm, PAGE_TABLE, 0x08, 0x0A, source);
+        let kernel_only = page_entry(0x10, PAGE_PRESENT | PAGE_WRITABLE);
+        vm.memory
+            .write2(PAGE_TABLE + 2 * 0x10, kernel_only)
+            .unwrap();
+        let entries = vm.memory.get_subset_of_memory(0x1000, 0x1020);
+
+        enter_user_mode(&mut vm, PAGE_TABLE);
+        assert!(vm.run().is_halted());
+        // Moving RSP inside the stack is fine, pointing it at the page table raised the stack fault
+        assert_eq!(vm.memory.read2(0x03FA), Some(0x0404));
+        assert_eq!(reg(&vm, RegisterId::RSP), 0x03FA);
+        assert_eq!(vm.memory.get_subset_of_memory(0x1000, 0x1020), entries);
+
+        // A kernel that left RSP outside the stack halts instead of pushing the frame there
+        vm.halted = false;
+        enter_user_mode(&mut vm, PAGE_TABLE);
+        vm.registers
+            .get_register_mut(RegisterId::RSP.id())
+            .unwrap()
+            .value = 0x100C;
+        vm.registers
+            .get_register_mut(RegisterId::RPC.id())
+            .unwrap()
+            .value = 0x0408;
+        let result = vm.run();
+        assert!(matches!(
+            result.reason,
+            ExitReason::Error(ref error) if matches!(error.cause(), VMError::PrivilegedInstruction)
+        ));
+        assert_eq!(vm.memory.get_subset_of_memory(0x1000, 0x1020), entries);
+    }
+
+    #[test]
+    fn test_page_faults_report_the_virtual_address() {
+        let mut vm = paged_vm();
+        map_program(
+            &mut vm,
+            0x1000,
+            0x08,
+            0x0A,
+            "LOAD_IMM16 R1, 0x0100\nLOAD R0, R1\nHALT",
+        );
+        enter_user_mode(&mut vm, 0x1000);
+        assert!(vm.run().is_halted());
+        // The kernel page is not a user page, the saved return address is the faulting LOAD
+        assert_eq!(reg(&vm, RegisterId::RFAR), 0x0100);
+        assert_eq!(vm.memory.read2(0x03FC), Some(0x0404));
+
+        // Without a handler the fault halts the machine
+        vm.memory.write2(TrapVector::PageFault.entry(), 0).unwrap();
+        vm.halted = false;
+        enter_user_mode(&mut vm, 0x1000);
+        let result = vm.run();
+        assert!(matches!(
+            result.reason,
+            ExitReason::Error(ref error) if matches!(error.cause(), VMError::PageFault { addr: 0x0100, kind: Access::Read })
+        ));
+    }
+}
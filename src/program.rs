@@ -0,0 +1,89 @@
+use crate::asm::Assembly;
+use crate::constants::{START_ADDRESS, VMWord, VmAddr};
+use crate::error::{Result, VMError};
+
+/// A program image ready to be loaded into VM memory.
+/// `words` are written from `load_address` upwards and execution starts at `entry_point`.
+/// Words from `data_address` on are data, the loader only makes the words before it executable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub load_address: VmAddr,
+    pub entry_point: VmAddr,
+    pub words: Vec<VMWord>,
+    pub data_address: Option<VmAddr>, // None when every word is code
+}
+
+impl Program {
+    // Loaded and started at START_ADDRESS, like the programs built in `utils`
+    pub fn new(words: Vec<VMWord>) -> Self {
+        Self {
+            load_address: START_ADDRESS,
+            entry_point: START_ADDRESS,
+            words,
+            data_address: None,
+        }
+    }
+
+    pub fn with_load_address(mut self, load_address: VmAddr) -> Self {
+        self.load_address = load_address;
+        self
+    }
+
+    pub fn with_entry_point(mut self, entry_point: VmAddr) -> Self {
+        self.entry_point = entry_point;
+        self
+    }
+
+    pub fn with_data_address(mut self, data_address: VmAddr) -> Self {
+        self.data_address = Some(data_address);
+        self
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.words.len() * 2
+    }
+
+    // First address after the image, computed wide so an image reaching the end of the address space is representable
+    pub fn end_address(&self) -> usize {
+        usize::from(self.load_address) + self.size_in_bytes()
+    }
+
+    // First address after the code, the data address or the end of the image
+    pub fn code_end(&self) -> usize {
+        self.data_address.map_or(self.end_address(), usize::from)
+    }
+
+    /// Checks that the image fits in `memory_size` bytes and that the entry point is an instruction of its code
+    pub fn validate(&self, memory_size: usize) -> Result<()> {
+        if !self.load_address.is_multiple_of(2) || self.end_address() > memory_size {
+            return Err(VMError::ProgramDoesNotFit);
+        }
+        if let Some(data) = self.data_address
+            && (!data.is_multiple_of(2)
+                || data < self.load_address
+                || usize::from(data) > self.end_address())
+        {
+            return Err(VMError::InvalidImageLayout);
+        }
+        let entry = usize::from(self.entry_point);
+        if !self.entry_point.is_multiple_of(2)
+            || entry < usize::from(self.load_address)
+            || entry + 2 > self.code_end()
+        {
+            return Err(VMError::InvalidEntryPoint);
+        }
+        Ok(())
+    }
+}
+
+impl From<Assembly> for Program {
+    fn from(assembly: Assembly) -> Self {
+        let entry_point = assembly.entry_point();
+        let program = Program::new(assembly.words).with_entry_point(entry_point);
+        // The zero words between the code and `.data` belong to the data, they are never executed
+        match assembly.data_start {
+            Some(_) => program.with_data_address(assembly.code_end as VmAddr),
+            None => program,
+        }
+    }
+}
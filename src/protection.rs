@@ -0,0 +1,425 @@
+use std::fmt;
+
+use crate::bus::BusDevice;
+use crate::constants::VmAddr;
+use crate::error::{Result, VMError};
+
+/*
+    Memory protection.
+
+    Every access is checked against the permissions of the address: data reads need READ, data writes WRITE and
+    instruction fetches in `VM::tick` EXECUTE. A denied access fails with `VMError::ProtectionFault`, which raises
+    the memory fault vector like any other bad access.
+
+    `ProtectedBus` wraps a flat memory, `VMBuilder::build` adds it unless `Config::memory_protection` is off.
+    The VM marks the reserved prefix with the vector table and the rodata segment read-only, so programs cannot
+    redirect traps; the host installs handlers with `VM::set_trap_handler`. `VM::load_program` marks the code of the program read+execute and
+    its data read+write. Addresses nobody marked (the stack, free memory) are read+write, so only the code of the
+    program can be executed and nothing can overwrite it or the vectors. `SystemBus` regions carry the same
+    permissions.
+
+    Protection is on by default, a program that overwrites its own code or the vectors is a bug more often than a
+    trick. Programs written for the flat memory that keep data between their instructions, write their own vectors
+    or run off their end into zeroed memory fault under it, they opt out with `memory_protection = false` (or
+    `--no-memory-protection` on the command line). `VM::new` with `VM::set_memory` attaches the memory as given.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute, // instruction fetch
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "execute",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const READ_ONLY: Self = Self::new(true, false, false);
+    pub const READ_WRITE: Self = Self::new(true, true, false);
+    pub const READ_EXECUTE: Self = Self::new(true, false, true);
+    pub const ALL: Self = Self::new(true, true, true);
+
+    const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+
+    // Fails with a ProtectionFault for `addr` when `access` is not allowed
+    pub fn check(&self, addr: VmAddr, access: Access) -> Result<()> {
+        if self.allows(access) {
+            Ok(())
+        } else {
+            Err(VMError::ProtectionFault { addr, kind: access })
+        }
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ProtectedRange {
+    start: usize,
+    end: usize,
+    permissions: Permissions,
+}
+
+/// Bus device forwarding to `inner` the accesses the permissions of their address allow
+#[derive(Debug)]
+pub struct ProtectedBus {
+    inner: Box<dyn BusDevice>,
+    ranges: Vec<ProtectedRange>, // later ranges take precedence
+    default: Permissions,        // of addresses outside every range
+}
+
+impl ProtectedBus {
+    pub fn new(inner: Box<dyn BusDevice>) -> Self {
+        Self {
+            inner,
+            ranges: Vec::new(),
+            default: Permissions::READ_WRITE,
+        }
+    }
+
+    pub fn permissions(&self, addr: VmAddr) -> Permissions {
+        let addr = usize::from(addr);
+        self.ranges
+            .iter()
+            .rev()
+            .find(|range| range.start <= addr && addr < range.end)
+            .map_or(self.default, |range| range.permissions)
+    }
+
+    fn check(&self, addr: VmAddr, len: VmAddr, access: Access) -> Result<()> {
+        (0..len).try_for_each(|offset| {
+            let addr = addr.checked_add(offset).ok_or(VMError::OutOfBounds(addr))?;
+            self.permissions(addr).check(addr, access)
+        })
+    }
+}
+
+impl BusDevice for ProtectedBus {
+    fn read(&self, addr: VmAddr) -> Option<u8> {
+        self.check(addr, 1, Access::Read).ok()?;
+        self.inner.read(addr)
+    }
+
+    fn write(&mut self, addr: VmAddr, value: u8) -> Result<()> {
+        self.check(addr, 1, Access::Write)?;
+        self.inner.write(addr, value)
+    }
+
+    fn read2(&self, addr: VmAddr) -> Option<u16> {
+        self.try_read2(addr).ok()
+    }
+
+    fn fetch2(&self, addr: VmAddr) -> Option<u16> {
+        self.try_fetch2(addr).ok()
+    }
+
+    fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
+        self.check(addr, 2, Access::Write)?;
+        self.inner.write2(addr, value)
+    }
+
+    fn try_read2(&self, addr: VmAddr) -> Result<u16> {
+        self.check(addr, 2, Access::Read)?;
+        self.inner.try_read2(addr)
+    }
+
+    fn try_fetch2(&self, addr: VmAddr) -> Result<u16> {
+        self.check(addr, 2, Access::Execute)?;
+        self.inner.try_fetch2(addr)
+    }
+
+    // Inspection is not an access, the permissions do not apply
+    fn peek(&self, addr: VmAddr) -> Option<u8> {
+        self.inner.peek(addr)
+    }
+
+    fn peek2(&self, addr: VmAddr) -> Option<u16> {
+        self.inner.peek2(addr)
+    }
+
+    fn protect(&mut self, start: VmAddr, len: usize, permissions: Permissions) {
+        let start = usize::from(start);
+        let end = start + len;
+        // Ranges the new one covers completely can never match again
+        self.ranges
+            .retain(|range| range.start < start || range.end > end);
+        self.ranges.push(ProtectedRange {
+            start,
+            end,
+            permissions,
+        });
+    }
+
+    fn memory_range(&self) -> usize {
+        self.inner.memory_range()
+    }
+
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.inner.pending_interrupts()
+    }
+
+    fn acknowledge_interrupt(&mut self, line: u8) {
+        self.inner.acknowledge_interrupt(line)
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.inner.dma_pending()
+    }
+
+    fn dma(&mut self, memory: &mut dyn BusDevice) {
+        self.inner.dma(memory)
+    }
+
+    fn as_bytes(&self) -> &Vec<u8> {
+        self.inner.as_bytes()
+    }
+
+    fn get_specific_memory_location(&self, idx: usize) -> u16 {
+        self.inner.get_specific_memory_location(idx)
+    }
+
+    fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8> {
+        self.inner.get_subset_of_memory(start_addr, end_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_with_symbols;
+    use crate::constants::START_ADDRESS;
+    use crate::interrupt::TrapVector;
+    use crate::memory::LinearMemory;
+    use crate::program::Program;
+    use crate::register::RegisterId;
+    use crate::vm::{ExitReason, VM};
+
+    // Protection is on by default
+    fn protected_vm(source: &str) -> VM {
+        let mut vm = VM::builder().build().unwrap();
+        vm.load_program(&Program::from(assemble_with_symbols(source).unwrap()))
+            .unwrap();
+        vm
+    }
+
+    fn protection_fault(result: &ExitReason) -> Option<(VmAddr, Access)> {
+        match result {
+            ExitReason::Error(error) => match error.cause() {
+                VMError::ProtectionFault { addr, kind } => Some((*addr, *kind)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_permissions_of_marked_ranges() {
+        let mut bus = ProtectedBus::new(Box::new(LinearMemory::new(0x100)));
+        bus.protect(0x10, 0x10, Permissions::READ_EXECUTE);
+        bus.protect(0x18, 2, Permissions::READ_ONLY);
+
+        assert_eq!(bus.permissions(0x0F), Permissions::READ_WRITE);
+        assert_eq!(bus.permissions(0x10).to_string(), "r-x");
+        assert_eq!(bus.permissions(0x18).to_string(), "r--");
+        assert!(bus.write2(0x0E, 1).is_ok());
+        assert!(matches!(
+            bus.write2(0x0F, 1),
+            Err(VMError::ProtectionFault {
+                addr: 0x10,
+                kind: Access::Write
+            })
+        ));
+        assert_eq!(bus.try_fetch2(0x10).unwrap(), 0);
+        assert!(matches!(
+            bus.try_fetch2(0x18),
+            Err(VMError::ProtectionFault {
+                addr: 0x18,
+                kind: Access::Execute
+            })
+        ));
+        assert_eq!(bus.read2(0x18), Some(0));
+
+        // Covered ranges are dropped
+        bus.protect(0x00, 0x100, Permissions::ALL);
+        assert_eq!(bus.ranges.len(), 1);
+    }
+
+    const OVERWRITES_CODE: &str = "
+            COPY R0, 5
+            STORE_OUT R0
+            LOAD_IMM16 R1, 0x100
+            WRITE R1, R0
+            HALT
+    ";
+
+    #[test]
+    fn test_code_is_not_writable() {
+        let mut vm = protected_vm(OVERWRITES_CODE);
+        let result = vm.run();
+        assert_eq!(
+            protection_fault(&result.reason),
+            Some((START_ADDRESS, Access::Write))
+        );
+        // The output word is data, the first instruction is intact
+        assert_eq!(result.output, Some(5));
+        assert_eq!(vm.memory.read2(START_ADDRESS), Some(0x1065));
+
+        // Legacy programs opt out
+        let mut vm = VM::builder().memory_protection(false).build().unwrap();
+        vm.load_program(&Program::from(
+            assemble_with_symbols(OVERWRITES_CODE).unwrap(),
+        ))
+        .unwrap();
+        assert!(vm.run().is_halted());
+        assert_eq!(vm.memory.read2(START_ADDRESS), Some(5));
+    }
+
+    #[test]
+    fn test_only_code_is_executable() {
+        let mut vm = protected_vm(
+            "
+                    LOAD_IMM16 R1, value
+                    LOAD R0, R1
+                    ADD R0, 1
+                    WRITE R1, R0
+                    CALL R1
+                    HALT
+            .data
+            value:  .word 0
+            ",
+        );
+        let result = vm.run();
+        let value = vm.config.memory_map.data_address();
+        assert_eq!(
+            protection_fault(&result.reason),
+            Some((value, Access::Execute))
+        );
+        // The data was read and written before the jump into it
+        assert_eq!(vm.memory.read2(value), Some(1));
+    }
+
+    #[test]
+    fn test_vector_table_is_read_only() {
+        let mut vm = protected_vm(
+            "
+            LOAD_IMM16 R1, 0x0002
+            LOAD_IMM16 R0, 0x0100
+            WRITE R1, R0
+            HALT
+            ",
+        );
+        let result = vm.run();
+        assert_eq!(
+            protection_fault(&result.reason),
+            Some((0x0002, Access::Write))
+        );
+        assert_eq!(vm.memory.read2(TrapVector::MemoryFault.entry()), Some(0));
+
+        // The host still installs handlers, the entry stays read-only for the program
+        vm.set_trap_handler(TrapVector::MemoryFault, START_ADDRESS + 12)
+            .unwrap();
+        assert_eq!(
+            vm.memory.read2(TrapVector::MemoryFault.entry()),
+            Some(START_ADDRESS + 12)
+        );
+        assert!(matches!(
+            vm.memory.write2(TrapVector::MemoryFault.entry(), 0),
+            Err(VMError::ProtectionFault { addr: 0x0002, .. })
+        ));
+    }
+
+    #[test]
+    fn test_execute_faults_raise_the_memory_fault_vector() {
+        let mut vm = protected_vm(
+            "
+                    LOAD_IMM16 R1, value
+                    CALL R1
+                    HALT
+            handler:
+                    POP R2
+                    HALT
+            .data
+            value:  .word 0
+            ",
+        );
+        vm.set_trap_handler(TrapVector::MemoryFault, START_ADDRESS + 8)
+            .unwrap();
+        let result = vm.run();
+        assert!(result.is_halted());
+        // LOAD_IMM16, CALL, POP and HALT executed, the failed fetch is not a step
+        assert_eq!(result.steps, 4);
+        // The handler got the address of the fetch that faulted
+        let return_addr = vm
+            .registers
+            .get_register_read_only(RegisterId::RR2.id())
+            .unwrap()
+            .value;
+        assert_eq!(return_addr, vm.config.memory_map.data_address());
+    }
+
+    #[test]
+    fn test_rodata_constants_are_read_only() {
+        let mut vm = protected_vm(
+            "
+                    LOAD_IMM16 R1, limit
+                    LOAD R0, R1
+                    STORE_OUT R0
+                    WRITE R1, R0
+                    HALT
+            .data 0x1000
+            limit:  .word 9
+            ",
+        );
+        let result = vm.run();
+        assert_eq!(result.output, Some(9));
+        assert_eq!(
+            protection_fault(&result.reason),
+            Some((0x1000, Access::Write))
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::constants::{START_ADDRESS, VMWord};
+use crate::constants::{STACK_TOP, START_ADDRESS, VMWord};
 use crate::error::{Result, VMError};
 use std::collections::BTreeMap;
 use wincode_derive::SchemaWrite;
@@ -17,25 +17,83 @@ use wincode_derive::SchemaWrite;
 
     R0 to R3 are general-purpose registers
 */
-#[derive(Clone, Copy, Debug, SchemaWrite)]
+#[derive(Clone, Copy, Debug, PartialEq, SchemaWrite)]
 #[repr(u8)]
 pub enum RegisterId {
     RR0, // return value register
     RR1,
     RR2,
     RR3,
-    RPC, // program counter, holds the address of the next ix to exec
-    RIR, // holds current instruction being executed when VM fetches an ix from memory
-    RIM, // holds immediate values
+    RPC,    // program counter, holds the address of the next ix to exec
+    RIR,    // holds current instruction being executed when VM fetches an ix from memory
+    RIM,    // holds immediate values
+    RFLAGS, // condition flags set by ADD and CMP, read by conditional jumps
+    RSP,    // stack pointer, address of the last pushed word, the stack grows downwards
+    RPTBR,  // physical address of the page table, 0 runs without address translation
+    RFAR,   // virtual address of the last page fault
 }
 
 impl RegisterId {
     pub fn id(&self) -> u8 {
         *self as u8
     }
+
+    // Name used in assembly source, general-purpose registers drop the extra `R` prefix
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegisterId::RR0 => "R0",
+            RegisterId::RR1 => "R1",
+            RegisterId::RR2 => "R2",
+            RegisterId::RR3 => "R3",
+            RegisterId::RPC => "RPC",
+            RegisterId::RIR => "RIR",
+            RegisterId::RIM => "RIM",
+            RegisterId::RFLAGS => "RFLAGS",
+            RegisterId::RSP => "RSP",
+            RegisterId::RPTBR => "RPTBR",
+            RegisterId::RFAR => "RFAR",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..MAX_REGS as u8)
+            .filter_map(|id| RegisterId::try_from(id).ok())
+            .find(|reg| reg.name().eq_ignore_ascii_case(name))
+    }
 }
 
-pub const MAX_REGS: usize = 8;
+impl TryFrom<u8> for RegisterId {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(RegisterId::RR0),
+            1 => Ok(RegisterId::RR1),
+            2 => Ok(RegisterId::RR2),
+            3 => Ok(RegisterId::RR3),
+            4 => Ok(RegisterId::RPC),
+            5 => Ok(RegisterId::RIR),
+            6 => Ok(RegisterId::RIM),
+            7 => Ok(RegisterId::RFLAGS),
+            8 => Ok(RegisterId::RSP),
+            9 => Ok(RegisterId::RPTBR),
+            10 => Ok(RegisterId::RFAR),
+
+            _ => Err(VMError::UnknownRegister(value)),
+        }
+    }
+}
+
+pub const MAX_REGS: usize = 16; // register fields in an instruction are 4 bits wide
+
+// Condition flag bits held in `RFLAGS`
+pub const FLAG_ZERO: VMWord = 1 << 0; // result was zero
+pub const FLAG_CARRY: VMWord = 1 << 1; // unsigned carry out (ADD) or borrow (CMP)
+pub const FLAG_NEGATIVE: VMWord = 1 << 2; // most significant bit of the result is set
+pub const FLAG_OVERFLOW: VMWord = 1 << 3; // signed overflow
+pub const FLAG_INTERRUPT_ENABLE: VMWord = 1 << 4; // external interrupts are taken between instructions, set by EI
+pub const FLAG_USER: VMWord = 1 << 5; // user mode, only user pages are accessible and privileged instructions fault
+pub const CONDITION_FLAGS_MASK: VMWord = FLAG_ZERO | FLAG_CARRY | FLAG_NEGATIVE | FLAG_OVERFLOW;
 
 /// Registers should hold a copy of the value from memory, not a pointer, and not remove the value from memory.
 #[derive(Clone, Copy, Debug, SchemaWrite)]
@@ -60,7 +118,7 @@ impl Register {
     }
 }
 
-#[derive(Debug, SchemaWrite)]
+#[derive(Debug, Clone, SchemaWrite)]
 pub struct RegisterBank {
     pub register_map: BTreeMap<u8, Register>, // TODO: Storing registers like that is not the most efficient way, but i am going to leave it for now, to experiment with zk first.
 }
@@ -118,6 +176,34 @@ impl Default for RegisterBank {
                     value: 0x00,
                 },
             ),
+            (
+                RegisterId::RFLAGS.id(),
+                Register {
+                    id: RegisterId::RFLAGS,
+                    value: 0x00,
+                },
+            ),
+            (
+                RegisterId::RSP.id(),
+                Register {
+                    id: RegisterId::RSP,
+                    value: STACK_TOP, // Empty stack, the first push writes just below the top
+                },
+            ),
+            (
+                RegisterId::RPTBR.id(),
+                Register {
+                    id: RegisterId::RPTBR,
+                    value: 0x00, // identity mapped, see `mmu.rs`
+                },
+            ),
+            (
+                RegisterId::RFAR.id(),
+                Register {
+                    id: RegisterId::RFAR,
+                    value: 0x00,
+                },
+            ),
         ]
         .into();
 
@@ -136,7 +222,7 @@ impl RegisterBank {
         if let Some(reg) = self.register_map.get(&name).copied() {
             Ok(reg)
         } else {
-            Err(VMError::UnknownRegister)
+            Err(VMError::UnknownRegister(name))
         }
     }
 
@@ -144,7 +230,18 @@ impl RegisterBank {
         if let Some(reg) = self.register_map.get_mut(&name) {
             Ok(reg)
         } else {
-            Err(VMError::UnknownRegister)
+            Err(VMError::UnknownRegister(name))
         }
     }
+
+    pub fn flags(&self) -> Result<VMWord> {
+        Ok(self.get_register_read_only(RegisterId::RFLAGS.id())?.value)
+    }
+
+    // Only the condition bits are replaced, any other bit kept in RFLAGS is preserved
+    pub fn set_condition_flags(&mut self, flags: VMWord) -> Result<()> {
+        let rflags = self.get_register_mut(RegisterId::RFLAGS.id())?;
+        rflags.value = (rflags.value & !CONDITION_FLAGS_MASK) | (flags & CONDITION_FLAGS_MASK);
+        Ok(())
+    }
 }
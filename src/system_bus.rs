@@ -0,0 +1,430 @@
+use crate::bus::{BusDevice, NO_BYTES};
+use crate::constants::VmAddr;
+use crate::error::{Result, VMError};
+use crate::protection::{Access, Permissions};
+
+/*
+    Address decoding.
+
+    `SystemBus` is a `BusDevice` made of other devices, each mapped to its own range of the 16-bit address space:
+
+        0x0000 - 0x0fff     ram     LinearMemory of 0x1000 bytes, read/write/execute
+        0x4000 - 0x43ff     rom     LinearMemory of 0x400 bytes, read-only
+        0xff00 - 0xff03     uart    a peripheral with four byte registers
+
+    A region is as large as the `memory_range` of its device, and a device only sees offsets from the start of its
+    region. Regions may not overlap. Accessing an unmapped address fails with `Unmapped`, an access the permissions
+    of the region deny (see `protection.rs`) with `ProtectionFault`, instructions are only fetched from executable
+    regions. Word accesses that stay inside one region are forwarded
+    as words, so a peripheral sees a 16-bit register write as one access.
+    Devices that copy data on their own get the bus for a DMA transfer right after the write that started it.
+*/
+
+/// A mapped address range, `end` is exclusive and may be 0x10000 for a region ending at the top of memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub start: VmAddr,
+    pub end: usize,
+    pub permissions: Permissions,
+}
+
+impl Region {
+    fn contains(&self, addr: VmAddr) -> bool {
+        self.start <= addr && usize::from(addr) < self.end
+    }
+}
+
+#[derive(Debug)]
+struct Mapping {
+    region: Region,
+    device: Box<dyn BusDevice>,
+}
+
+#[derive(Debug, Default)]
+pub struct SystemBus {
+    mappings: Vec<Mapping>, // sorted by start address
+}
+
+impl SystemBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `device` at `start`, failing when its range leaves the address space or overlaps a mapped region
+    pub fn map(
+        &mut self,
+        name: impl Into<String>,
+        start: VmAddr,
+        device: Box<dyn BusDevice>,
+        permissions: Permissions,
+    ) -> Result<()> {
+        let name = name.into();
+        let invalid = |reason: String| Err(VMError::InvalidBusMapping(reason));
+        let size = device.memory_range();
+        let end = usize::from(start) + size;
+        if size == 0 {
+            return invalid(format!("`{name}` is empty"));
+        }
+        if end > usize::from(VmAddr::MAX) + 1 {
+            return invalid(format!("`{name}` ends past the 16-bit address space"));
+        }
+        if let Some(other) = self
+            .regions()
+            .find(|other| usize::from(other.start) < end && usize::from(start) < other.end)
+        {
+            return invalid(format!(
+                "`{name}` at {start:#06x}..{end:#06x} overlaps `{}` at {:#06x}..{:#06x}",
+                other.name, other.start, other.end
+            ));
+        }
+
+        let at = self
+            .mappings
+            .partition_point(|mapping| mapping.region.start < start);
+        self.mappings.insert(
+            at,
+            Mapping {
+                region: Region {
+                    name,
+                    start,
+                    end,
+                    permissions,
+                },
+                device,
+            },
+        );
+        Ok(())
+    }
+
+    // Chaining form of `map`
+    pub fn with(
+        mut self,
+        name: impl Into<String>,
+        start: VmAddr,
+        device: Box<dyn BusDevice>,
+        permissions: Permissions,
+    ) -> Result<Self> {
+        self.map(name, start, device, permissions)?;
+        Ok(self)
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.mappings.iter().map(|mapping| &mapping.region)
+    }
+
+    pub fn region_at(&self, addr: VmAddr) -> Option<&Region> {
+        self.mapping(addr).map(|mapping| &mapping.region)
+    }
+
+    pub fn device(&self, name: &str) -> Option<&dyn BusDevice> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.region.name == name)
+            .map(|mapping| mapping.device.as_ref())
+    }
+
+    // Bypasses the permissions of the region, e.g. to load a ROM
+    pub fn device_mut(&mut self, name: &str) -> Option<&mut (dyn BusDevice + 'static)> {
+        self.mappings
+            .iter_mut()
+            .find(|mapping| mapping.region.name == name)
+            .map(|mapping| mapping.device.as_mut())
+    }
+
+    fn mapping(&self, addr: VmAddr) -> Option<&Mapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.region.contains(addr))
+    }
+
+    fn mapping_mut(&mut self, addr: VmAddr) -> Option<&mut Mapping> {
+        self.mappings
+            .iter_mut()
+            .find(|mapping| mapping.region.contains(addr))
+    }
+
+    // The mapping holding both bytes of the word at `addr`, None when the word is split between regions
+    fn word_mapping(&self, addr: VmAddr) -> Option<&Mapping> {
+        let mapping = self.mapping(addr)?;
+        (usize::from(addr) + 2 <= mapping.region.end).then_some(mapping)
+    }
+
+    // The mapping at `addr` and the offset into it, when its permissions allow a read or an instruction fetch
+    fn readable(&self, addr: VmAddr, access: Access) -> Result<(&Mapping, VmAddr)> {
+        let mapping = self.mapping(addr).ok_or(VMError::Unmapped(addr))?;
+        mapping.region.permissions.check(addr, access)?;
+        Ok((mapping, addr - mapping.region.start))
+    }
+
+    fn writable(&mut self, addr: VmAddr) -> Result<(&mut Mapping, VmAddr)> {
+        let mapping = self.mapping_mut(addr).ok_or(VMError::Unmapped(addr))?;
+        mapping.region.permissions.check(addr, Access::Write)?;
+        let offset = addr - mapping.region.start;
+        Ok((mapping, offset))
+    }
+
+    fn read_byte(&self, addr: VmAddr, access: Access) -> Result<u8> {
+        let (mapping, offset) = self.readable(addr, access)?;
+        mapping
+            .device
+            .read(offset)
+            .ok_or(VMError::OutOfBounds(addr))
+    }
+
+    fn read_word(&self, addr: VmAddr, access: Access) -> Result<u16> {
+        if self.word_mapping(addr).is_none() {
+            let high = addr.checked_add(1).ok_or(VMError::OutOfBounds(addr))?;
+            let low = self.read_byte(addr, access)?;
+            return Ok(u16::from(low) | (u16::from(self.read_byte(high, access)?) << 8));
+        }
+        let (mapping, offset) = self.readable(addr, access)?;
+        let word = match access {
+            Access::Execute => mapping.device.fetch2(offset),
+            _ => mapping.device.read2(offset),
+        };
+        word.ok_or(VMError::OutOfBounds(addr))
+    }
+
+    // Lets the device at `addr` finish a transfer it was asked for, it is unmapped while it accesses the bus
+    fn run_dma(&mut self, addr: VmAddr) {
+        let Some(index) = self
+            .mappings
+            .iter()
+            .position(|mapping| mapping.region.contains(addr) && mapping.device.dma_pending())
+        else {
+            return;
+        };
+        let mut mapping = self.mappings.remove(index);
+        mapping.device.dma(self);
+        self.mappings.insert(index, mapping);
+    }
+}
+
+impl BusDevice for SystemBus {
+    fn read(&self, addr: VmAddr) -> Option<u8> {
+        self.read_byte(addr, Access::Read).ok()
+    }
+
+    fn write(&mut self, addr: VmAddr, value: u8) -> Result<()> {
+        let (mapping, offset) = self.writable(addr)?;
+        mapping.device.write(offset, value)?;
+        self.run_dma(addr);
+        Ok(())
+    }
+
+    fn read2(&self, addr: VmAddr) -> Option<u16> {
+        self.read_word(addr, Access::Read).ok()
+    }
+
+    fn fetch2(&self, addr: VmAddr) -> Option<u16> {
+        self.read_word(addr, Access::Execute).ok()
+    }
+
+    fn try_read2(&self, addr: VmAddr) -> Result<u16> {
+        self.read_word(addr, Access::Read)
+    }
+
+    fn try_fetch2(&self, addr: VmAddr) -> Result<u16> {
+        self.read_word(addr, Access::Execute)
+    }
+
+    fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
+        if self.word_mapping(addr).is_none() {
+            // Both bytes are checked first, so a failing word write changes nothing
+            let high = addr.checked_add(1).ok_or(VMError::OutOfBounds(addr))?;
+            self.writable(addr)?;
+            self.writable(high)?;
+            self.write(addr, value as u8)?;
+            return self.write(high, (value >> 8) as u8);
+        }
+        let (mapping, offset) = self.writable(addr)?;
+        mapping.device.write2(offset, value)?;
+        self.run_dma(addr);
+        Ok(())
+    }
+
+    // Inspection looks past the permissions of the region, only unmapped addresses have no value
+    fn peek(&self, addr: VmAddr) -> Option<u8> {
+        let mapping = self.mapping(addr)?;
+        mapping.device.peek(addr - mapping.region.start)
+    }
+
+    fn peek2(&self, addr: VmAddr) -> Option<u16> {
+        if let Some(mapping) = self.word_mapping(addr) {
+            return mapping.device.peek2(addr - mapping.region.start);
+        }
+        let low = self.peek(addr)?;
+        let high = self.peek(addr.checked_add(1)?)?;
+        Some(u16::from(low) | (u16::from(high) << 8))
+    }
+
+    // End of the highest region, addresses below it may still be unmapped
+    fn memory_range(&self) -> usize {
+        self.mappings.last().map_or(0, |mapping| mapping.region.end)
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.mappings.iter().fold(0, |lines, mapping| {
+            lines | mapping.device.pending_interrupts()
+        })
+    }
+
+    // Every device is told, devices ignore lines they do not raise
+    fn acknowledge_interrupt(&mut self, line: u8) {
+        for mapping in &mut self.mappings {
+            mapping.device.acknowledge_interrupt(line);
+        }
+    }
+
+    fn tick(&mut self) {
+        for mapping in &mut self.mappings {
+            mapping.device.tick();
+        }
+    }
+
+    // The bytes of the device mapped at address 0, usually the main memory
+    fn as_bytes(&self) -> &Vec<u8> {
+        match self.mappings.first() {
+            Some(mapping) if mapping.region.start == 0 => mapping.device.as_bytes(),
+            _ => &NO_BYTES,
+        }
+    }
+
+    fn get_specific_memory_location(&self, idx: usize) -> u16 {
+        VmAddr::try_from(idx)
+            .ok()
+            .and_then(|addr| self.peek2(addr))
+            .unwrap_or_default()
+    }
+
+    // Unmapped addresses read as 0
+    fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8> {
+        (start_addr..end_addr)
+            .map(|idx| {
+                VmAddr::try_from(idx)
+                    .ok()
+                    .and_then(|addr| self.peek(addr))
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::constants::START_ADDRESS;
+    use crate::memory::LinearMemory;
+    use crate::program::Program;
+    use crate::vm::{ExitReason, VM};
+
+    fn bus() -> SystemBus {
+        SystemBus::new()
+            .with(
+                "ram",
+                0x0000,
+                Box::new(LinearMemory::new(0x1000)),
+                Permissions::ALL,
+            )
+            .unwrap()
+            .with(
+                "rom",
+                0x4000,
+                Box::new(LinearMemory::new(0x400)),
+                Permissions::READ_ONLY,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_routes_accesses_to_devices() {
+        let mut bus = bus();
+        bus.write2(0x0200, 0xBEEF).unwrap();
+        assert_eq!(bus.read2(0x0200), Some(0xBEEF));
+
+        // The ROM is loaded through its device, which sees offsets into the region
+        bus.device_mut("rom").unwrap().write2(0x10, 0x1234).unwrap();
+        assert_eq!(bus.read2(0x4010), Some(0x1234));
+        assert_eq!(bus.device("rom").unwrap().read2(0x10), Some(0x1234));
+
+        assert_eq!(bus.region_at(0x43FF).unwrap().name, "rom");
+        assert_eq!(bus.memory_range(), 0x4400);
+        let regions: Vec<String> = bus
+            .regions()
+            .map(|region| format!("{} {}", region.name, region.permissions))
+            .collect();
+        assert_eq!(regions, ["ram rwx", "rom r--"]);
+    }
+
+    #[test]
+    fn test_unmapped_and_read_only_accesses_fail() {
+        let mut bus = bus();
+        assert_eq!(bus.read(0x2000), None);
+        assert_eq!(bus.read2(0x0FFF), None); // the high byte is unmapped
+        assert!(matches!(
+            bus.write(0x2000, 1),
+            Err(VMError::Unmapped(0x2000))
+        ));
+        assert!(matches!(
+            bus.write2(0x4000, 1),
+            Err(VMError::ProtectionFault {
+                addr: 0x4000,
+                kind: Access::Write
+            })
+        ));
+        assert_eq!(bus.read2(0x4000), Some(0));
+        assert!(matches!(
+            bus.try_fetch2(0x4000),
+            Err(VMError::ProtectionFault {
+                addr: 0x4000,
+                kind: Access::Execute
+            })
+        ));
+        assert!(matches!(
+            bus.try_read2(0x2000),
+            Err(VMError::Unmapped(0x2000))
+        ));
+    }
+
+    #[test]
+    fn test_overlapping_regions_are_rejected() {
+        let mut bus = bus();
+        for (start, size) in [(0x0800, 0x10), (0x3F00, 0x200), (0xFFF0, 0x20), (0x5000, 0)] {
+            assert!(
+                matches!(
+                    bus.map(
+                        "io",
+                        start,
+                        Box::new(LinearMemory::new(size)),
+                        Permissions::READ_WRITE
+                    ),
+                    Err(VMError::InvalidBusMapping(_))
+                ),
+                "{start:#06x}"
+            );
+        }
+        bus.map(
+            "io",
+            0x1000,
+            Box::new(LinearMemory::new(0x10)),
+            Permissions::READ_WRITE,
+        )
+        .unwrap();
+        assert_eq!(bus.regions().count(), 3);
+    }
+
+    #[test]
+    fn test_vm_runs_on_a_system_bus() {
+        let mut vm = VM::builder().memory(Box::new(bus())).build().unwrap();
+        let program = assemble("LOAD_IMM16 R1, 0x4000\nCOPY R0, 7\nWRITE R1, R0\nHALT").unwrap();
+        vm.load_program(&Program::new(program)).unwrap();
+
+        let result = vm.run();
+        assert!(
+            matches!(result.reason, ExitReason::Error(ref error) if matches!(error.cause(), VMError::ProtectionFault { addr: 0x4000, .. }))
+        );
+        assert_eq!(vm.memory.read2(START_ADDRESS + 4), Some(0x1067));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::bus::{BusDevice, NO_BYTES};
+use crate::constants::{VMWord, VmAddr};
+use crate::error::{Result, VMError};
+
+/*
+    Programmable timer.
+
+    A peripheral for the `SystemBus` with three word registers, offsets are relative to where it is mapped:
+
+        0x0     COUNTER     instructions counted so far, writable
+        0x2     COMPARE     the counter value that fires the timer
+        0x4     CONTROL     bit 0 ENABLE    count executed instructions
+                            bit 1 IRQ       raise the interrupt line while PENDING is set
+                            bit 2 PERIODIC  restart the count from 0 when it fires
+                            bit 3 PENDING   set when the timer fires, cleared by writing 1 to it or when the VM
+                                            takes the interrupt
+
+    Time is the number of executed instructions, the VM advances the timer through `BusDevice::tick` after every
+    one, never the wall clock, so a run raises its interrupts at the same instructions every time and its trace can
+    be reproduced. With COMPARE = n and PERIODIC set the interrupt is raised every n instructions.
+*/
+
+pub const TIMER_COUNTER: VmAddr = 0x0;
+pub const TIMER_COMPARE: VmAddr = 0x2;
+pub const TIMER_CONTROL: VmAddr = 0x4;
+pub const TIMER_SIZE: usize = 6;
+
+pub const TIMER_ENABLE: VMWord = 1 << 0;
+pub const TIMER_IRQ: VMWord = 1 << 1;
+pub const TIMER_PERIODIC: VMWord = 1 << 2;
+pub const TIMER_PENDING: VMWord = 1 << 3;
+
+#[derive(Debug, Clone)]
+pub struct Timer {
+    line: u8, // interrupt line raised when the timer fires
+    counter: VMWord,
+    compare: VMWord,
+    control: VMWord,
+}
+
+impl Timer {
+    pub fn new(line: u8) -> Self {
+        Self {
+            line,
+            counter: 0,
+            compare: 0,
+            control: 0,
+        }
+    }
+
+    pub fn counter(&self) -> VMWord {
+        self.counter
+    }
+
+    fn register(&self, offset: VmAddr) -> Option<VMWord> {
+        match offset {
+            TIMER_COUNTER => Some(self.counter),
+            TIMER_COMPARE => Some(self.compare),
+            TIMER_CONTROL => Some(self.control),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, offset: VmAddr, value: VMWord) -> Result<()> {
+        match offset {
+            TIMER_COUNTER => self.counter = value,
+            TIMER_COMPARE => self.compare = value,
+            // PENDING is write-1-to-clear, the other bits are plain settings
+            TIMER_CONTROL => {
+                let pending = self.control & TIMER_PENDING & !value;
+                self.control = (value & !TIMER_PENDING) | pending;
+            }
+            _ => return Err(VMError::OutOfBounds(offset)),
+        }
+        Ok(())
+    }
+}
+
+impl BusDevice for Timer {
+    // Byte accesses address the low or high half of a register
+    fn read(&self, addr: VmAddr) -> Option<u8> {
+        let value = self.register(addr & !1)?;
+        Some((value >> (8 * (addr & 1))) as u8)
+    }
+
+    fn write(&mut self, addr: VmAddr, value: u8) -> Result<()> {
+        let offset = addr & !1;
+        let old = self.register(offset).ok_or(VMError::OutOfBounds(addr))?;
+        let shift = 8 * (addr & 1);
+        let word = (old & !(0xFF << shift)) | (VMWord::from(value) << shift);
+        self.set_register(offset, word)
+    }
+
+    fn read2(&self, addr: VmAddr) -> Option<u16> {
+        if addr % 2 == 1 {
+            return None;
+        }
+        self.register(addr)
+    }
+
+    fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
+        if addr % 2 == 1 {
+            return Err(VMError::OutOfBounds(addr));
+        }
+        self.set_register(addr, value)
+    }
+
+    fn memory_range(&self) -> usize {
+        TIMER_SIZE
+    }
+
+    fn tick(&mut self) {
+        if self.control & TIMER_ENABLE == 0 {
+            return;
+        }
+        self.counter = self.counter.wrapping_add(1);
+        if self.counter == self.compare {
+            self.control |= TIMER_PENDING;
+            if self.control & TIMER_PERIODIC != 0 {
+                self.counter = 0;
+            }
+        }
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        let raised = TIMER_IRQ | TIMER_PENDING;
+        if self.control & raised == raised {
+            1 << self.line
+        } else {
+            0
+        }
+    }
+
+    fn acknowledge_interrupt(&mut self, line: u8) {
+        if line == self.line {
+            self.control &= !TIMER_PENDING;
+        }
+    }
+
+    fn as_bytes(&self) -> &Vec<u8> {
+        &NO_BYTES
+    }
+
+    fn get_specific_memory_location(&self, idx: usize) -> u16 {
+        VmAddr::try_from(idx)
+            .ok()
+            .and_then(|addr| self.read2(addr))
+            .unwrap_or_default()
+    }
+
+    fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8> {
+        (start_addr..end_addr)
+            .map(|idx| {
+                VmAddr::try_from(idx)
+                    .ok()
+                    .and_then(|addr| self.read(addr))
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_with_symbols;
+    use crate::interrupt::TrapVector;
+    use crate::memory::LinearMemory;
+    use crate::program::Program;
+    use crate::protection::Permissions;
+    use crate::register::RegisterId;
+    use crate::system_bus::SystemBus;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_fires_when_the_counter_reaches_compare() {
+        let mut timer = Timer::new(3);
+        timer.write2(TIMER_COMPARE, 4).unwrap();
+        timer.tick();
+        assert_eq!(timer.counter(), 0); // not enabled yet
+
+        timer
+            .write2(TIMER_CONTROL, TIMER_ENABLE | TIMER_IRQ | TIMER_PERIODIC)
+            .unwrap();
+        for _ in 0..3 {
+            timer.tick();
+        }
+        assert_eq!(timer.pending_interrupts(), 0);
+        timer.tick();
+        assert_eq!(timer.pending_interrupts(), 1 << 3);
+        assert_eq!(timer.counter(), 0);
+
+        timer.acknowledge_interrupt(2);
+        assert_eq!(timer.pending_interrupts(), 1 << 3);
+        timer.acknowledge_interrupt(3);
+        assert_eq!(timer.pending_interrupts(), 0);
+
+        // Without IRQ the timer only sets PENDING, which the program clears by writing it back
+        timer.write2(TIMER_CONTROL, TIMER_ENABLE).unwrap();
+        timer.write(TIMER_COUNTER, 3).unwrap();
+        timer.tick();
+        assert_eq!(timer.pending_interrupts(), 0);
+        assert_eq!(
+            timer.read2(TIMER_CONTROL),
+            Some(TIMER_ENABLE | TIMER_PENDING)
+        );
+        timer
+            .write2(TIMER_CONTROL, TIMER_ENABLE | TIMER_PENDING)
+            .unwrap();
+        assert_eq!(timer.read2(TIMER_CONTROL), Some(TIMER_ENABLE));
+        assert_eq!(timer.read(TIMER_COUNTER + 1), Some(0));
+        assert_eq!(timer.read(TIMER_SIZE as VmAddr), None);
+    }
+
+    fn timed_vm() -> VM {
+        let assembly = assemble_with_symbols(
+            "
+                    LOAD_IMM16 R1, 0xff12
+                    COPY R0, 10
+                    WRITE R1, R0
+                    LOAD_IMM16 R1, 0xff14
+                    COPY R0, 7
+                    WRITE R1, R0
+                    EI
+            loop:   JMP loop
+            tick:   ADD R2, 1
+                    IRET
+            ",
+        )
+        .unwrap();
+        let bus = SystemBus::new()
+            .with(
+                "ram",
+                0x0000,
+                Box::new(LinearMemory::new(0x2000)),
+                Permissions::ALL,
+            )
+            .unwrap()
+            .with(
+                "timer",
+                0xFF10,
+                Box::new(Timer::new(0)),
+                Permissions::READ_WRITE,
+            )
+            .unwrap();
+        let mut vm = VM::builder().memory(Box::new(bus)).build().unwrap();
+        vm.load_program(&Program::new(assembly.words)).unwrap();
+        vm.set_trap_handler(TrapVector::Interrupt(0), assembly.symbols["tick"])
+            .unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_periodic_interrupts_are_deterministic() {
+        let mut first = timed_vm();
+        let mut second = timed_vm();
+        first.run_steps(200);
+        second.run_steps(200);
+
+        let ticks = |vm: &VM| {
+            vm.registers
+                .get_register_read_only(RegisterId::RR2.id())
+                .unwrap()
+                .value
+        };
+        // The setup takes 7 instructions, then the timer fires every 10, handler included
+        assert_eq!(ticks(&first), 19);
+        assert_eq!(ticks(&first), ticks(&second));
+        assert_eq!(
+            first
+                .registers
+                .get_register_read_only(RegisterId::RPC.id())
+                .unwrap()
+                .value,
+            second
+                .registers
+                .get_register_read_only(RegisterId::RPC.id())
+                .unwrap()
+                .value
+        );
+        assert_eq!(first.memory.read2(0xFF10), second.memory.read2(0xFF10));
+    }
+}
@@ -0,0 +1,318 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::bus::{BusDevice, NO_BYTES};
+use crate::constants::{VMWord, VmAddr};
+use crate::error::{Result, VMError};
+
+/*
+    UART console.
+
+    A peripheral for the `SystemBus` with two word registers, offsets are relative to where it is mapped:
+
+        0x0     DATA    write: send the low byte to the output
+                        read: take the next input byte, 0 when there is none
+        0x2     STATUS  bit 0 TX_READY, always set
+                        bit 1 RX_READY, an input byte is waiting in DATA
+
+    A byte access to the low byte of a register behaves like the word access, the high bytes read as 0 and ignore
+    writes. Reading DATA consumes input, so the device keeps the byte it peeked for STATUS until DATA is read.
+    `BusDevice::peek` shows that byte without consuming it, so inspecting DATA leaves the input to the program.
+*/
+
+pub const UART_DATA: VmAddr = 0x0;
+pub const UART_STATUS: VmAddr = 0x2;
+pub const UART_SIZE: usize = 4;
+
+pub const UART_TX_READY: VMWord = 1 << 0;
+pub const UART_RX_READY: VMWord = 1 << 1;
+
+pub struct Uart {
+    output: Box<dyn Write>,
+    input: RefCell<Box<dyn Read>>,
+    peeked: RefCell<Option<u8>>, // input byte read ahead for STATUS
+}
+
+impl std::fmt::Debug for Uart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Uart")
+            .field("peeked", &self.peeked.borrow())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Uart {
+    pub fn new(output: impl Write + 'static, input: impl Read + 'static) -> Self {
+        Self {
+            output: Box::new(output),
+            input: RefCell::new(Box::new(input)),
+            peeked: RefCell::new(None),
+        }
+    }
+
+    // Console on the standard streams of the host
+    pub fn stdio() -> Self {
+        Self::new(io::stdout(), io::stdin())
+    }
+
+    // The next input byte, without consuming it. A failing input counts as exhausted
+    fn next_input(&self) -> Option<u8> {
+        let mut peeked = self.peeked.borrow_mut();
+        if peeked.is_none() {
+            let mut byte = [0u8];
+            if let Ok(1) = self.input.borrow_mut().read(&mut byte) {
+                *peeked = Some(byte[0]);
+            }
+        }
+        *peeked
+    }
+
+    fn read_register(&self, offset: VmAddr) -> Option<VMWord> {
+        match offset {
+            UART_DATA => {
+                let byte = self.next_input();
+                self.peeked.borrow_mut().take();
+                Some(byte.map_or(0, VMWord::from))
+            }
+            UART_STATUS => match self.next_input() {
+                Some(_) => Some(UART_TX_READY | UART_RX_READY),
+                None => Some(UART_TX_READY),
+            },
+            _ => None,
+        }
+    }
+
+    // `read_register` without consuming the input byte in DATA
+    fn peek_register(&self, offset: VmAddr) -> Option<VMWord> {
+        match offset {
+            UART_DATA => Some(self.next_input().map_or(0, VMWord::from)),
+            _ => self.read_register(offset),
+        }
+    }
+
+    fn write_register(&mut self, offset: VmAddr, value: VMWord) -> Result<()> {
+        match offset {
+            UART_DATA => {
+                self.output.write_all(&[value as u8])?;
+                self.output.flush()?;
+                Ok(())
+            }
+            UART_STATUS => Ok(()),
+            _ => Err(VMError::OutOfBounds(offset)),
+        }
+    }
+}
+
+impl BusDevice for Uart {
+    fn read(&self, addr: VmAddr) -> Option<u8> {
+        if addr % 2 == 1 {
+            return (usize::from(addr) < UART_SIZE).then_some(0);
+        }
+        self.read_register(addr).map(|value| value as u8)
+    }
+
+    fn write(&mut self, addr: VmAddr, value: u8) -> Result<()> {
+        if addr % 2 == 1 {
+            return if usize::from(addr) < UART_SIZE {
+                Ok(())
+            } else {
+                Err(VMError::OutOfBounds(addr))
+            };
+        }
+        self.write_register(addr, value.into())
+    }
+
+    fn read2(&self, addr: VmAddr) -> Option<u16> {
+        if addr % 2 == 1 {
+            return None;
+        }
+        self.read_register(addr)
+    }
+
+    fn peek(&self, addr: VmAddr) -> Option<u8> {
+        if addr % 2 == 1 {
+            return (usize::from(addr) < UART_SIZE).then_some(0);
+        }
+        self.peek_register(addr).map(|value| value as u8)
+    }
+
+    fn peek2(&self, addr: VmAddr) -> Option<u16> {
+        if addr % 2 == 1 {
+            return None;
+        }
+        self.peek_register(addr)
+    }
+
+    fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
+        if addr % 2 == 1 {
+            return Err(VMError::OutOfBounds(addr));
+        }
+        self.write_register(addr, value)
+    }
+
+    fn memory_range(&self) -> usize {
+        UART_SIZE
+    }
+
+    fn as_bytes(&self) -> &Vec<u8> {
+        &NO_BYTES
+    }
+
+    // Registers are not memory, inspecting them must not consume input
+    fn get_specific_memory_location(&self, _idx: usize) -> u16 {
+        0
+    }
+
+    fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8> {
+        vec![0; end_addr.saturating_sub(start_addr)]
+    }
+}
+
+/// In-memory output shared between a `Uart` and whoever inspects what the program wrote, e.g. a test
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::memory::LinearMemory;
+    use crate::program::Program;
+    use crate::protection::Permissions;
+    use crate::system_bus::SystemBus;
+    use crate::vm::VM;
+    use crate::watch::{WatchKind, Watchpoint, Watchpoints};
+
+    const UART_BASE: VmAddr = 0xFF00;
+
+    // Copies input to output until no input byte is waiting
+    const ECHO: &str = "
+                LOAD_IMM16 R1, 0xff00
+                LOAD_IMM16 R2, 0xff02
+                COPY R3, 2
+        wait:   LOAD R0, R2
+                AND R0, R3
+                JZ done
+                LOAD R0, R1
+                WRITE R1, R0
+                JMP wait
+        done:   HALT
+    ";
+
+    fn console_vm(source: &str, input: &'static [u8]) -> (VM, SharedBuffer) {
+        let output = SharedBuffer::new();
+        let bus = SystemBus::new()
+            .with(
+                "ram",
+                0x0000,
+                Box::new(LinearMemory::new(0x1000)),
+                Permissions::ALL,
+            )
+            .unwrap()
+            .with(
+                "uart",
+                UART_BASE,
+                Box::new(Uart::new(output.clone(), input)),
+                Permissions::READ_WRITE,
+            )
+            .unwrap();
+        let mut vm = VM::builder().memory(Box::new(bus)).build().unwrap();
+        vm.load_program(&Program::new(assemble(source).unwrap()))
+            .unwrap();
+        (vm, output)
+    }
+
+    #[test]
+    fn test_program_prints_a_string() {
+        let (mut vm, output) = console_vm(
+            "
+                    LOAD_IMM16 R1, 0xff00
+                    LOAD_IMM16 R2, text
+                    LOAD_IMM16 R3, 0xff
+            next:   LOAD R0, R2
+                    AND R0, R3
+                    JZ done
+                    WRITE R1, R0
+                    ADD R2, 2
+                    JMP next
+            done:   HALT
+            text:   .word 0x48, 0x69, 0x0a, 0
+            ",
+            b"",
+        );
+        assert!(vm.run().is_halted());
+        assert_eq!(output.contents(), b"Hi\n");
+    }
+
+    #[test]
+    fn test_program_echoes_its_input() {
+        let (mut vm, output) = console_vm(ECHO, b"echo");
+        assert!(vm.run().is_halted());
+        assert_eq!(output.contents(), b"echo");
+    }
+
+    #[test]
+    fn test_echo_under_watchpoints() {
+        let (mut vm, output) = console_vm(ECHO, b"echo");
+        let watchpoints = Watchpoints::new();
+        watchpoints.attach(&mut vm);
+        watchpoints.add(Watchpoint {
+            addr: UART_BASE + UART_DATA,
+            len: 2,
+            kind: WatchKind::Write,
+        });
+
+        // Looking at DATA, as the debugger does, leaves the byte to the program
+        assert_eq!(
+            vm.memory.peek2(UART_BASE + UART_DATA),
+            Some(u16::from(b'e'))
+        );
+        assert!(vm.run().is_halted());
+        assert_eq!(output.contents(), b"echo");
+        let written: Vec<u8> = watchpoints
+            .take_hits()
+            .iter()
+            .map(|hit| hit.new as u8)
+            .collect();
+        assert_eq!(written, b"echo");
+    }
+
+    #[test]
+    fn test_status_reports_input_without_consuming_it() {
+        let mut uart = Uart::new(SharedBuffer::new(), &b"ok"[..]);
+        assert_eq!(uart.read2(UART_STATUS), Some(UART_TX_READY | UART_RX_READY));
+        assert_eq!(uart.read2(UART_STATUS), Some(UART_TX_READY | UART_RX_READY));
+        assert_eq!(uart.peek2(UART_DATA), Some(u16::from(b'o')));
+        assert_eq!(uart.peek(UART_DATA), Some(b'o'));
+        assert_eq!(uart.read2(UART_DATA), Some(u16::from(b'o')));
+        assert_eq!(uart.read(UART_DATA), Some(b'k'));
+        assert_eq!(uart.read2(UART_STATUS), Some(UART_TX_READY));
+        assert_eq!(uart.read2(UART_DATA), Some(0));
+
+        assert_eq!(uart.read(UART_DATA + 1), Some(0));
+        assert!(uart.write2(UART_STATUS + 2, 1).is_err());
+        assert_eq!(uart.read(UART_STATUS + 2), None);
+    }
+}
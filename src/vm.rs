@@ -1,36 +1,48 @@
 #![allow(dead_code)]
 
 use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::{self, OpenOptions};
-use std::io::Write;
+use std::io::{self, Write};
 
 use ark_bn254::Fr;
 use ark_ff::AdditiveGroup;
+use derive_more::Display;
 use wincode::serialize;
 
-use crate::constants::{START_ADDRESS, VMWord};
+use crate::config::{Config, GasCosts, OverflowMode, StackRegion, TraceMode};
+use crate::constants::{VMWord, VmAddr};
+use crate::disasm::decode_instruction;
 use crate::error::Result;
+use crate::host::{HostFunctions, SYS_STEPS};
+use crate::interrupt::{IRQ_LINES, TrapVector};
+use crate::utils::instruction_builder;
 use crate::zk::{Sha256Hash, ZkContext};
 use crate::{
     bus::BusDevice,
     error::VMError,
     memory::LinearMemory,
-    register::{Register, RegisterBank, RegisterId},
+    mmu::Mmu,
+    program::Program,
+    protection::{Permissions, ProtectedBus},
+    register::{
+        FLAG_CARRY, FLAG_INTERRUPT_ENABLE, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_USER, FLAG_ZERO,
+        MAX_REGS, Register, RegisterBank, RegisterId,
+    },
 };
 
-// The VM config
-pub struct Config {}
-
 #[derive(Debug, Clone)]
 pub struct TraceEntry {
-    pc: VMWord,
+    pub pc: VMWord,
 
-    opcode: Opcode,
-    dst: u8,
-    src: u8,
-    imm: VMWord,
+    pub opcode: Opcode,
+    pub dst: u8,
+    pub src: u8,
+    pub imm: VMWord,
+    pub ext: Option<VMWord>, // literal word following a wide instruction
+    pub host_function: Option<String>, // name of the host function a SYSCALL called
 
-    registers: BTreeMap<u8, Register>, // TODO: Storing registers like that is not the most efficient way, but i am going to leave it for now, to experiment with zk first.
+    pub registers: BTreeMap<u8, Register>, // TODO: Storing registers like that is not the most efficient way, but i am going to leave it for now, to experiment with zk first.
 }
 
 impl TraceEntry {
@@ -40,6 +52,7 @@ impl TraceEntry {
         dst: u8,
         src: u8,
         imm: VMWord,
+        ext: Option<VMWord>,
         registers: BTreeMap<u8, Register>,
     ) -> Self {
         Self {
@@ -48,27 +61,112 @@ impl TraceEntry {
             dst,
             src,
             imm,
+            ext,
+            host_function: None,
             registers,
         }
     }
+
+    // `pc` is captured after the fetch, the instruction starts one word (two for wide instructions) earlier
+    pub fn addr(&self) -> VmAddr {
+        let size = if self.ext.is_some() { 4 } else { 2 };
+        self.pc.wrapping_sub(size)
+    }
+
+    // The executed instruction in assembler syntax, followed by the host function for a SYSCALL
+    pub fn text(&self) -> String {
+        let word = instruction_builder(self.opcode.id(), self.dst, self.src, self.imm as u8);
+        let text = decode_instruction(self.addr(), word, self.ext)
+            .unwrap_or_else(|| format!(".word {word:#06x}"));
+        match &self.host_function {
+            Some(name) => format!("{text} ({name})"),
+            None => text,
+        }
+    }
+}
+
+// Disassembled instruction followed by the register state captured with it
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let registers: Vec<String> = self
+            .registers
+            .values()
+            .map(|reg| format!("{}={:#06x}", reg.id.name(), reg.value))
+            .collect();
+        write!(
+            f,
+            "{:#06x}: {:<28}| {}",
+            self.addr(),
+            self.text(),
+            registers.join(" ")
+        )
+    }
+}
+
+/// Why `VM::run`, `run_until` or `run_steps` returned
+#[derive(Debug)]
+pub enum ExitReason {
+    Halted,
+    Error(VMError), // the machine is halted
+    StepLimit,      // `run_steps` used up its steps or `Config::max_steps` was reached
+    OutOfGas,
+    Breakpoint, // the `run_until` predicate held
+}
+
+/// State of the machine when a run returned, only `StepLimit`, `OutOfGas` and `Breakpoint` runs can be resumed
+#[derive(Debug)]
+pub struct ExecutionResult {
+    pub reason: ExitReason,
+    pub steps: u64, // instructions executed by this run
+    pub registers: RegisterBank,
+    pub output: Option<VMWord>, // the word at `Config::output_address`, where STORE_OUT writes
+    pub trace: Vec<TraceEntry>, // entries recorded by this run, empty unless tracing is enabled
+}
+
+impl ExecutionResult {
+    pub fn is_halted(&self) -> bool {
+        matches!(self.reason, ExitReason::Halted)
+    }
 }
 
 pub trait VMOperations {
-    fn halt(&mut self, _: Register, _: Register);
-    fn write(&mut self, source_reg: Register, destination_reg: Register);
-    fn copy(&mut self, source_reg: Register, destination_reg: Register);
-    fn add(&mut self, source_reg: Register, destination_reg: Register);
-    fn load(&mut self, source_reg: Register, destination_reg: Register);
-    fn load_imm(&mut self, _: Register, _: Register);
-    fn store_out(&mut self, source_reg: Register, _: Register);
+    fn halt(&mut self, _: Register, _: Register) -> Result<()>;
+    fn write(&mut self, source_reg: Register, destination_reg: Register) -> Result<()>;
+    fn copy(&mut self, source_reg: Register, destination_reg: Register) -> Result<()>;
+    fn add(&mut self, source_reg: Register, destination_reg: Register) -> Result<()>;
+    fn load(&mut self, source_reg: Register, destination_reg: Register) -> Result<()>;
+    fn load_imm(&mut self, _: Register, _: Register) -> Result<()>;
+    fn store_out(&mut self, source_reg: Register, _: Register) -> Result<()>;
+    fn cmp(&mut self, source_reg: Register, destination_reg: Register) -> Result<()>;
+    fn jump(&mut self, condition: Condition, target: JumpTarget) -> Result<()>;
+    fn alu(
+        &mut self,
+        function: AluFunction,
+        source_reg: Register,
+        destination_reg: Register,
+    ) -> Result<()>;
+    fn push(&mut self, source_reg: Register) -> Result<()>;
+    fn pop(&mut self, destination_reg: Register) -> Result<()>;
+    fn call(&mut self, target: VmAddr) -> Result<()>;
+    fn ret(&mut self) -> Result<()>;
+    fn load_imm16(&mut self, destination_reg: Register, value: VMWord) -> Result<()>;
+    fn addi16(&mut self, destination_reg: Register, value: VMWord) -> Result<()>;
+    fn iret(&mut self) -> Result<()>;
+    fn set_interrupts_enabled(&mut self, enabled: bool) -> Result<()>;
+    fn syscall(&mut self, number: VMWord) -> Result<()>;
 }
 
 // It will simulate the computer for the 16bit VM
 #[derive(Debug)]
 pub struct VM {
+    pub config: Config,
     pub registers: RegisterBank,
     pub memory: Box<dyn BusDevice>, // main memory
+    pub host: HostFunctions,        // called by SYSCALL
     pub halted: bool, // Signal when the VM should stop processing instructions, after program finishes or encounter a fatal error
+    pub steps: u64,   // instructions completed so far, checked against `Config::max_steps`
+    gas: Option<u64>, // remaining gas, None when the VM is not metered
+    pending_interrupts: u8, // lines raised through `raise_interrupt`, one bit per line
 
     pub trace_enabled: bool,
     pub trace_buffer: Vec<TraceEntry>, // store trace entries
@@ -78,9 +176,14 @@ pub struct VM {
 impl Default for VM {
     fn default() -> Self {
         Self {
+            config: Config::default(),
             registers: RegisterBank::new(),
             memory: Box::new(LinearMemory::new(0)),
+            host: HostFunctions::with_builtins(io::stdout(), io::stdin()),
             halted: false,
+            steps: 0,
+            gas: None,
+            pending_interrupts: 0,
             trace_enabled: false,
             trace_buffer: Vec::new(),
             zk_output_enabled: false,
@@ -93,14 +196,163 @@ impl VM {
         Self::default()
     }
 
+    pub fn builder() -> VMBuilder {
+        VMBuilder::new()
+    }
+
+    // The memory is left empty, `VMBuilder::build` also attaches a memory of `config.memory_size` bytes
+    pub fn with_config(config: Config) -> Self {
+        let mut vm = Self {
+            trace_enabled: config.trace != TraceMode::Off,
+            zk_output_enabled: config.trace == TraceMode::Zk,
+            gas: config.gas_limit,
+            config,
+            ..Self::default()
+        };
+        vm.registers
+            .get_register_mut(RegisterId::RSP.id())
+            .expect("RSP is part of the default register bank")
+            .value = vm.config.stack.top;
+        vm
+    }
+
     pub fn set_memory(&mut self, memory: Box<dyn BusDevice>) {
-        self.memory = memory;
-        println!("Set a new memory");
+        self.memory = Box::new(Mmu::new(memory));
+    }
+
+    /// Writes the program image into memory and points RPC at its entry point, the program is not executed.
+    /// `Config::entry_point` takes precedence over the entry point of the program.
+    pub fn load_program(&mut self, program: &Program) -> Result<()> {
+        let entry_point = self.config.entry_point.unwrap_or(program.entry_point);
+        if entry_point != program.entry_point {
+            program
+                .clone()
+                .with_entry_point(entry_point)
+                .validate(self.memory.memory_range())?;
+        } else {
+            program.validate(self.memory.memory_range())?;
+        }
+        // The code stays inside the code segment, past its end it would run over rodata and data
+        let code = &self.config.memory_map.code;
+        if !code.contains(program.load_address) || program.code_end() > code.end {
+            return Err(VMError::CodeOutsideSegment {
+                start: program.load_address,
+                end: program.code_end(),
+            });
+        }
+        // The image is made writable for the loader, then its code is made read-only and executable
+        let size = program.size_in_bytes();
+        self.memory
+            .protect(program.load_address, size, Permissions::READ_WRITE);
+        let addresses = (program.load_address..=VmAddr::MAX).step_by(2);
+        for (addr, word) in addresses.zip(&program.words) {
+            self.memory.write2(addr, *word)?;
+        }
+        let code_size = program.code_end() - usize::from(program.load_address);
+        self.memory
+            .protect(program.load_address, code_size, Permissions::READ_EXECUTE);
+        // Constants the image placed in rodata stay read-only
+        if self.config.memory_protection {
+            let rodata = self.config.memory_map.rodata;
+            self.memory
+                .protect(rodata.start, rodata.len(), Permissions::READ_ONLY);
+        }
+        self.registers.get_register_mut(RegisterId::RPC.id())?.value = entry_point;
+        Ok(())
+    }
+
+    // Raw words are loaded at `Config::load_address` and started there
+    pub fn load_words(&mut self, words: &[VMWord]) -> Result<()> {
+        let program = Program::new(words.to_vec())
+            .with_load_address(self.config.load_address)
+            .with_entry_point(self.config.load_address);
+        self.load_program(&program)
+    }
+
+    /// Installs `handler` for `vector`, 0 removes it.
+    /// The vector table is read-only under memory protection, only the host can change it.
+    pub fn set_trap_handler(&mut self, vector: TrapVector, handler: VmAddr) -> Result<()> {
+        let entry = vector.entry();
+        self.memory.protect(entry, 2, Permissions::READ_WRITE);
+        let written = self.memory.write2(entry, handler);
+        self.memory.protect(entry, 2, Permissions::READ_ONLY);
+        written
+    }
+
+    /// Ticks until the program halts, fails or runs out of steps or gas
+    pub fn run(&mut self) -> ExecutionResult {
+        self.run_with(None, |_| false)
+    }
+
+    /// Like `run`, but also stops once `predicate` holds after an instruction.
+    /// It is not checked before the first one, so a run stopped at a breakpoint resumes past it.
+    pub fn run_until(&mut self, predicate: impl FnMut(&VM) -> bool) -> ExecutionResult {
+        self.run_with(None, predicate)
+    }
+
+    /// Like `run`, but executes at most `steps` instructions
+    pub fn run_steps(&mut self, steps: u64) -> ExecutionResult {
+        self.run_with(Some(steps), |_| false)
+    }
+
+    fn run_with(
+        &mut self,
+        max_steps: Option<u64>,
+        mut predicate: impl FnMut(&VM) -> bool,
+    ) -> ExecutionResult {
+        let first_step = self.steps;
+        let first_entry = self.trace_buffer.len();
+        let reason = loop {
+            if self.halted {
+                break ExitReason::Halted;
+            }
+            if max_steps.is_some_and(|max| self.steps - first_step >= max) {
+                break ExitReason::StepLimit;
+            }
+            match self.tick() {
+                Ok(()) if !self.halted && predicate(self) => break ExitReason::Breakpoint,
+                Ok(()) => {}
+                Err(VMError::StepLimitReached) => break ExitReason::StepLimit,
+                Err(VMError::OutOfGas) => break ExitReason::OutOfGas,
+                Err(error) => break ExitReason::Error(error),
+            }
+        };
+        ExecutionResult {
+            reason,
+            steps: self.steps - first_step,
+            registers: self.registers.clone(),
+            output: self.memory.read2(self.config.output_address()),
+            trace: self.trace_buffer[first_entry..].to_vec(),
+        }
+    }
+
+    // Replaces the remaining gas, a VM that ran out of gas resumes with the next `tick`
+    pub fn set_gas_budget(&mut self, budget: u64) {
+        self.gas = Some(budget);
+    }
+
+    pub fn remaining_gas(&self) -> Option<u64> {
+        self.gas
+    }
+
+    /// Raises external interrupt `line`, it is taken before the next instruction once interrupts are enabled.
+    /// Devices on the bus raise theirs through `BusDevice::pending_interrupts` instead.
+    pub fn raise_interrupt(&mut self, line: u8) -> Result<()> {
+        if line >= IRQ_LINES {
+            return Err(VMError::InvalidInterruptLine(line));
+        }
+        self.pending_interrupts |= 1 << line;
+        Ok(())
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.registers
+            .flags()
+            .is_ok_and(|flags| flags & FLAG_INTERRUPT_ENABLE != 0)
     }
 
     pub fn enable_trace(&mut self) {
         self.trace_enabled = true;
-        println!("Trace enabled");
     }
 
     pub fn enable_zk_output(&mut self) {
@@ -111,7 +363,12 @@ impl VM {
         Tick and execute_instruction will load an instruction into the IR and execute it if the machine is not halted.
         It will decode the instruction into the opcode, the register indices and the immediate data and pass this along the instruction.
     */
-    pub fn execute_instruction(&mut self, instruction: VMWord) -> Result<()> {
+    // `extension` is the literal word that follows a wide instruction, see `Opcode::has_extension_word`
+    pub fn execute_instruction(
+        &mut self,
+        instruction: VMWord,
+        extension: Option<VMWord>,
+    ) -> Result<()> {
         // Decode the instruction
         let opcode = Opcode::try_from((instruction >> 12) as u8)?;
         let dest_reg_i = ((instruction & 0x0F00) >> 8) as u8;
@@ -119,9 +376,112 @@ impl VM {
         let immediate_value = instruction & 0x000F;
 
         if self.trace_enabled {
-            self.trace(opcode, dest_reg_i, source_reg_i, immediate_value);
+            self.trace(opcode, dest_reg_i, source_reg_i, immediate_value, extension)?;
+        }
+        if self.user_mode() {
+            check_privilege(opcode, dest_reg_i, immediate_value)?;
+            // Traps push onto the stack with supervisor rights, user code may only move RSP inside the stack.
+            // An instruction pointing it elsewhere faults and leaves the registers as it found them
+            if dest_reg_i == RegisterId::RSP.id() {
+                let registers = self.registers.clone();
+                self.execute_decoded(opcode, dest_reg_i, source_reg_i, immediate_value, extension)?;
+                if let Err(error) = self.check_stack_pointer() {
+                    self.registers = registers;
+                    return Err(error);
+                }
+                return Ok(());
+            }
+        }
+        self.execute_decoded(opcode, dest_reg_i, source_reg_i, immediate_value, extension)
+    }
+
+    fn execute_decoded(
+        &mut self,
+        opcode: Opcode,
+        dest_reg_i: u8,
+        source_reg_i: u8,
+        immediate_value: VMWord,
+        extension: Option<VMWord>,
+    ) -> Result<()> {
+        match opcode {
+            // Jumps reuse the register fields: dst holds the condition and src the register with the absolute target,
+            // when src is RIM the imm nibble is a signed PC-relative offset counted in instructions
+            Opcode::JMP => {
+                let condition = Condition::try_from(dest_reg_i)?;
+                let target = if source_reg_i == RegisterId::RIM.id() {
+                    JumpTarget::Relative(sign_extend_nibble(immediate_value))
+                } else {
+                    JumpTarget::Absolute(self.registers.get_register_read_only(source_reg_i)?.value)
+                };
+                self.jump(condition, target)
+            }
+            // The imm nibble selects the ALU function, so it cannot double as an immediate operand
+            Opcode::ALU => {
+                let function = AluFunction::try_from(immediate_value as u8)?;
+                let dest_reg = self.registers.get_register_read_only(dest_reg_i)?;
+                let src_reg = self.registers.get_register_read_only(source_reg_i)?;
+                self.alu(function, src_reg, dest_reg)
+            }
+            // The imm nibble selects the stack operation, PUSH and CALL read src while POP writes dst
+            Opcode::STACK => match StackOperation::try_from(immediate_value as u8)? {
+                StackOperation::PUSH => {
+                    let src_reg = self.registers.get_register_read_only(source_reg_i)?;
+                    self.push(src_reg)
+                }
+                StackOperation::POP => {
+                    let dest_reg = self.registers.get_register_read_only(dest_reg_i)?;
+                    self.pop(dest_reg)
+                }
+                StackOperation::CALL => {
+                    let target = self.registers.get_register_read_only(source_reg_i)?.value;
+                    self.call(target)
+                }
+                StackOperation::RET => self.ret(),
+            },
+            Opcode::INT => match IntOperation::try_from(immediate_value as u8)? {
+                IntOperation::IRET => self.iret(),
+                IntOperation::EI => self.set_interrupts_enabled(true),
+                IntOperation::DI => self.set_interrupts_enabled(false),
+            },
+            // The number comes from src, RIM with a non-zero imm is the number itself
+            Opcode::SYSCALL => {
+                let number = self.resolve_register_or_immediate(source_reg_i, immediate_value)?;
+                self.syscall(number.value)
+            }
+            // The imm nibble selects the operation and the operand is the full 16-bit word after the instruction
+            Opcode::WIDE => {
+                let value = extension.ok_or(VMError::MemoryReadError)?;
+                match WideOperation::try_from(immediate_value as u8)? {
+                    WideOperation::LOAD_IMM16 => {
+                        let dest_reg = self.registers.get_register_read_only(dest_reg_i)?;
+                        self.load_imm16(dest_reg, value)
+                    }
+                    WideOperation::ADDI16 => {
+                        let dest_reg = self.registers.get_register_read_only(dest_reg_i)?;
+                        self.addi16(dest_reg, value)
+                    }
+                    // Like JMP, dst holds the condition
+                    WideOperation::JMP16 => {
+                        let condition = Condition::try_from(dest_reg_i)?;
+                        self.jump(condition, JumpTarget::Absolute(value))
+                    }
+                    WideOperation::CALL16 => self.call(value),
+                }
+            }
+            _ => {
+                self.execute_register_instruction(opcode, dest_reg_i, source_reg_i, immediate_value)
+            }
         }
+    }
 
+    // Instructions whose operands are plain registers, with RIM loaded from the imm nibble when used
+    fn execute_register_instruction(
+        &mut self,
+        opcode: Opcode,
+        dest_reg_i: u8,
+        source_reg_i: u8,
+        immediate_value: VMWord,
+    ) -> Result<()> {
         let dest_reg = self.resolve_register_or_immediate(dest_reg_i, immediate_value)?;
         let src_reg = self.resolve_register_or_immediate(source_reg_i, immediate_value)?;
 
@@ -134,9 +494,16 @@ impl VM {
             Opcode::LOAD => self.load(src_reg, dest_reg),
             Opcode::LOAD_IMM => self.load_imm(src_reg, dest_reg),
             Opcode::STORE_OUT => self.store_out(src_reg, dest_reg),
+            Opcode::CMP => self.cmp(src_reg, dest_reg),
+            Opcode::JMP
+            | Opcode::ALU
+            | Opcode::STACK
+            | Opcode::WIDE
+            | Opcode::INT
+            | Opcode::SYSCALL => {
+                unreachable!("{opcode:?} decodes its own operands in execute_instruction")
+            }
         }
-
-        Ok(())
     }
 
     // If not halted, execute the instruction
@@ -149,6 +516,16 @@ impl VM {
         if self.halted {
             return Err(VMError::Halted);
         }
+        // The machine is not halted, raising `max_steps` lets it continue
+        if self.config.max_steps.is_some_and(|max| self.steps >= max) {
+            return Err(VMError::StepLimitReached);
+        }
+
+        self.sync_address_space()?;
+        if let Err(error) = self.service_interrupt() {
+            self.halted = true;
+            return Err(error);
+        }
 
         // This holds the start address to read from memory
         let pc_reg_addr = self
@@ -156,24 +533,151 @@ impl VM {
             .get_register_read_only(RegisterId::RPC.id())?
             .value;
 
-        // TODO: Improve error handling
-        let raw_instruction: u16 = self.memory.read2(pc_reg_addr).unwrap();
+        let raw_instruction: u16 = match self.memory.try_fetch2(pc_reg_addr) {
+            Ok(word) => word,
+            Err(cause) => {
+                return self.fetch_fault(VMError::fetch_failed(cause, pc_reg_addr), pc_reg_addr);
+            }
+        };
+
+        // Wide instructions are followed by a literal word, which is fetched together with the instruction
+        let opcode = Opcode::try_from((raw_instruction >> 12) as u8);
+        let extension = match opcode {
+            Ok(opcode) if opcode.has_extension_word() => {
+                let ext_addr = pc_reg_addr.wrapping_add(2);
+                let ext = match pc_reg_addr.checked_add(2) {
+                    Some(addr) => self.memory.try_fetch2(addr),
+                    None => Err(VMError::OutOfBounds(ext_addr)),
+                };
+                match ext {
+                    Ok(ext) => Some(ext),
+                    Err(cause) => {
+                        let fault = VMError::fault(cause, pc_reg_addr, raw_instruction, None);
+                        return self.fetch_fault(fault, pc_reg_addr);
+                    }
+                }
+            }
+            _ => None,
+        };
 
-        {
-            let ir = self.registers.get_register_mut(RegisterId::RIR.id())?;
-            ir.value = raw_instruction;
+        // Gas is charged before any state changes, so running out leaves the instruction to be retried
+        if let (Ok(opcode), Some(gas)) = (opcode, self.gas) {
+            let cost = self.config.gas_costs.cost(opcode);
+            self.gas = Some(gas.checked_sub(cost).ok_or(VMError::OutOfGas)?);
         }
 
-        {
-            let pc = self.registers.get_register_mut(RegisterId::RPC.id())?;
-            pc.inc_program_counter()?;
+        self.registers.get_register_mut(RegisterId::RIR.id())?.value = raw_instruction;
+
+        let executed = self
+            .advance_pc(extension.is_some())
+            .and_then(|()| self.execute_instruction(raw_instruction, extension));
+        if let Err(error) = executed {
+            let fault = VMError::fault(error, pc_reg_addr, raw_instruction, extension);
+            // The faulting instruction is retried when its handler returns
+            if !self.trap(&fault, pc_reg_addr) {
+                self.halted = true;
+                return Err(fault);
+            }
         }
+        self.steps += 1;
+        self.memory.tick();
+
+        Ok(())
+    }
 
-        if let Err(error) = self.execute_instruction(raw_instruction) {
+    // A failed fetch raises its vector like a faulting instruction, the handler can map the page or change the
+    // permissions and return to retry it. No instruction executed, so it is neither a step nor a device tick
+    fn fetch_fault(&mut self, fault: VMError, pc: VmAddr) -> Result<()> {
+        if !self.trap(&fault, pc) {
             self.halted = true;
-            return Err(error);
+            return Err(fault);
+        }
+        Ok(())
+    }
+
+    // Enters the handler of the vector `fault` is delivered to, false when the machine has to halt
+    fn trap(&mut self, fault: &VMError, return_addr: VmAddr) -> bool {
+        let Some(vector) = TrapVector::for_error(fault) else {
+            return false;
+        };
+        if let VMError::PageFault { addr, .. } = fault.cause()
+            && let Ok(rfar) = self.registers.get_register_mut(RegisterId::RFAR.id())
+        {
+            rfar.value = *addr;
+        }
+        self.enter_trap(vector, return_addr).unwrap_or(false)
+    }
+
+    pub fn user_mode(&self) -> bool {
+        self.registers
+            .flags()
+            .is_ok_and(|flags| flags & FLAG_USER != 0)
+    }
+
+    // Hands RPTBR and the mode to the memory, see `mmu.rs`
+    fn sync_address_space(&mut self) -> Result<()> {
+        let page_table = self
+            .registers
+            .get_register_read_only(RegisterId::RPTBR.id())?
+            .value;
+        let user = self.user_mode();
+        self.memory.set_address_space(page_table, user);
+        Ok(())
+    }
+
+    // Takes the lowest pending interrupt line when interrupts are enabled, an interrupt without a handler is dropped
+    fn service_interrupt(&mut self) -> Result<()> {
+        if !self.interrupts_enabled() {
+            return Ok(());
+        }
+        let pending = self.pending_interrupts | self.memory.pending_interrupts();
+        if pending == 0 {
+            return Ok(());
         }
+        let line = pending.trailing_zeros() as u8;
+        self.pending_interrupts &= !(1 << line);
+        self.memory.acknowledge_interrupt(line);
 
+        let return_addr = self
+            .registers
+            .get_register_read_only(RegisterId::RPC.id())?
+            .value;
+        self.enter_trap(TrapVector::Interrupt(line), return_addr)?;
+        Ok(())
+    }
+
+    // Saves RFLAGS and `return_addr` on the stack and jumps to the handler of `vector`, false when there is none
+    fn enter_trap(&mut self, vector: TrapVector, return_addr: VmAddr) -> Result<bool> {
+        // Handlers run in supervisor mode, the vector table and the stack are accessed with its rights
+        let page_table = self
+            .registers
+            .get_register_read_only(RegisterId::RPTBR.id())?
+            .value;
+        self.memory.set_address_space(page_table, false);
+        let handler = self.memory.read2(vector.entry()).unwrap_or(0);
+        if handler == 0 {
+            return Ok(false);
+        }
+        let handler = self.validate_jump_target(handler)?;
+        // The frame goes where RSP points, never outside the stack
+        self.check_stack_pointer()?;
+        let flags = self.registers.flags()?;
+        self.push_word(flags)?;
+        self.push_word(return_addr)?;
+        self.registers
+            .get_register_mut(RegisterId::RFLAGS.id())?
+            .value = flags & !(FLAG_INTERRUPT_ENABLE | FLAG_USER);
+        self.registers.get_register_mut(RegisterId::RPC.id())?.value = handler;
+        Ok(true)
+    }
+
+    // Moves RPC past the instruction being executed, wide instructions take two words
+    fn advance_pc(&mut self, wide: bool) -> Result<()> {
+        let pc = self.registers.get_register_mut(RegisterId::RPC.id())?;
+        pc.inc_program_counter()?;
+        if wide {
+            pc.inc_program_counter()?;
+        }
         Ok(())
     }
 
@@ -190,12 +694,81 @@ impl VM {
         Ok(reg)
     }
 
-    fn trace(&mut self, opcode: Opcode, dst: u8, src: u8, imm: VMWord) {
-        // TODO: Improve error handling
+    // RSP is inside the stack, or right above it when the stack is empty
+    fn check_stack_pointer(&self) -> Result<()> {
+        let sp = self
+            .registers
+            .get_register_read_only(RegisterId::RSP.id())?
+            .value;
+        if sp < self.config.stack.base {
+            return Err(VMError::StackOverflow);
+        }
+        if sp > self.config.stack.top {
+            return Err(VMError::StackUnderflow);
+        }
+        Ok(())
+    }
+
+    fn push_word(&mut self, value: VMWord) -> Result<()> {
+        let sp = self
+            .registers
+            .get_register_read_only(RegisterId::RSP.id())?
+            .value;
+        if sp > self.config.stack.top {
+            return Err(VMError::StackUnderflow);
+        }
+        let new_sp = sp
+            .checked_sub(2)
+            .filter(|addr| *addr >= self.config.stack.base)
+            .ok_or(VMError::StackOverflow)?;
+        self.memory.write2(new_sp, value)?;
+        self.registers.get_register_mut(RegisterId::RSP.id())?.value = new_sp;
+        Ok(())
+    }
+
+    fn pop_word(&mut self) -> Result<VMWord> {
+        let sp = self
+            .registers
+            .get_register_read_only(RegisterId::RSP.id())?
+            .value;
+        if sp < self.config.stack.base {
+            return Err(VMError::StackOverflow);
+        }
+        if u32::from(sp) + 2 > u32::from(self.config.stack.top) {
+            return Err(VMError::StackUnderflow);
+        }
+        let value = self.memory.try_read2(sp)?;
+        self.registers.get_register_mut(RegisterId::RSP.id())?.value = sp + 2;
+        Ok(value)
+    }
+
+    // In `OverflowMode::Trap` a result with the overflow flag set is an error instead of a wrapped value
+    fn check_overflow(&self, flags: VMWord) -> Result<()> {
+        if self.config.overflow == OverflowMode::Trap && flags & FLAG_OVERFLOW != 0 {
+            return Err(VMError::Overflow);
+        }
+        Ok(())
+    }
+
+    // Jump targets must be word aligned and point to a complete instruction inside memory
+    fn validate_jump_target(&self, target: VmAddr) -> Result<VmAddr> {
+        if !target.is_multiple_of(2) || usize::from(target) + 1 >= self.memory.memory_range() {
+            return Err(VMError::InvalidJumpTarget(target));
+        }
+        Ok(target)
+    }
+
+    fn trace(
+        &mut self,
+        opcode: Opcode,
+        dst: u8,
+        src: u8,
+        imm: VMWord,
+        ext: Option<VMWord>,
+    ) -> Result<()> {
         let pc_addr = self
             .registers
-            .get_register_read_only(RegisterId::RPC.id())
-            .unwrap()
+            .get_register_read_only(RegisterId::RPC.id())?
             .value;
         self.trace_buffer.push(TraceEntry::new(
             pc_addr,
@@ -203,11 +776,27 @@ impl VM {
             dst,
             src,
             imm,
+            ext,
             self.registers.register_map.clone(),
         ));
+        Ok(())
     }
 
     pub fn _write_logs<T: std::fmt::Debug>(data: T, file_name: &str) {
+        VM::_write_log_file(&format!("{:#?}", data), file_name);
+    }
+
+    // Readable trace, one disassembled instruction per line
+    pub fn _write_trace_logs(&self) {
+        let lines: Vec<String> = self
+            .trace_buffer
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        VM::_write_log_file(&lines.join("\n"), "vm_trace");
+    }
+
+    fn _write_log_file(contents: &str, file_name: &str) {
         let log_dir = ".logs";
         // Create the directory if it doesn't exist
         if let Err(e) = fs::create_dir_all(log_dir) {
@@ -221,7 +810,7 @@ impl VM {
             .truncate(true)
             .open(format!("{}/{}.log", log_dir, file_name))
         {
-            writeln!(file, "{:#?}", data).unwrap();
+            writeln!(file, "{}", contents).unwrap();
         }
     }
 
@@ -231,7 +820,7 @@ impl VM {
         let mut private_program_state: Vec<Fr> = vec![];
 
         for entry in &self.trace_buffer {
-            let mut reg_array = [0u16; 7];
+            let mut reg_array = [0u16; MAX_REGS];
 
             for (idx, reg) in entry.registers.iter() {
                 reg_array[*idx as usize] = reg.value;
@@ -242,19 +831,31 @@ impl VM {
             let register_bytes: Vec<u8> = serialize(&reg_array).unwrap();
             let pc_bytes = serialize(&entry.pc).unwrap();
             let opcode_bytes = serialize(&(entry.opcode as u16)).unwrap();
-
-            let hashed_state =
-                Sha256Hash::hash_multiple(&[&mem_bytes, &register_bytes, &pc_bytes, &opcode_bytes]);
+            // Wide instructions are only fully committed to together with their literal word
+            let ext_bytes = serialize(&entry.ext).unwrap();
+
+            let hashed_state = Sha256Hash::hash_multiple(&[
+                &mem_bytes,
+                &register_bytes,
+                &pc_bytes,
+                &opcode_bytes,
+                &ext_bytes,
+            ]);
             let poseidon_hash = ZkContext::_compute_poseidon_hash(hashed_state).unwrap();
 
             pub_program_state.push(poseidon_hash);
             private_program_state.push(hashed_state);
         }
 
-        if let Ok(state) = std::env::var("ZK_STATE_CAPACITY") {
+        let capacity = self.config.zk_state_capacity.or_else(|| {
+            std::env::var("ZK_STATE_CAPACITY")
+                .ok()
+                .and_then(|state| state.parse().ok())
+        });
+        if let Some(capacity) = capacity {
             // Add dummy states to fit zk program expected state capacity
             let current_state_len = pub_program_state.len();
-            let state_capacity = state.parse::<usize>().unwrap() - current_state_len;
+            let state_capacity = capacity.saturating_sub(current_state_len);
             VM::_write_logs(current_state_len, "state_len");
 
             for _i in 0..state_capacity {
@@ -265,8 +866,122 @@ impl VM {
             VM::_write_logs(pub_program_state, "public_program_state");
             VM::_write_logs(private_program_state, "private_program_state");
         } else {
-            eprintln!("ZK_STATE_CAPACITY is not defined in .env file!");
+            eprintln!(
+                "zk_state_capacity is not configured and ZK_STATE_CAPACITY is not defined in .env file!"
+            );
+        }
+    }
+}
+
+/// Builds a `VM` from a `Config`, the config is validated before anything is allocated.
+///
+/// ```ignore
+/// let mut vm = VM::builder().memory_size(0x1000).max_steps(10_000).build()?;
+/// ```
+#[derive(Debug, Default)]
+pub struct VMBuilder {
+    config: Config,
+    memory: Option<Box<dyn BusDevice>>,
+    host: Option<HostFunctions>,
+}
+
+impl VMBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces every setting, setters called afterwards still apply on top of it
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn memory_size(mut self, memory_size: usize) -> Self {
+        self.config.memory_size = memory_size;
+        self
+    }
+
+    pub fn load_address(mut self, load_address: VmAddr) -> Self {
+        self.config.load_address = load_address;
+        self
+    }
+
+    pub fn entry_point(mut self, entry_point: VmAddr) -> Self {
+        self.config.entry_point = Some(entry_point);
+        self
+    }
+
+    pub fn stack(mut self, stack: StackRegion) -> Self {
+        self.config.stack = stack;
+        self
+    }
+
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.config.max_steps = Some(max_steps);
+        self
+    }
+
+    pub fn trace(mut self, trace: TraceMode) -> Self {
+        self.config.trace = trace;
+        self
+    }
+
+    pub fn zk_state_capacity(mut self, capacity: usize) -> Self {
+        self.config.zk_state_capacity = Some(capacity);
+        self
+    }
+
+    pub fn overflow(mut self, overflow: OverflowMode) -> Self {
+        self.config.overflow = overflow;
+        self
+    }
+
+    pub fn gas_limit(mut self, gas_limit: u64) -> Self {
+        self.config.gas_limit = Some(gas_limit);
+        self
+    }
+
+    pub fn gas_costs(mut self, gas_costs: GasCosts) -> Self {
+        self.config.gas_costs = gas_costs;
+        self
+    }
+
+    pub fn memory_protection(mut self, enabled: bool) -> Self {
+        self.config.memory_protection = enabled;
+        self
+    }
+
+    // Uses `memory` instead of a `LinearMemory` of `memory_size` bytes
+    pub fn memory(mut self, memory: Box<dyn BusDevice>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    // Replaces the built-in host functions, which print to stdout and read from stdin
+    pub fn host_functions(mut self, host: HostFunctions) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    pub fn build(self) -> Result<VM> {
+        self.config.validate()?;
+        let mut memory = self
+            .memory
+            .unwrap_or_else(|| Box::new(LinearMemory::new(self.config.memory_size)));
+        if self.config.memory_protection {
+            memory = Box::new(ProtectedBus::new(memory));
+            // Programs cannot redirect traps, the host installs handlers with `VM::set_trap_handler`
+            let map = &self.config.memory_map;
+            for segment in [map.prefix, map.rodata] {
+                memory.protect(segment.start, segment.len(), Permissions::READ_ONLY);
+            }
         }
+        let mut vm = VM::with_config(self.config);
+        vm.memory = Box::new(Mmu::new(memory));
+        if let Some(host) = self.host {
+            vm.host = host;
+        }
+        Ok(vm)
     }
 }
 
@@ -276,121 +991,288 @@ impl VM {
 /// such as halting, reading, writing, copying, and adding values.
 /// Each method is invoked in response to a specific opcode during program execution.
 impl VMOperations for VM {
-    // TODO: Improve error handling for VMOperations
-    fn halt(&mut self, _: Register, _: Register) {
-        VM::_write_logs(&self.trace_buffer, "vm_trace");
+    fn halt(&mut self, _: Register, _: Register) -> Result<()> {
+        self._write_trace_logs();
         if self.zk_output_enabled {
             self._parse_private_inputs();
         }
 
         self.halted = true;
+        Ok(())
     }
 
-    fn write(&mut self, source_reg: Register, destination_reg: Register) {
+    fn write(&mut self, source_reg: Register, destination_reg: Register) -> Result<()> {
         // dst_reg is address
-        if self
-            .memory
-            .write2(destination_reg.value, source_reg.value)
-            .is_err()
-        {
-            self.halted = true;
-        }
+        self.memory.write2(destination_reg.value, source_reg.value)
     }
 
-    fn copy(&mut self, source_reg: Register, destination_reg: Register) {
-        let dest_register = self
-            .registers
-            .get_register_mut(destination_reg.id.id())
-            .unwrap();
-        dest_register.value = source_reg.value;
+    fn copy(&mut self, source_reg: Register, destination_reg: Register) -> Result<()> {
+        self.registers
+            .get_register_mut(destination_reg.id.id())?
+            .value = source_reg.value;
+        Ok(())
     }
 
-    fn add(&mut self, source_reg: Register, destination_reg: Register) {
-        let result = source_reg
-            .value
-            .checked_add(destination_reg.value)
-            .expect("Add instruction failed with overflow");
-        let dest_register = self
-            .registers
-            .get_register_mut(destination_reg.id.id())
-            .unwrap();
-        dest_register.value = result;
+    fn add(&mut self, source_reg: Register, destination_reg: Register) -> Result<()> {
+        self.addi16(destination_reg, source_reg.value)
     }
 
-    fn load(&mut self, source_reg: Register, destination_reg: Register) {
-        if let Some(val) = self.memory.read2(source_reg.value) {
-            // When load reg.value is interpret as an address to a memory location
-            let dest_register = self
-                .registers
-                .get_register_mut(destination_reg.id.id())
-                .unwrap();
-            dest_register.value = val;
-        } else {
-            eprintln!("LOAD instruction fails");
-            self.halted = true;
-        }
+    fn load(&mut self, source_reg: Register, destination_reg: Register) -> Result<()> {
+        // When load reg.value is interpret as an address to a memory location
+        let value = self.memory.try_read2(source_reg.value)?;
+        self.registers
+            .get_register_mut(destination_reg.id.id())?
+            .value = value;
+        Ok(())
     }
 
-    fn load_imm(&mut self, _: Register, _: Register) {
+    fn load_imm(&mut self, _: Register, _: Register) -> Result<()> {
         // print!("Immediate value loaded successfully");
+        Ok(())
     }
 
-    fn store_out(&mut self, source_reg: Register, _: Register) {
-        if let Err(err) = self.memory.write2(START_ADDRESS, source_reg.value) {
-            eprintln!("Store out error: {}", err.message());
-            self.halted = true;
+    fn store_out(&mut self, source_reg: Register, _: Register) -> Result<()> {
+        self.memory
+            .write2(self.config.output_address(), source_reg.value)
+    }
+
+    fn cmp(&mut self, source_reg: Register, destination_reg: Register) -> Result<()> {
+        // Computes dst - src only to update the flags, carry holds the unsigned borrow
+        let (result, borrow) = destination_reg.value.overflowing_sub(source_reg.value);
+        let (_, overflow) = (destination_reg.value as i16).overflowing_sub(source_reg.value as i16);
+        self.registers
+            .set_condition_flags(condition_flags(result, borrow, overflow))
+    }
+
+    fn jump(&mut self, condition: Condition, target: JumpTarget) -> Result<()> {
+        if !condition.holds(self.registers.flags()?) {
+            return Ok(());
         }
+
+        let pc = self
+            .registers
+            .get_register_read_only(RegisterId::RPC.id())?;
+        let target = match target {
+            JumpTarget::Absolute(addr) => addr,
+            // Relative to the already incremented PC, so an offset of 0 falls through to the next instruction
+            JumpTarget::Relative(offset) => {
+                pc.value
+                    .checked_add_signed(offset * 2)
+                    .ok_or(VMError::InvalidJumpTarget(
+                        pc.value.wrapping_add_signed(offset * 2),
+                    ))?
+            }
+        };
+        let target = self.validate_jump_target(target)?;
+        self.registers.get_register_mut(RegisterId::RPC.id())?.value = target;
+        Ok(())
     }
-}
 
-/*
-Instruction set which tells the CPU to do some fundamental task, such as add two numbers. Instructions have opcode (kind of task) and a set of parameters which provide inputs to the task being performed.
+    fn alu(
+        &mut self,
+        function: AluFunction,
+        source_reg: Register,
+        destination_reg: Register,
+    ) -> Result<()> {
+        let (result, flags) = function.compute(destination_reg.value, source_reg.value)?;
+        self.check_overflow(flags)?;
+        self.registers
+            .get_register_mut(destination_reg.id.id())?
+            .value = result;
+        self.registers.set_condition_flags(flags)
+    }
 
-Each opcode is one task that the CPU knows how to do.
-Each instruction is 16-bit in my case, with the left 4 bits storing the opcode. The rest of the bits are used to store the parameters.
+    fn push(&mut self, source_reg: Register) -> Result<()> {
+        self.push_word(source_reg.value)
+    }
 
-So i decide how much bit/byte to give for my opcode when i decide how much unique operations i want my VM to support
-*/
-// enum Opcode {
-//     HALT      // Stop execution
-//     READ,
-//     WRITE,
-//     COPY,
-//     ADD,      // Add
-//     SUB,      // Subtract
-//     MUL,      // Multiply
-//     DIV,      // Divide
-//     MOV,      // Move value
-//     LOAD,     // Load from memory
-//     STORE,    // Store to memory
-//     JMP,      // Jump
-//     BEQ,      // Branch if equal
-//     BNE,      // Branch if not equal
-//     AND,      // Bitwise AND
-//     OR,       // Bitwise OR
-//     XOR,      // Bitwise XOR
-//     NOT,      // Bitwise NOT
-//     NOP,      // No operation
-// }
+    fn pop(&mut self, destination_reg: Register) -> Result<()> {
+        let value = self.pop_word()?;
+        self.registers
+            .get_register_mut(destination_reg.id.id())?
+            .value = value;
+        Ok(())
+    }
 
-/// It depends on the OPCODE, sometimes reg.value is a bytes holding data already taken from memory, at other opcodes reg.value is an address pointing to a location in memory
-#[derive(Debug, Copy, Clone)]
-#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
-enum Opcode {
-    // These are so called mnemonics, human-readable representations of machine instructions, used to make VM ISA easier to understand
-    HALT,
+    fn call(&mut self, target: VmAddr) -> Result<()> {
+        // PC already points past the CALL, which is the return address
+        let target = self.validate_jump_target(target)?;
+        let return_addr = self
+            .registers
+            .get_register_read_only(RegisterId::RPC.id())?
+            .value;
+        self.push_word(return_addr)?;
+        self.registers.get_register_mut(RegisterId::RPC.id())?.value = target;
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<()> {
+        let return_addr = self.pop_word()?;
+        let return_addr = self.validate_jump_target(return_addr)?;
+        self.registers.get_register_mut(RegisterId::RPC.id())?.value = return_addr;
+        Ok(())
+    }
+
+    fn load_imm16(&mut self, destination_reg: Register, value: VMWord) -> Result<()> {
+        self.registers
+            .get_register_mut(destination_reg.id.id())?
+            .value = value;
+        Ok(())
+    }
+
+    fn addi16(&mut self, destination_reg: Register, value: VMWord) -> Result<()> {
+        // Wraps around like a real 16-bit ALU, an unsigned overflow is reported through the carry flag
+        let (result, carry) = destination_reg.value.overflowing_add(value);
+        let (_, overflow) = (destination_reg.value as i16).overflowing_add(value as i16);
+        let flags = condition_flags(result, carry, overflow);
+        self.check_overflow(flags)?;
+        self.registers
+            .get_register_mut(destination_reg.id.id())?
+            .value = result;
+        self.registers.set_condition_flags(flags)
+    }
+
+    // Pops what `enter_trap` pushed, restoring RFLAGS also restores the interrupt-enable flag
+    fn iret(&mut self) -> Result<()> {
+        let return_addr = self.pop_word()?;
+        let return_addr = self.validate_jump_target(return_addr)?;
+        let flags = self.pop_word()?;
+        self.registers
+            .get_register_mut(RegisterId::RFLAGS.id())?
+            .value = flags;
+        self.registers.get_register_mut(RegisterId::RPC.id())?.value = return_addr;
+        Ok(())
+    }
+
+    fn set_interrupts_enabled(&mut self, enabled: bool) -> Result<()> {
+        let rflags = self.registers.get_register_mut(RegisterId::RFLAGS.id())?;
+        if enabled {
+            rflags.value |= FLAG_INTERRUPT_ENABLE;
+        } else {
+            rflags.value &= !FLAG_INTERRUPT_ENABLE;
+        }
+        Ok(())
+    }
+
+    // A registered host function takes precedence over the step counter the VM answers SYS_STEPS with
+    fn syscall(&mut self, number: VMWord) -> Result<()> {
+        let name = match self.host.name(number) {
+            Some(name) => name.to_string(),
+            None if number == SYS_STEPS => "steps".to_string(),
+            None => return Err(VMError::UnknownSyscall(number)),
+        };
+        if self.trace_enabled
+            && let Some(entry) = self.trace_buffer.last_mut()
+        {
+            entry.host_function = Some(name);
+        }
+
+        if self.host.name(number).is_some() {
+            return self
+                .host
+                .call(number, &mut self.registers, self.memory.as_mut());
+        }
+        self.registers.get_register_mut(RegisterId::RR0.id())?.value = self.steps as VMWord;
+        self.registers.get_register_mut(RegisterId::RR1.id())?.value = (self.steps >> 16) as VMWord;
+        Ok(())
+    }
+}
+
+fn condition_flags(result: VMWord, carry: bool, overflow: bool) -> VMWord {
+    let mut flags = 0;
+    if result == 0 {
+        flags |= FLAG_ZERO;
+    }
+    if carry {
+        flags |= FLAG_CARRY;
+    }
+    if result & 0x8000 != 0 {
+        flags |= FLAG_NEGATIVE;
+    }
+    if overflow {
+        flags |= FLAG_OVERFLOW;
+    }
+    flags
+}
+
+// User mode cannot stop the machine, switch interrupts or write the registers that hold its privileges
+fn check_privilege(opcode: Opcode, dest_reg_i: u8, immediate_value: VMWord) -> Result<()> {
+    let writes_dst = match opcode {
+        Opcode::HALT | Opcode::INT => return Err(VMError::PrivilegedInstruction),
+        Opcode::COPY | Opcode::ADD | Opcode::LOAD | Opcode::LOAD_IMM | Opcode::ALU => true,
+        Opcode::STACK => matches!(
+            StackOperation::try_from(immediate_value as u8),
+            Ok(StackOperation::POP)
+        ),
+        Opcode::WIDE => matches!(
+            WideOperation::try_from(immediate_value as u8),
+            Ok(WideOperation::LOAD_IMM16 | WideOperation::ADDI16)
+        ),
+        _ => false,
+    };
+    let privileged = [RegisterId::RFLAGS, RegisterId::RPTBR, RegisterId::RFAR];
+    if writes_dst && privileged.iter().any(|reg| reg.id() == dest_reg_i) {
+        return Err(VMError::PrivilegedInstruction);
+    }
+    Ok(())
+}
+
+// The imm nibble is a 4-bit two's complement value when used as an offset: 0..7 forward, -8..-1 backward
+fn sign_extend_nibble(imm: VMWord) -> i16 {
+    (((imm & 0xF) as i16) << 12) >> 12
+}
+
+/*
+Instruction set which tells the CPU to do some fundamental task, such as add two numbers. Instructions have opcode (kind of task) and a set of parameters which provide inputs to the task being performed.
+
+Each opcode is one task that the CPU knows how to do.
+Each instruction is 16-bit in my case, with the left 4 bits storing the opcode. The rest of the bits are used to store the parameters.
+
+So i decide how much bit/byte to give for my opcode when i decide how much unique operations i want my VM to support
+*/
+// enum Opcode {
+//     HALT      // Stop execution
+//     READ,
+//     WRITE,
+//     COPY,
+//     ADD,      // Add
+//     MOV,      // Move value
+//     LOAD,     // Load from memory
+//     STORE,    // Store to memory
+//     NOP,      // No operation
+// }
+
+/// It depends on the OPCODE, sometimes reg.value is a bytes holding data already taken from memory, at other opcodes reg.value is an address pointing to a location in memory
+#[derive(Debug, Display, Copy, Clone, PartialEq)]
+#[display("{self:?}")]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum Opcode {
+    // These are so called mnemonics, human-readable representations of machine instructions, used to make VM ISA easier to understand
+    HALT,
     COPY,      // register <- register
     LOAD,      // register <- memory[address in register]
     WRITE,     // memory[address in register] <- register
     ADD,       // register <- register + register
     LOAD_IMM,  // register <- immediage
-    STORE_OUT, // store result from R0 to memory at start address
+    STORE_OUT, // store result from R0 to the output word of the data segment
+    CMP,       // flags <- register - register
+    JMP,       // PC <- register or PC + offset, when the condition in the dst field holds
+    ALU,       // register <- register (function in imm) register
+    STACK,     // PUSH / POP / CALL / RET selected by imm
+    WIDE,      // operation selected by imm, followed by a 16-bit literal word
+    INT,       // IRET / EI / DI selected by imm
+    SYSCALL,   // call the host function numbered by register or imm, see `host.rs`
 }
 
 impl Opcode {
     pub fn id(&self) -> u8 {
         *self as u8
     }
+
+    // Wide instructions take two words in memory, the VM fetches both and advances PC by 4
+    pub fn has_extension_word(&self) -> bool {
+        matches!(self, Opcode::WIDE)
+    }
 }
 
 impl TryFrom<u8> for Opcode {
@@ -405,30 +1287,304 @@ impl TryFrom<u8> for Opcode {
             4 => Ok(Opcode::ADD),
             5 => Ok(Opcode::LOAD_IMM),
             6 => Ok(Opcode::STORE_OUT),
+            7 => Ok(Opcode::CMP),
+            8 => Ok(Opcode::JMP),
+            9 => Ok(Opcode::ALU),
+            10 => Ok(Opcode::STACK),
+            11 => Ok(Opcode::WIDE),
+            12 => Ok(Opcode::INT),
+            13 => Ok(Opcode::SYSCALL),
 
             _ => Err(VMError::OpcodeDoesNotExist),
         }
     }
 }
 
+/// Condition encoded in the dst field of a JMP instruction, evaluated against `RFLAGS`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Condition {
+    Always,
+    Zero,
+    NotZero,
+    Equal,
+    NotEqual,
+    Less,                 // signed <
+    GreaterEqual,         // signed >=
+    LessUnsigned,         // unsigned <
+    GreaterEqualUnsigned, // unsigned >=
+}
+
+impl Condition {
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    // Mnemonic of the JMP instruction using this condition
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Condition::Always => "JMP",
+            Condition::Zero => "JZ",
+            Condition::NotZero => "JNZ",
+            Condition::Equal => "JEQ",
+            Condition::NotEqual => "JNE",
+            Condition::Less => "JLT",
+            Condition::GreaterEqual => "JGE",
+            Condition::LessUnsigned => "JLTU",
+            Condition::GreaterEqualUnsigned => "JGEU",
+        }
+    }
+
+    pub fn holds(&self, flags: VMWord) -> bool {
+        let zero = flags & FLAG_ZERO != 0;
+        let carry = flags & FLAG_CARRY != 0;
+        let negative = flags & FLAG_NEGATIVE != 0;
+        let overflow = flags & FLAG_OVERFLOW != 0;
+
+        match self {
+            Condition::Always => true,
+            Condition::Zero | Condition::Equal => zero,
+            Condition::NotZero | Condition::NotEqual => !zero,
+            Condition::Less => negative != overflow,
+            Condition::GreaterEqual => negative == overflow,
+            Condition::LessUnsigned => carry,
+            Condition::GreaterEqualUnsigned => !carry,
+        }
+    }
+}
+
+impl TryFrom<u8> for Condition {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Condition::Always),
+            1 => Ok(Condition::Zero),
+            2 => Ok(Condition::NotZero),
+            3 => Ok(Condition::Equal),
+            4 => Ok(Condition::NotEqual),
+            5 => Ok(Condition::Less),
+            6 => Ok(Condition::GreaterEqual),
+            7 => Ok(Condition::LessUnsigned),
+            8 => Ok(Condition::GreaterEqualUnsigned),
+
+            _ => Err(VMError::ConditionDoesNotExist),
+        }
+    }
+}
+
+/// The 4-bit opcode space is almost used up, so the remaining arithmetic and bitwise operations share the ALU opcode.
+/// The imm nibble selects the function, every function computes `dst <- dst (op) src` and sets the flags.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum AluFunction {
+    SUB,
+    MUL,
+    DIV, // unsigned
+    MOD, // unsigned
+    AND,
+    OR,
+    XOR,
+    NOT, // unary, src is ignored
+    SHL,
+    SHR, // logical, fills with zeros
+    SAR, // arithmetic, fills with the sign bit
+}
+
+impl AluFunction {
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    // Returns the result together with the condition flags it produces
+    pub fn compute(&self, dst: VMWord, src: VMWord) -> Result<(VMWord, VMWord)> {
+        // Shift amounts wrap at the word size, like the 5-bit mask on 32-bit x86 shifts
+        let shift = u32::from(src & 0xF);
+        let (result, carry, overflow) = match self {
+            AluFunction::SUB => {
+                let (result, borrow) = dst.overflowing_sub(src);
+                let (_, overflow) = (dst as i16).overflowing_sub(src as i16);
+                (result, borrow, overflow)
+            }
+            AluFunction::MUL => {
+                let (result, carry) = dst.overflowing_mul(src);
+                let (_, overflow) = (dst as i16).overflowing_mul(src as i16);
+                (result, carry, overflow)
+            }
+            AluFunction::DIV => (
+                dst.checked_div(src).ok_or(VMError::DivideByZero)?,
+                false,
+                false,
+            ),
+            AluFunction::MOD => (
+                dst.checked_rem(src).ok_or(VMError::DivideByZero)?,
+                false,
+                false,
+            ),
+            AluFunction::AND => (dst & src, false, false),
+            AluFunction::OR => (dst | src, false, false),
+            AluFunction::XOR => (dst ^ src, false, false),
+            AluFunction::NOT => (!dst, false, false),
+            // Carry holds the last bit shifted out
+            AluFunction::SHL => (
+                dst << shift,
+                shift > 0 && (dst >> (16 - shift)) & 1 != 0,
+                false,
+            ),
+            AluFunction::SHR => (
+                dst >> shift,
+                shift > 0 && (dst >> (shift - 1)) & 1 != 0,
+                false,
+            ),
+            AluFunction::SAR => (
+                ((dst as i16) >> shift) as VMWord,
+                shift > 0 && (dst >> (shift - 1)) & 1 != 0,
+                false,
+            ),
+        };
+        Ok((result, condition_flags(result, carry, overflow)))
+    }
+}
+
+impl TryFrom<u8> for AluFunction {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(AluFunction::SUB),
+            1 => Ok(AluFunction::MUL),
+            2 => Ok(AluFunction::DIV),
+            3 => Ok(AluFunction::MOD),
+            4 => Ok(AluFunction::AND),
+            5 => Ok(AluFunction::OR),
+            6 => Ok(AluFunction::XOR),
+            7 => Ok(AluFunction::NOT),
+            8 => Ok(AluFunction::SHL),
+            9 => Ok(AluFunction::SHR),
+            10 => Ok(AluFunction::SAR),
+
+            _ => Err(VMError::AluFunctionDoesNotExist),
+        }
+    }
+}
+
+/// Selected by the imm nibble of the STACK opcode, the stack lives in the `Config::stack` region
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum StackOperation {
+    PUSH, // stack <- src
+    POP,  // dst <- stack
+    CALL, // stack <- PC, PC <- src
+    RET,  // PC <- stack
+}
+
+impl StackOperation {
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for StackOperation {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(StackOperation::PUSH),
+            1 => Ok(StackOperation::POP),
+            2 => Ok(StackOperation::CALL),
+            3 => Ok(StackOperation::RET),
+
+            _ => Err(VMError::StackOperationDoesNotExist),
+        }
+    }
+}
+
+/// Selected by the imm nibble of the INT opcode, see `interrupt.rs`
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum IntOperation {
+    IRET, // PC <- stack, RFLAGS <- stack
+    EI,   // enable interrupts
+    DI,   // disable interrupts
+}
+
+impl IntOperation {
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for IntOperation {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(IntOperation::IRET),
+            1 => Ok(IntOperation::EI),
+            2 => Ok(IntOperation::DI),
+
+            _ => Err(VMError::IntOperationDoesNotExist),
+        }
+    }
+}
+
+/// Selected by the imm nibble of the WIDE opcode, the operand is the literal word following the instruction
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum WideOperation {
+    LOAD_IMM16, // dst <- literal
+    ADDI16,     // dst <- dst + literal, sets flags
+    JMP16,      // PC <- literal, when the condition in the dst field holds
+    CALL16,     // stack <- PC, PC <- literal
+}
+
+impl WideOperation {
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for WideOperation {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(WideOperation::LOAD_IMM16),
+            1 => Ok(WideOperation::ADDI16),
+            2 => Ok(WideOperation::JMP16),
+            3 => Ok(WideOperation::CALL16),
+
+            _ => Err(VMError::WideOperationDoesNotExist),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JumpTarget {
+    Absolute(VmAddr),
+    Relative(i16), // counted in instructions, not bytes
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::asm::assemble_with_symbols;
     use crate::bus::BusDevice;
-    use crate::constants::VmAddr;
+    use crate::constants::{MEMORY_SIZE, STACK_TOP, START_ADDRESS, VmAddr};
     use crate::error::VMError;
     use crate::register::RegisterId;
-    use crate::utils::build_simple_program;
+    use crate::utils::{build_simple_program, instruction_builder};
 
     #[derive(Debug)]
     struct MockBus {
         memory: Vec<u8>,
+        irq: u8, // raised interrupt lines
     }
 
     impl MockBus {
         fn new() -> Self {
             Self {
-                memory: vec![0; 1024],
+                memory: vec![0; MEMORY_SIZE],
+                irq: 0,
             }
         }
     }
@@ -442,13 +1598,21 @@ mod tests {
                 *slot = value;
                 Ok(())
             } else {
-                Err(VMError::OutOfBounds)
+                Err(VMError::OutOfBounds(addr))
             }
         }
         fn memory_range(&self) -> usize {
             self.memory.len()
         }
 
+        fn pending_interrupts(&self) -> u8 {
+            self.irq
+        }
+
+        fn acknowledge_interrupt(&mut self, line: u8) {
+            self.irq &= !(1 << line);
+        }
+
         fn as_bytes(&self) -> &Vec<u8> {
             &self.memory
         }
@@ -465,6 +1629,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_vm_initialization() {
         let vm = VM::new();
         assert_eq!(vm.halted, false);
@@ -477,7 +1642,7 @@ mod tests {
         let mut vm = VM::new();
         let dummy = Box::new(MockBus::new());
         vm.set_memory(dummy);
-        assert_eq!(vm.memory.memory_range(), 1024);
+        assert_eq!(vm.memory.memory_range(), MEMORY_SIZE);
     }
 
     #[test]
@@ -512,31 +1677,13 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn text_execute_instruction_registers_and_pc() {
-        let program = build_simple_program();
+        let program = Program::new(build_simple_program());
         let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(5000)));
+        vm.load_program(&program).unwrap();
 
-        let mut memory = LinearMemory::new(5000);
-        for (i, add_reg) in program.iter().enumerate() {
-            let address_to_write = u16::try_from(i)
-                // START_ADDRESS + (i as u16) * 2;
-                .expect("Value out of range for u16")
-                .checked_mul(2) // Implementation of a for loop step by 2
-                .expect("i * 2 failed")
-                .checked_add(START_ADDRESS)
-                .expect("Index + 0x100 out of range");
-
-            println!("\nAddress: {}, Value: {}", address_to_write, add_reg);
-
-            if let Err(e) = memory.write2(address_to_write, *add_reg) {
-                println!(
-                    "Writing on memory error on location: {}, err: {}",
-                    address_to_write, e
-                );
-            }
-        }
-
-        vm.set_memory(Box::new(memory));
         let mut step = 0;
         let expected_pcs: Vec<u16> = vec![258, 260, 262, 264, 266, 268, 270];
         let expected_registers = vec![
@@ -577,4 +1724,992 @@ mod tests {
             }
         }
     }
+
+    // First address past the memory of `vm_with_program`
+    const END: VmAddr = MEMORY_SIZE as VmAddr;
+
+    fn vm_with_program(program: &[u16]) -> VM {
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(MEMORY_SIZE)));
+        vm.load_program(&Program::new(program.to_vec())).unwrap();
+        vm
+    }
+
+    fn run_to_halt(vm: &mut VM) -> Result<()> {
+        while !vm.halted {
+            vm.tick()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cmp_sets_flags() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x07, 0x00, 0x06, 0x03), // CMP R0, 3
+        ]);
+        vm.registers
+            .get_register_mut(RegisterId::RR0.id())
+            .unwrap()
+            .value = 3;
+        vm.tick().unwrap();
+        assert_eq!(vm.registers.flags().unwrap(), FLAG_ZERO);
+
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x07, 0x00, 0x06, 0x03), // CMP R0, 3
+        ]);
+        vm.registers
+            .get_register_mut(RegisterId::RR0.id())
+            .unwrap()
+            .value = 1;
+        vm.tick().unwrap();
+        assert_eq!(vm.registers.flags().unwrap(), FLAG_CARRY | FLAG_NEGATIVE);
+        assert!(Condition::Less.holds(vm.registers.flags().unwrap()));
+        assert!(Condition::LessUnsigned.holds(vm.registers.flags().unwrap()));
+    }
+
+    #[test]
+    fn test_add_sets_carry_instead_of_panicking() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x04, 0x00, 0x06, 0x01), // ADD R0, 1
+        ]);
+        vm.registers
+            .get_register_mut(RegisterId::RR0.id())
+            .unwrap()
+            .value = 0xFFFF;
+        vm.tick().unwrap();
+        assert_eq!(
+            vm.registers
+                .get_register_read_only(RegisterId::RR0.id())
+                .unwrap()
+                .value,
+            0
+        );
+        assert_eq!(vm.registers.flags().unwrap(), FLAG_ZERO | FLAG_CARRY);
+    }
+
+    #[test]
+    fn test_relative_conditional_jump_loops() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x01, 0x06, 0x01), // COPY R1, 1
+            instruction_builder(0x04, 0x00, 0x01, 0x00), // ADD R0, R1
+            instruction_builder(0x07, 0x00, 0x06, 0x04), // CMP R0, 4
+            instruction_builder(0x08, Condition::NotEqual.id(), 0x06, 0x0D), // JNE -3
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(
+            vm.registers
+                .get_register_read_only(RegisterId::RR0.id())
+                .unwrap()
+                .value,
+            4
+        );
+    }
+
+    #[test]
+    fn test_absolute_jump_through_register() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x08, Condition::Always.id(), 0x01, 0x00), // JMP R1
+            instruction_builder(0x01, 0x00, 0x06, 0x09),                   // COPY R0, 9 (skipped)
+            instruction_builder(0x00, 0x00, 0x00, 0x00),                   // HALT
+        ]);
+        vm.registers
+            .get_register_mut(RegisterId::RR1.id())
+            .unwrap()
+            .value = START_ADDRESS + 4;
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(
+            vm.registers
+                .get_register_read_only(RegisterId::RR0.id())
+                .unwrap()
+                .value,
+            0
+        );
+    }
+
+    #[test]
+    fn test_invalid_jump_targets_are_errors() {
+        for target in [START_ADDRESS + 1, 0x2000] {
+            let mut vm = vm_with_program(&[
+                instruction_builder(0x08, Condition::Always.id(), 0x01, 0x00), // JMP R1
+            ]);
+            vm.registers
+                .get_register_mut(RegisterId::RR1.id())
+                .unwrap()
+                .value = target;
+            let error = vm.tick().unwrap_err();
+            assert!(matches!(error.cause(), VMError::InvalidJumpTarget(addr) if *addr == target));
+            assert_eq!(error.addr(), Some(target));
+            assert!(vm.halted);
+        }
+    }
+
+    fn alu_ix(function: AluFunction, dst: u8, src: u8) -> u16 {
+        instruction_builder(0x09, dst, src, function.id())
+    }
+
+    fn reg(vm: &VM, id: RegisterId) -> u16 {
+        vm.registers.get_register_read_only(id.id()).unwrap().value
+    }
+
+    #[test]
+    fn test_alu_arithmetic() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x00, 0x06, 0x0F), // COPY R0, 15
+            instruction_builder(0x01, 0x01, 0x06, 0x04), // COPY R1, 4
+            alu_ix(AluFunction::MUL, 0x00, 0x01),        // R0 = 60
+            alu_ix(AluFunction::SUB, 0x00, 0x01),        // R0 = 56
+            instruction_builder(0x01, 0x02, 0x00, 0x00), // COPY R2, R0
+            alu_ix(AluFunction::DIV, 0x00, 0x01),        // R0 = 14
+            alu_ix(AluFunction::MOD, 0x02, 0x03),        // R2 = 56 % R3, R3 is still 0
+        ]);
+        for _ in 0..6 {
+            vm.tick().unwrap();
+        }
+        assert_eq!(reg(&vm, RegisterId::RR0), 14);
+        assert_eq!(reg(&vm, RegisterId::RR2), 56);
+        assert!(matches!(
+            vm.tick().map_err(VMError::into_cause),
+            Err(VMError::DivideByZero)
+        ));
+        assert!(vm.halted);
+    }
+
+    #[test]
+    fn test_alu_function_flags() {
+        assert_eq!(
+            AluFunction::SUB.compute(2, 3).unwrap(),
+            (0xFFFF, FLAG_CARRY | FLAG_NEGATIVE)
+        );
+        assert_eq!(
+            AluFunction::MUL.compute(0x4000, 4).unwrap(),
+            (0, FLAG_ZERO | FLAG_CARRY | FLAG_OVERFLOW)
+        );
+        assert_eq!(
+            AluFunction::XOR.compute(0xAA, 0xAA).unwrap(),
+            (0, FLAG_ZERO)
+        );
+        assert_eq!(
+            AluFunction::NOT.compute(0x00FF, 0).unwrap(),
+            (0xFF00, FLAG_NEGATIVE)
+        );
+        assert_eq!(
+            AluFunction::SHL.compute(0x8001, 1).unwrap(),
+            (0x0002, FLAG_CARRY)
+        );
+        assert_eq!(
+            AluFunction::SHR.compute(0x8001, 1).unwrap(),
+            (0x4000, FLAG_CARRY)
+        );
+        assert_eq!(
+            AluFunction::SAR.compute(0x8000, 3).unwrap(),
+            (0xF000, FLAG_NEGATIVE)
+        );
+        assert!(matches!(
+            AluFunction::DIV.compute(1, 0),
+            Err(VMError::DivideByZero)
+        ));
+        assert!(matches!(
+            AluFunction::try_from(11),
+            Err(VMError::AluFunctionDoesNotExist)
+        ));
+    }
+
+    fn stack_ix(operation: StackOperation, dst: u8, src: u8) -> u16 {
+        instruction_builder(0x0A, dst, src, operation.id())
+    }
+
+    #[test]
+    fn test_push_pop_is_lifo() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x00, 0x06, 0x07), // COPY R0, 7
+            instruction_builder(0x01, 0x01, 0x06, 0x09), // COPY R1, 9
+            stack_ix(StackOperation::PUSH, 0x00, 0x00),  // PUSH R0
+            stack_ix(StackOperation::PUSH, 0x00, 0x01),  // PUSH R1
+            stack_ix(StackOperation::POP, 0x02, 0x00),   // POP R2
+            stack_ix(StackOperation::POP, 0x03, 0x00),   // POP R3
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(reg(&vm, RegisterId::RR2), 9);
+        assert_eq!(reg(&vm, RegisterId::RR3), 7);
+        assert_eq!(reg(&vm, RegisterId::RSP), STACK_TOP);
+    }
+
+    #[test]
+    fn test_call_and_ret_restore_pc() {
+        let mut vm = vm_with_program(&[
+            stack_ix(StackOperation::CALL, 0x00, 0x01),  // CALL R1
+            instruction_builder(0x04, 0x00, 0x06, 0x01), // ADD R0, 1
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+            instruction_builder(0x01, 0x00, 0x06, 0x05), // sub: COPY R0, 5
+            stack_ix(StackOperation::RET, 0x00, 0x00),   // RET
+        ]);
+        vm.registers
+            .get_register_mut(RegisterId::RR1.id())
+            .unwrap()
+            .value = START_ADDRESS + 6;
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(reg(&vm, RegisterId::RR0), 6);
+        assert_eq!(reg(&vm, RegisterId::RSP), STACK_TOP);
+    }
+
+    #[test]
+    fn test_stack_bounds_are_checked() {
+        let push = stack_ix(StackOperation::PUSH, 0x00, 0x00);
+        let mut vm = vm_with_program(&[push, push]);
+        vm.config.stack = StackRegion {
+            base: STACK_TOP - 2,
+            top: STACK_TOP,
+        };
+        vm.tick().unwrap();
+        assert!(matches!(
+            vm.tick().map_err(VMError::into_cause),
+            Err(VMError::StackOverflow)
+        ));
+
+        let mut vm = vm_with_program(&[stack_ix(StackOperation::RET, 0x00, 0x00)]);
+        assert!(matches!(
+            vm.tick().map_err(VMError::into_cause),
+            Err(VMError::StackUnderflow)
+        ));
+
+        // A stack pointer above the stack does not let a push write past its top
+        let mut vm = vm_with_program(&[push]);
+        vm.config.stack.top = STACK_TOP - 4;
+        assert!(matches!(
+            vm.tick().map_err(VMError::into_cause),
+            Err(VMError::StackUnderflow)
+        ));
+        assert_eq!(reg(&vm, RegisterId::RSP), STACK_TOP);
+        assert_eq!(vm.memory.read2(STACK_TOP - 2), Some(0));
+    }
+
+    #[test]
+    fn test_with_config_initialises_rsp() {
+        let vm = VM::with_config(Config {
+            stack: StackRegion {
+                base: 0x200,
+                top: 0x300,
+            },
+            ..Config::default()
+        });
+        assert_eq!(reg(&vm, RegisterId::RSP), 0x300);
+    }
+
+    #[test]
+    fn test_default_code_segment_holds_large_programs() {
+        let mut source = "ADD R0, 1\n".repeat(200);
+        source.push_str("HALT");
+        let mut vm = VM::builder().build().unwrap();
+        vm.load_program(&Program::from(assemble_with_symbols(&source).unwrap()))
+            .unwrap();
+        assert!(vm.run().is_halted());
+        assert_eq!(reg(&vm, RegisterId::RR0), 200);
+    }
+
+    #[test]
+    fn test_builder_applies_config() {
+        let mut vm = VM::builder()
+            .memory_size(0x2000)
+            .load_address(0x180)
+            .stack(StackRegion {
+                base: 0x1800,
+                top: 0x1900,
+            })
+            .trace(TraceMode::Trace)
+            .memory_protection(false) // the program runs off its end into zeroed memory, which is HALT
+            .build()
+            .unwrap();
+        assert_eq!(vm.memory.memory_range(), 0x2000);
+        assert_eq!(reg(&vm, RegisterId::RSP), 0x1900);
+        assert!(vm.trace_enabled && !vm.zk_output_enabled);
+
+        vm.load_words(&build_simple_program()).unwrap();
+        assert_eq!(reg(&vm, RegisterId::RPC), 0x180);
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(vm.trace_buffer.len() as u64, vm.steps);
+
+        let unaligned = VM::builder().load_address(0x181).build();
+        assert!(matches!(unaligned, Err(VMError::InvalidConfig(_))));
+        let outside_code = VM::builder().load_address(0x1000).build();
+        assert!(matches!(outside_code, Err(VMError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let jump_to_self = instruction_builder(0x08, Condition::Always.id(), 0x06, 0x0F); // JMP -1 (relative)
+        let mut vm = vm_with_program(&[jump_to_self]);
+        vm.config.max_steps = Some(3);
+        for _ in 0..3 {
+            vm.tick().unwrap();
+        }
+        assert!(matches!(vm.tick(), Err(VMError::StepLimitReached)));
+        assert!(!vm.halted);
+        assert_eq!(vm.steps, 3);
+
+        // Raising the limit resumes the program
+        vm.config.max_steps = Some(4);
+        vm.tick().unwrap();
+    }
+
+    #[test]
+    fn test_run_api() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x00, 0x06, 0x01), // COPY R0, 1
+            instruction_builder(0x04, 0x00, 0x00, 0x00), // loop: ADD R0, R0
+            instruction_builder(0x08, Condition::NotZero.id(), 0x06, 0x0E), // JNZ loop
+            instruction_builder(0x06, 0x00, 0x00, 0x00), // STORE_OUT R0
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        vm.enable_trace();
+
+        let result = vm.run_steps(3);
+        assert!(matches!(result.reason, ExitReason::StepLimit));
+        assert_eq!((result.steps, result.trace.len()), (3, 3));
+
+        // The predicate is checked after each instruction, so the run moves past the current PC first
+        let loop_start = START_ADDRESS + 2;
+        let result = vm.run_until(|vm| {
+            vm.registers
+                .get_register_read_only(RegisterId::RPC.id())
+                .unwrap()
+                .value
+                == loop_start
+        });
+        assert!(matches!(result.reason, ExitReason::Breakpoint));
+        assert_eq!(result.steps, 2);
+        assert_eq!(
+            result.registers.register_map[&RegisterId::RR0.id()].value,
+            4
+        );
+
+        let result = vm.run();
+        assert!(result.is_halted());
+        assert_eq!(result.output, Some(0));
+        assert_eq!(result.trace.len() as u64, result.steps);
+        assert_eq!(vm.trace_buffer.len() as u64, vm.steps);
+        assert!(vm.run().is_halted());
+    }
+
+    #[test]
+    fn test_run_boundaries() {
+        let program = [
+            instruction_builder(0x01, 0x00, 0x06, 0x01), // COPY R0, 1
+            instruction_builder(0x01, 0x01, 0x06, 0x02), // COPY R1, 2
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ];
+        let mut vm = vm_with_program(&program);
+
+        // No instruction runs with a budget of 0 steps
+        let result = vm.run_steps(0);
+        assert!(matches!(result.reason, ExitReason::StepLimit));
+        assert_eq!(result.steps, 0);
+        assert_eq!(reg(&vm, RegisterId::RPC), START_ADDRESS);
+
+        // A predicate that always holds still lets one instruction run
+        let result = vm.run_until(|_| true);
+        assert!(matches!(result.reason, ExitReason::Breakpoint));
+        assert_eq!(result.steps, 1);
+
+        // Running fewer steps than requested is not a step limit when the program halts
+        let result = vm.run_steps(10);
+        assert!(result.is_halted());
+        assert_eq!(result.steps, 2);
+
+        // A halted machine returns at once, however it is run
+        assert_eq!(vm.run_steps(1).steps, 0);
+        let result = vm.run_until(|_| true);
+        assert!(result.is_halted());
+        assert_eq!(result.steps, 0);
+        assert!(result.trace.is_empty());
+    }
+
+    #[test]
+    fn test_run_limits_and_output() {
+        // A predicate that holds on the halting instruction does not hide the halt
+        let mut vm = vm_with_program(&[instruction_builder(0x00, 0x00, 0x00, 0x00)]);
+        assert!(vm.run_until(|_| true).is_halted());
+
+        // `Config::max_steps` bounds the whole life of the VM, raising it resumes the run
+        let program = [
+            instruction_builder(0x01, 0x00, 0x06, 0x01), // COPY R0, 1
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ];
+        let mut vm = vm_with_program(&program);
+        vm.config.max_steps = Some(1);
+        let result = vm.run();
+        assert!(matches!(result.reason, ExitReason::StepLimit));
+        assert_eq!(result.steps, 1);
+        assert!(matches!(vm.run().reason, ExitReason::StepLimit));
+        vm.config.max_steps = Some(2);
+        assert!(vm.run().is_halted());
+
+        // Without memory at the output address there is no output word
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(0x200)));
+        vm.load_program(&Program::new(program.to_vec())).unwrap();
+        let result = vm.run();
+        assert!(result.is_halted());
+        assert_eq!(result.output, None);
+    }
+
+    #[test]
+    fn test_word_accesses_at_the_top_of_memory() {
+        let mut vm = VM::builder().memory_size(0x10000).build().unwrap();
+        let source = "LOAD_IMM16 R1, 0xFFFF\nWRITE R1, R0\nHALT";
+        vm.load_words(&crate::asm::assemble(source).unwrap())
+            .unwrap();
+        let error = run_to_halt(&mut vm).unwrap_err();
+        assert!(matches!(error.cause(), VMError::OutOfBounds(0xFFFF)));
+        assert_eq!(vm.memory.read2(0x0000), Some(0));
+
+        // An image whose data ends at the top of memory loads
+        let program = Program::new(vec![0; 0x7F80]).with_data_address(START_ADDRESS + 2);
+        vm.load_program(&program).unwrap();
+    }
+
+    #[test]
+    fn test_exit_reasons() {
+        let program = [
+            instruction_builder(0x01, 0x00, 0x06, 0x01), // COPY R0, 1
+            instruction_builder(0x02, 0x00, 0x01, 0x00), // LOAD R0, R1
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ];
+
+        // Runs stopped by a budget can be resumed, the machine is not halted
+        let mut vm = vm_with_program(&program);
+        vm.config.max_steps = Some(1);
+        assert!(matches!(vm.run().reason, ExitReason::StepLimit));
+        assert!(!vm.halted);
+
+        let mut vm = vm_with_program(&program);
+        vm.set_gas_budget(0);
+        assert!(matches!(vm.run().reason, ExitReason::OutOfGas));
+        assert!(!vm.halted);
+
+        // An error halts the machine and carries the faulting instruction
+        let mut vm = vm_with_program(&program);
+        vm.registers
+            .get_register_mut(RegisterId::RR1.id())
+            .unwrap()
+            .value = 0x2000;
+        let result = vm.run();
+        let ExitReason::Error(error) = &result.reason else {
+            panic!("expected an error, got {:?}", result.reason);
+        };
+        assert_eq!(error.as_fault().unwrap().pc, START_ADDRESS + 2);
+        assert_eq!(result.steps, 1);
+        assert!(vm.halted);
+        assert!(matches!(vm.tick(), Err(VMError::Halted)));
+
+        // So does an instruction that cannot be fetched, the fault has its address but no instruction
+        let mut vm = vm_with_program(&program);
+        vm.registers
+            .get_register_mut(RegisterId::RPC.id())
+            .unwrap()
+            .value = END;
+        let result = vm.run();
+        let ExitReason::Error(error) = &result.reason else {
+            panic!("expected an error, got {:?}", result.reason);
+        };
+        let fault = error.as_fault().unwrap();
+        assert_eq!((fault.pc, fault.instruction), (END, None));
+        assert!(matches!(error.cause(), VMError::OutOfBounds(END)));
+        assert_eq!(
+            error.to_string(),
+            "Memory access is out of bounds (0x1388) fetching the instruction at 0x1388"
+        );
+        assert_eq!(result.steps, 0);
+        assert!(vm.halted);
+    }
+
+    #[test]
+    fn test_gas_metering() {
+        let program = [
+            instruction_builder(0x01, 0x00, 0x06, 0x02), // COPY R0, 2
+            instruction_builder(0x06, 0x00, 0x00, 0x00), // STORE_OUT R0
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ];
+        let mut vm = vm_with_program(&program);
+        assert_eq!(vm.remaining_gas(), None);
+        run_to_halt(&mut vm).unwrap();
+
+        let mut vm = vm_with_program(&program);
+        vm.config.gas_costs.halt = 5;
+        vm.set_gas_budget(4);
+        vm.tick().unwrap();
+        vm.tick().unwrap();
+        assert_eq!(vm.remaining_gas(), Some(1));
+        assert!(matches!(vm.tick(), Err(VMError::OutOfGas)));
+        assert!(!vm.halted);
+        assert_eq!(reg(&vm, RegisterId::RPC), START_ADDRESS + 4);
+
+        // Topping up resumes at the instruction that ran out
+        vm.set_gas_budget(5);
+        vm.tick().unwrap();
+        assert!(vm.halted);
+        assert_eq!(vm.remaining_gas(), Some(0));
+    }
+
+    #[test]
+    fn test_out_of_gas_leaves_state_untouched() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x00, 0x06, 0x02), // COPY R0, 2
+            instruction_builder(0x04, 0x00, 0x00, 0x00), // ADD R0, R0
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        vm.config.gas_costs.add = 3;
+        vm.set_gas_budget(3);
+
+        let result = vm.run();
+        assert!(matches!(result.reason, ExitReason::OutOfGas));
+        assert_eq!(result.steps, 1);
+        assert_eq!(vm.remaining_gas(), Some(2));
+        assert_eq!(reg(&vm, RegisterId::RR0), 2);
+        assert_eq!(reg(&vm, RegisterId::RFLAGS), 0);
+        assert_eq!(reg(&vm, RegisterId::RPC), START_ADDRESS + 2);
+
+        // Every further run stops at the same instruction until the budget is raised
+        assert!(matches!(vm.run().reason, ExitReason::OutOfGas));
+        assert_eq!(vm.steps, 1);
+        vm.set_gas_budget(4);
+        assert!(vm.run().is_halted());
+        assert_eq!(reg(&vm, RegisterId::RR0), 4);
+        assert_eq!(vm.remaining_gas(), Some(0));
+    }
+
+    #[test]
+    fn test_gas_edge_cases() {
+        // The budget comes from the config
+        let vm = VM::builder().gas_limit(7).build().unwrap();
+        assert_eq!(vm.remaining_gas(), Some(7));
+
+        // Free instructions run on an empty budget
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x00, 0x06, 0x02), // COPY R0, 2
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        vm.config.gas_costs.copy = 0;
+        vm.config.gas_costs.halt = 0;
+        vm.set_gas_budget(0);
+        assert!(vm.run().is_halted());
+
+        // A wide instruction is charged once, its extension word is not an instruction
+        let mut vm = vm_with_program(&[
+            wide_ix(WideOperation::LOAD_IMM16, 0x00), // LOAD_IMM16 R0, 0x1234
+            0x1234,
+        ]);
+        vm.set_gas_budget(vm.config.gas_costs.wide);
+        vm.tick().unwrap();
+        assert_eq!(vm.remaining_gas(), Some(0));
+        assert_eq!(reg(&vm, RegisterId::RR0), 0x1234);
+
+        // An opcode that does not decode has no cost, it faults instead of running out of gas
+        let mut vm = vm_with_program(&[instruction_builder(0x0F, 0x00, 0x00, 0x00)]);
+        vm.set_gas_budget(0);
+        let result = vm.run();
+        assert!(
+            matches!(result.reason, ExitReason::Error(ref error) if matches!(error.cause(), VMError::OpcodeDoesNotExist))
+        );
+        assert_eq!(vm.remaining_gas(), Some(0));
+    }
+
+    #[test]
+    fn test_overflow_trap_keeps_destination() {
+        let program = [
+            wide_ix(WideOperation::LOAD_IMM16, 0x00), // LOAD_IMM16 R0, 0x7FFF
+            0x7FFF,
+            instruction_builder(0x04, 0x00, 0x06, 0x01), // ADD R0, 1
+        ];
+        let mut vm = vm_with_program(&program);
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(reg(&vm, RegisterId::RR0), 0x8000);
+
+        let mut vm = vm_with_program(&program);
+        vm.config.overflow = OverflowMode::Trap;
+        vm.tick().unwrap();
+        assert!(matches!(
+            vm.tick().map_err(VMError::into_cause),
+            Err(VMError::Overflow)
+        ));
+        assert_eq!(reg(&vm, RegisterId::RR0), 0x7FFF);
+    }
+
+    fn wide_ix(operation: WideOperation, dst: u8) -> u16 {
+        instruction_builder(0x0B, dst, 0x00, operation.id())
+    }
+
+    #[test]
+    fn test_wide_immediates() {
+        let mut vm = vm_with_program(&[
+            wide_ix(WideOperation::LOAD_IMM16, 0x00), // LOAD_IMM16 R0, 0x1234
+            0x1234,
+            wide_ix(WideOperation::LOAD_IMM16, 0x01), // LOAD_IMM16 R1, 0
+            0x0000,
+            wide_ix(WideOperation::ADDI16, 0x00), // ADDI16 R0, 0xEDCC
+            0xEDCC,
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        vm.registers
+            .get_register_mut(RegisterId::RR1.id())
+            .unwrap()
+            .value = 7;
+        vm.tick().unwrap();
+        assert_eq!(reg(&vm, RegisterId::RR0), 0x1234);
+        assert_eq!(reg(&vm, RegisterId::RPC), START_ADDRESS + 4);
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(reg(&vm, RegisterId::RR1), 0);
+        assert_eq!(reg(&vm, RegisterId::RR0), 0);
+        assert_eq!(vm.registers.flags().unwrap(), FLAG_ZERO | FLAG_CARRY);
+    }
+
+    #[test]
+    fn test_wide_jump_and_call() {
+        let mut vm = vm_with_program(&[
+            wide_ix(WideOperation::CALL16, 0x00), // CALL16 sub
+            START_ADDRESS + 10,
+            wide_ix(WideOperation::JMP16, Condition::NotZero.id()), // JNZ16 end
+            START_ADDRESS + 14,
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT (skipped)
+            instruction_builder(0x04, 0x00, 0x06, 0x03), // sub: ADD R0, 3
+            stack_ix(StackOperation::RET, 0x00, 0x00),   // RET
+            instruction_builder(0x04, 0x00, 0x06, 0x01), // end: ADD R0, 1
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        vm.enable_trace();
+        run_to_halt(&mut vm).unwrap();
+        assert_eq!(reg(&vm, RegisterId::RR0), 4);
+        assert_eq!(vm.trace_buffer[0].ext, Some(START_ADDRESS + 10));
+        assert_eq!(vm.trace_buffer[1].ext, None);
+    }
+
+    fn int_ix(operation: IntOperation) -> u16 {
+        instruction_builder(0x0C, 0x00, 0x00, operation.id())
+    }
+
+    #[test]
+    fn test_fault_handler_retries_faulting_instruction() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x09, 0x00, 0x01, AluFunction::DIV.id()), // DIV R0, R1
+            instruction_builder(0x06, 0x00, 0x00, 0x00),                  // STORE_OUT R0
+            instruction_builder(0x00, 0x00, 0x00, 0x00),                  // HALT
+            instruction_builder(0x01, 0x01, 0x06, 0x02),                  // handler: COPY R1, 2
+            int_ix(IntOperation::IRET),
+        ]);
+        let handler = START_ADDRESS + 6;
+        vm.memory
+            .write2(TrapVector::DivideByZero.entry(), handler)
+            .unwrap();
+        vm.registers
+            .get_register_mut(RegisterId::RR0.id())
+            .unwrap()
+            .value = 6;
+
+        vm.tick().unwrap();
+        assert_eq!(reg(&vm, RegisterId::RPC), handler);
+        assert_eq!(reg(&vm, RegisterId::RSP), STACK_TOP - 4);
+        assert_eq!(vm.memory.read2(STACK_TOP - 4), Some(START_ADDRESS));
+
+        let result = vm.run();
+        assert!(result.is_halted());
+        assert_eq!(result.output, Some(3));
+        assert_eq!(reg(&vm, RegisterId::RSP), STACK_TOP);
+
+        // Without a handler the fault still halts the machine
+        let mut vm = vm_with_program(&[int_ix(IntOperation::IRET)]);
+        assert!(matches!(
+            vm.tick().map_err(VMError::into_cause),
+            Err(VMError::StackUnderflow)
+        ));
+        assert!(vm.halted);
+    }
+
+    #[test]
+    fn test_interrupts_are_taken_between_instructions() {
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(MockBus::new()));
+        let program = Program::new(vec![
+            int_ix(IntOperation::EI),
+            instruction_builder(0x08, Condition::Always.id(), 0x06, 0x0F), // loop: JMP loop
+            instruction_builder(0x04, 0x00, 0x06, 0x01),                   // handler: ADD R0, 1
+            int_ix(IntOperation::IRET),
+        ]);
+        vm.load_program(&program).unwrap();
+        let loop_addr = START_ADDRESS + 2;
+        let handler = START_ADDRESS + 4;
+        vm.memory
+            .write2(TrapVector::Interrupt(2).entry(), handler)
+            .unwrap();
+        vm.memory
+            .write2(TrapVector::Interrupt(5).entry(), handler)
+            .unwrap();
+
+        // Raised while interrupts are disabled, so it waits until EI has executed
+        vm.raise_interrupt(2).unwrap();
+        vm.tick().unwrap();
+        assert!(vm.interrupts_enabled());
+        assert_eq!(reg(&vm, RegisterId::RPC), loop_addr);
+
+        vm.tick().unwrap();
+        assert_eq!(reg(&vm, RegisterId::RR0), 1);
+        assert!(!vm.interrupts_enabled());
+        vm.tick().unwrap();
+        assert_eq!(reg(&vm, RegisterId::RPC), loop_addr);
+        assert!(vm.interrupts_enabled());
+
+        // Lines raised by a device on the bus
+        vm.memory
+            .write2(TrapVector::Interrupt(2).entry(), 0)
+            .unwrap();
+        let mut bus = MockBus::new();
+        bus.memory = vm.memory.as_bytes().clone();
+        bus.irq = 1 << 5;
+        vm.set_memory(Box::new(bus));
+        vm.run_steps(2);
+        assert_eq!(reg(&vm, RegisterId::RR0), 2);
+        assert_eq!(vm.memory.pending_interrupts(), 0);
+
+        assert!(matches!(
+            vm.raise_interrupt(8),
+            Err(VMError::InvalidInterruptLine(8))
+        ));
+    }
+
+    #[test]
+    fn test_faults_carry_instruction_context() {
+        let load = instruction_builder(0x02, 0x00, 0x01, 0x00); // LOAD R0, R1
+        let mut vm = vm_with_program(&[instruction_builder(0x01, 0x00, 0x06, 0x01), load]);
+        vm.registers
+            .get_register_mut(RegisterId::RR1.id())
+            .unwrap()
+            .value = 0x2000;
+        vm.tick().unwrap();
+
+        let error = vm.tick().unwrap_err();
+        let fault = error.as_fault().unwrap();
+        assert_eq!(fault.pc, START_ADDRESS + 2);
+        assert_eq!(fault.instruction, Some(load));
+        assert_eq!(fault.opcode, Some(Opcode::LOAD));
+        assert_eq!(error.addr(), Some(0x2000));
+        assert!(vm.halted);
+        assert_eq!(
+            error.to_string(),
+            "Memory access is out of bounds (0x2000) at 0x0102: LOAD R0, R1 (0x2010)"
+        );
+
+        // Stores used to halt silently, now they report the address as well
+        let mut vm = vm_with_program(&[instruction_builder(0x03, 0x01, 0x00, 0x00)]); // WRITE R1, R0
+        vm.registers
+            .get_register_mut(RegisterId::RR1.id())
+            .unwrap()
+            .value = END - 1;
+        assert_eq!(vm.tick().unwrap_err().addr(), Some(END));
+
+        let mut vm = vm_with_program(&[0xF000]);
+        let error = vm.tick().unwrap_err();
+        assert!(matches!(error.cause(), VMError::OpcodeDoesNotExist));
+        assert_eq!(error.as_fault().unwrap().opcode, None);
+    }
+
+    #[test]
+    fn test_missing_extension_word_is_error() {
+        // The instruction is the last word in memory, so its literal cannot be fetched
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(0x200)));
+        let program = Program::new(vec![wide_ix(WideOperation::LOAD_IMM16, 0x00)])
+            .with_load_address(0x1FE)
+            .with_entry_point(0x1FE);
+        vm.load_program(&program).unwrap();
+        assert!(matches!(
+            vm.tick().map_err(VMError::into_cause),
+            Err(VMError::OutOfBounds(0x200))
+        ));
+    }
+
+    #[test]
+    fn test_load_program_validates_image() {
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(0x108)));
+
+        let too_big = Program::new(vec![0; 5]);
+        assert!(matches!(
+            vm.load_program(&too_big),
+            Err(VMError::ProgramDoesNotFit)
+        ));
+
+        let outside_entry = Program::new(vec![0; 2]).with_entry_point(START_ADDRESS + 4);
+        assert!(matches!(
+            vm.load_program(&outside_entry),
+            Err(VMError::InvalidEntryPoint)
+        ));
+
+        let program =
+            Program::new(vec![0x1234, 0x0000, 0x5678]).with_entry_point(START_ADDRESS + 2);
+        vm.load_program(&program).unwrap();
+        assert_eq!(reg(&vm, RegisterId::RPC), START_ADDRESS + 2);
+        assert_eq!(vm.memory.read2(START_ADDRESS + 4), Some(0x5678));
+
+        // Memory is large enough, but the code has to stay inside the code segment
+        vm.set_memory(Box::new(LinearMemory::new(MEMORY_SIZE)));
+        let past_code = Program::new(vec![0; 0x781]);
+        let error = vm.load_program(&past_code).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Program code does not fit in the code segment (0x0100..0x1002)"
+        );
+        let in_rodata = Program::new(vec![0]).with_load_address(0x1000);
+        assert!(matches!(
+            vm.load_program(&in_rodata.with_entry_point(0x1000)),
+            Err(VMError::CodeOutsideSegment { start: 0x1000, .. })
+        ));
+        let data_past_code = Program::new(vec![0; 0x781]).with_data_address(START_ADDRESS + 2);
+        vm.load_program(&data_past_code).unwrap();
+    }
+
+    #[test]
+    fn test_trace_entries_display_as_instructions() {
+        let mut vm = vm_with_program(&build_simple_program());
+        vm.enable_trace();
+        vm.tick().unwrap();
+        vm.tick().unwrap();
+        assert_eq!(Opcode::LOAD_IMM.to_string(), "LOAD_IMM");
+        let first = vm.trace_buffer[0].to_string();
+        assert!(first.starts_with("0x0100: LOAD_IMM RIM, 5"));
+        assert!(first.contains("RPC=0x0102"));
+        assert!(
+            vm.trace_buffer[1]
+                .to_string()
+                .starts_with("0x0102: COPY R0, RIM")
+        );
+    }
+
+    fn syscall_ix(src: u8, imm: u8) -> u16 {
+        instruction_builder(0x0D, 0x00, src, imm)
+    }
+
+    #[test]
+    fn test_syscall_calls_host_functions() {
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x00, 0x06, 0x09), // COPY R0, 9
+            syscall_ix(0x06, 0x07),                      // SYSCALL 7
+            instruction_builder(0x01, 0x02, 0x06, 0x05), // COPY R2, 5
+            syscall_ix(0x02, 0x00),                      // SYSCALL R2
+            syscall_ix(0x06, 0x04),                      // SYSCALL 4
+        ]);
+        vm.host.register(7, "double", |registers, memory| {
+            let r0 = registers.get_register_mut(RegisterId::RR0.id())?;
+            r0.value *= 2;
+            memory.write2(0x200, r0.value)
+        });
+        vm.enable_trace();
+
+        let result = vm.run();
+        assert!(matches!(
+            result.reason,
+            ExitReason::Error(ref error) if matches!(error.cause(), VMError::Aborted(3))
+        ));
+        assert_eq!(vm.memory.read2(0x200), Some(18));
+        // SYS_STEPS saw the three instructions before it, abort then reads the count as its code
+        assert_eq!(reg(&vm, RegisterId::RR0), 3);
+        assert_eq!(reg(&vm, RegisterId::RR1), 0);
+
+        let calls: Vec<_> = vm
+            .trace_buffer
+            .iter()
+            .filter_map(|entry| entry.host_function.as_deref())
+            .collect();
+        assert_eq!(calls, ["double", "steps", "abort"]);
+        assert!(
+            vm.trace_buffer[1]
+                .to_string()
+                .contains("SYSCALL 7 (double)")
+        );
+
+        // Unknown numbers are invalid instructions
+        let mut vm = vm_with_program(&[syscall_ix(0x06, 0x0F)]);
+        assert!(matches!(
+            vm.tick().unwrap_err().cause(),
+            VMError::UnknownSyscall(15)
+        ));
+    }
+
+    #[test]
+    fn test_syscall_errors() {
+        // An unknown number raises the invalid instruction vector, the handler returns to the SYSCALL
+        let handler = START_ADDRESS + 4;
+        let mut vm = vm_with_program(&[
+            syscall_ix(0x06, 0x09),                      // SYSCALL 9
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+            instruction_builder(0x01, 0x03, 0x06, 0x01), // handler: COPY R3, 1
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ]);
+        vm.memory
+            .write2(TrapVector::InvalidInstruction.entry(), handler)
+            .unwrap();
+        assert!(vm.run().is_halted());
+        assert_eq!(reg(&vm, RegisterId::RR3), 1);
+        assert_eq!(vm.memory.read2(STACK_TOP - 4), Some(START_ADDRESS));
+
+        // Errors of host functions fault at the SYSCALL and halt the machine, side effects before them remain
+        let mut vm = vm_with_program(&[syscall_ix(0x06, 0x07)]);
+        vm.host.register(7, "fails", |registers, _| {
+            registers.get_register_mut(RegisterId::RR1.id())?.value = 42;
+            Err(VMError::MemoryReadError)
+        });
+        let result = vm.run();
+        let ExitReason::Error(error) = &result.reason else {
+            panic!("expected an error, got {:?}", result.reason);
+        };
+        assert!(matches!(error.cause(), VMError::MemoryReadError));
+        assert_eq!(error.as_fault().unwrap().pc, START_ADDRESS);
+        assert_eq!(reg(&vm, RegisterId::RR1), 42);
+        assert!(vm.halted);
+
+        // The abort code reaches the caller unchanged, even with a handler for invalid instructions
+        let mut vm = vm_with_program(&[
+            instruction_builder(0x01, 0x00, 0x06, 0x0C), // COPY R0, 12
+            syscall_ix(0x06, 0x04),                      // SYSCALL 4
+        ]);
+        vm.memory
+            .write2(TrapVector::InvalidInstruction.entry(), START_ADDRESS)
+            .unwrap();
+        let result = vm.run();
+        assert!(matches!(
+            result.reason,
+            ExitReason::Error(ref error) if matches!(error.cause(), VMError::Aborted(12))
+        ));
+        assert!(vm.halted);
+    }
+
+    #[test]
+    fn test_syscall_numbers_can_be_reregistered() {
+        let program = [
+            syscall_ix(0x06, 0x05),                      // SYSCALL 5
+            syscall_ix(0x06, 0x01),                      // SYSCALL 1
+            instruction_builder(0x00, 0x00, 0x00, 0x00), // HALT
+        ];
+        let mut vm = vm_with_program(&program);
+        vm.enable_trace();
+        vm.host.register(5, "clock", |registers, _| {
+            registers.get_register_mut(RegisterId::RR0.id())?.value = 0xC10C;
+            Ok(())
+        });
+        vm.host.register(1, "first", |_, _| Ok(()));
+        vm.host.register(1, "second", |registers, _| {
+            registers.get_register_mut(RegisterId::RR2.id())?.value = 2;
+            Ok(())
+        });
+        assert!(vm.run().is_halted());
+
+        // The built-in steps function and the first registration are gone
+        assert_eq!(reg(&vm, RegisterId::RR0), 0xC10C);
+        assert_eq!(reg(&vm, RegisterId::RR2), 2);
+        let calls: Vec<_> = vm
+            .trace_buffer
+            .iter()
+            .filter_map(|entry| entry.host_function.as_deref())
+            .collect();
+        assert_eq!(calls, ["clock", "second"]);
+    }
 }
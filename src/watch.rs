@@ -0,0 +1,362 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bus::BusDevice;
+use crate::constants::{VMWord, VmAddr};
+use crate::error::Result;
+use crate::memory::LinearMemory;
+use crate::protection::Permissions;
+use crate::vm::VM;
+
+/*
+    Memory watchpoints.
+
+    `WatchBus` wraps any `BusDevice` and records every data access that touches a watched address range,
+    instruction fetches (`BusDevice::fetch2`) are not data accesses and never fire.
+    The recorded hits are shared with the front-end through a `Watchpoints` handle: the debugger or the gdb stub
+    drains them after every `VM::tick` and pauses when there are any. The bus does not know the PC, the front-end
+    reports the address of the instruction it just executed.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, // read or write
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+impl WatchKind {
+    fn fires_on(&self, access: AccessKind) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, AccessKind::Read)
+                | (WatchKind::Write, AccessKind::Write)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub addr: VmAddr,
+    pub len: u16, // bytes
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn overlaps(&self, addr: VmAddr, size: usize) -> bool {
+        let start = usize::from(self.addr);
+        let access = usize::from(addr);
+        access < start + usize::from(self.len) && start < access + size
+    }
+}
+
+/// One access that fired watchpoint `id`, `old` and `new` are equal for reads
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub access: AccessKind,
+    pub addr: VmAddr,
+    pub size: u8, // 1 for byte accesses, 2 for word accesses
+    pub old: VMWord,
+    pub new: VMWord,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = usize::from(self.size) * 2 + 2;
+        match self.access {
+            AccessKind::Read => write!(
+                f,
+                "watchpoint {}: read {:#06x} = {:#0width$x}",
+                self.id, self.addr, self.new
+            ),
+            AccessKind::Write => write!(
+                f,
+                "watchpoint {}: write {:#06x}: {:#0width$x} -> {:#0width$x}",
+                self.id, self.addr, self.old, self.new
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct WatchState {
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_id: usize,
+    hits: Vec<WatchHit>,
+}
+
+/// Handle shared between a `WatchBus` and the front-end that sets the watchpoints and collects the hits
+#[derive(Debug, Clone, Default)]
+pub struct Watchpoints {
+    state: Rc<RefCell<WatchState>>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps the memory of `vm` in a `WatchBus` reporting to this handle
+    pub fn attach(&self, vm: &mut VM) {
+        let memory = std::mem::replace(&mut vm.memory, Box::new(LinearMemory::new(0)));
+        vm.memory = Box::new(WatchBus::new(memory, self.clone()));
+    }
+
+    // Returns the id of the new watchpoint, ids start at 1 and are never reused
+    pub fn add(&self, watchpoint: Watchpoint) -> usize {
+        let mut state = self.state.borrow_mut();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.watchpoints.insert(id, watchpoint);
+        id
+    }
+
+    pub fn remove(&self, id: usize) -> Option<Watchpoint> {
+        self.state.borrow_mut().watchpoints.remove(&id)
+    }
+
+    pub fn list(&self) -> Vec<(usize, Watchpoint)> {
+        self.state
+            .borrow()
+            .watchpoints
+            .iter()
+            .map(|(id, watchpoint)| (*id, *watchpoint))
+            .collect()
+    }
+
+    // Hits recorded since the last call
+    pub fn take_hits(&self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.state.borrow_mut().hits)
+    }
+
+    fn record(&self, access: AccessKind, addr: VmAddr, size: u8, old: VMWord, new: VMWord) {
+        let mut state = self.state.borrow_mut();
+        let fired: Vec<usize> = state
+            .watchpoints
+            .iter()
+            .filter(|(_, watchpoint)| {
+                watchpoint.kind.fires_on(access) && watchpoint.overlaps(addr, usize::from(size))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        state.hits.extend(fired.into_iter().map(|id| WatchHit {
+            id,
+            access,
+            addr,
+            size,
+            old,
+            new,
+        }));
+    }
+}
+
+/// Bus device forwarding to `inner` and recording the accesses that fire a watchpoint
+#[derive(Debug)]
+pub struct WatchBus {
+    inner: Box<dyn BusDevice>,
+    watchpoints: Watchpoints,
+}
+
+impl WatchBus {
+    pub fn new(inner: Box<dyn BusDevice>, watchpoints: Watchpoints) -> Self {
+        Self { inner, watchpoints }
+    }
+}
+
+impl BusDevice for WatchBus {
+    fn read(&self, addr: VmAddr) -> Option<u8> {
+        let value = self.inner.read(addr)?;
+        self.watchpoints
+            .record(AccessKind::Read, addr, 1, value.into(), value.into());
+        Some(value)
+    }
+
+    // The old value is peeked, reading it could change a device, e.g. consume UART input
+    fn write(&mut self, addr: VmAddr, value: u8) -> Result<()> {
+        let old = self.inner.peek(addr).unwrap_or_default();
+        self.inner.write(addr, value)?;
+        self.watchpoints
+            .record(AccessKind::Write, addr, 1, old.into(), value.into());
+        Ok(())
+    }
+
+    // Words are forwarded as words, so a hit reports the whole value instead of two byte halves
+    fn read2(&self, addr: VmAddr) -> Option<u16> {
+        let value = self.inner.read2(addr)?;
+        self.watchpoints
+            .record(AccessKind::Read, addr, 2, value, value);
+        Some(value)
+    }
+
+    fn write2(&mut self, addr: VmAddr, value: u16) -> Result<()> {
+        let old = self.inner.peek2(addr).unwrap_or_default();
+        self.inner.write2(addr, value)?;
+        self.watchpoints
+            .record(AccessKind::Write, addr, 2, old, value);
+        Ok(())
+    }
+
+    fn try_read2(&self, addr: VmAddr) -> Result<u16> {
+        let value = self.inner.try_read2(addr)?;
+        self.watchpoints
+            .record(AccessKind::Read, addr, 2, value, value);
+        Ok(value)
+    }
+
+    fn fetch2(&self, addr: VmAddr) -> Option<u16> {
+        self.inner.fetch2(addr)
+    }
+
+    // Inspection is not an access, it never fires
+    fn peek(&self, addr: VmAddr) -> Option<u8> {
+        self.inner.peek(addr)
+    }
+
+    fn peek2(&self, addr: VmAddr) -> Option<u16> {
+        self.inner.peek2(addr)
+    }
+
+    fn try_fetch2(&self, addr: VmAddr) -> Result<u16> {
+        self.inner.try_fetch2(addr)
+    }
+
+    fn protect(&mut self, start: VmAddr, len: usize, permissions: Permissions) {
+        self.inner.protect(start, len, permissions)
+    }
+
+    fn set_address_space(&mut self, page_table: VmAddr, user: bool) {
+        self.inner.set_address_space(page_table, user)
+    }
+
+    fn memory_range(&self) -> usize {
+        self.inner.memory_range()
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.inner.pending_interrupts()
+    }
+
+    fn acknowledge_interrupt(&mut self, line: u8) {
+        self.inner.acknowledge_interrupt(line)
+    }
+
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.inner.dma_pending()
+    }
+
+    fn dma(&mut self, memory: &mut dyn BusDevice) {
+        self.inner.dma(memory)
+    }
+
+    fn as_bytes(&self) -> &Vec<u8> {
+        self.inner.as_bytes()
+    }
+
+    fn get_specific_memory_location(&self, idx: usize) -> u16 {
+        self.inner.get_specific_memory_location(idx)
+    }
+
+    fn get_subset_of_memory(&self, start_addr: usize, end_addr: usize) -> Vec<u8> {
+        self.inner.get_subset_of_memory(start_addr, end_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::constants::{MEMORY_SIZE, START_ADDRESS};
+    use crate::program::Program;
+
+    fn watched_vm(source: &str) -> (VM, Watchpoints) {
+        let mut vm = VM::new();
+        vm.set_memory(Box::new(LinearMemory::new(MEMORY_SIZE)));
+        vm.load_program(&Program::new(assemble(source).unwrap()))
+            .unwrap();
+        let watchpoints = Watchpoints::new();
+        watchpoints.attach(&mut vm);
+        (vm, watchpoints)
+    }
+
+    #[test]
+    fn test_write_watchpoint_reports_old_and_new_value() {
+        let (mut vm, watchpoints) =
+            watched_vm("LOAD_IMM16 R1, 0x200\nCOPY R0, 7\nWRITE R1, R0\nHALT");
+        let id = watchpoints.add(Watchpoint {
+            addr: 0x201,
+            len: 1,
+            kind: WatchKind::Write,
+        });
+
+        vm.tick().unwrap();
+        vm.tick().unwrap();
+        assert!(watchpoints.take_hits().is_empty());
+        vm.tick().unwrap();
+        let hits = watchpoints.take_hits();
+        assert_eq!(
+            hits,
+            vec![WatchHit {
+                id,
+                access: AccessKind::Write,
+                addr: 0x200,
+                size: 2,
+                old: 0,
+                new: 7,
+            }]
+        );
+        assert_eq!(
+            hits[0].to_string(),
+            "watchpoint 1: write 0x0200: 0x0000 -> 0x0007"
+        );
+    }
+
+    #[test]
+    fn test_store_out_and_reads_fire_but_fetches_do_not() {
+        let (mut vm, watchpoints) = watched_vm("COPY R0, 5\nSTORE_OUT R0\nLOAD R1, R2\nHALT");
+        watchpoints.add(Watchpoint {
+            addr: START_ADDRESS,
+            len: 2,
+            kind: WatchKind::Access,
+        });
+        watchpoints.add(Watchpoint {
+            addr: vm.config.output_address(),
+            len: 2,
+            kind: WatchKind::Write,
+        });
+        let read_id = watchpoints.add(Watchpoint {
+            addr: 0,
+            len: 2,
+            kind: WatchKind::Read,
+        });
+
+        // Fetching COPY from the watched code word is not a data access
+        vm.tick().unwrap();
+        assert!(watchpoints.take_hits().is_empty());
+        vm.tick().unwrap();
+        let hits = watchpoints.take_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].access, hits[0].new), (AccessKind::Write, 5));
+
+        vm.tick().unwrap();
+        let hits = watchpoints.take_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].id, hits[0].access), (read_id, AccessKind::Read));
+
+        assert!(watchpoints.remove(read_id).is_some());
+        assert_eq!(watchpoints.list().len(), 2);
+    }
+}
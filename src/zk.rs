@@ -1,8 +1,10 @@
 use crate::{
     bus::BusDevice,
-    constants::{BN254_MODULUS, START_ADDRESS, VMWord},
+    config::{Config, SegmentKind},
+    constants::BN254_MODULUS,
     error::{Result, VMError},
-    register::{RegisterBank, RegisterId},
+    image::ExecutableImage,
+    register::RegisterBank,
 };
 use ark_bn254::Fr;
 use ark_ff::{AdditiveGroup, PrimeField};
@@ -39,8 +41,10 @@ impl ZkContext {
         Self::default()
     }
 
-    pub fn set_public_program(&mut self, program: Vec<VMWord>) -> Result<()> {
-        let serialized_program = serialize(&program).unwrap();
+    // The commitment is computed over the canonical executable image, raw words are treated as an image loaded at START_ADDRESS,
+    // so every tool that ends up with the same image produces the same program hash
+    pub fn set_public_program(&mut self, program: impl Into<ExecutableImage>) -> Result<()> {
+        let serialized_program = program.into().canonical_bytes()?;
         let sha_to_bn254_field = Sha256Hash::hash(&serialized_program);
         // Save the hash as a private representation of raw_program witness
         self.private_program_sha254 = sha_to_bn254_field;
@@ -56,21 +60,23 @@ impl ZkContext {
         todo!()
     }
 
+    // The output state is the output word, the data segment of `config` (the part inside memory) and the registers
     pub fn set_public_output(
         &mut self,
         registers: &RegisterBank,
         memory: &dyn BusDevice,
+        config: &Config,
     ) -> Result<()> {
         // Serialize registers and memory
-        let pc = registers
-            .get_register_read_only(RegisterId::RPC.id())?
-            .value as usize;
         let output_from_r0 = memory
-            .read2(START_ADDRESS)
+            .read2(config.output_address())
             .ok_or(VMError::MemoryReadError)?;
 
         let output_state = serialize(&output_from_r0).unwrap();
-        let final_memory_subset = memory.get_subset_of_memory(START_ADDRESS as usize, pc);
+        let data = config.segment(SegmentKind::Data);
+        let end = data.end.min(memory.memory_range());
+        let final_memory_subset =
+            memory.get_subset_of_memory(usize::from(data.start).min(end), end);
         let final_registers_state = wincode::serialize(registers).unwrap();
 
         let sha_to_bn254_field = Sha256Hash::hash_multiple(&[